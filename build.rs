@@ -1,9 +1,35 @@
 use std::env;
-use std::path::Path;
-use std::process::Command;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// The assembler from src/ compiled into the build script, so the examples
+// build without cc65. The `src` module mirrors the crate root so its
+// `super::cpu6502::operation` import resolves the same way here.
+#[allow(dead_code, clippy::upper_case_acronyms)]
+#[path = "src"]
+mod src {
+    pub mod asm;
+    pub mod cpu6502 {
+        pub mod operation;
+    }
+}
+
+// Every .s file in a directory, with the bios.cfg next to it
+fn sources(dir: &Path) -> Vec<PathBuf> {
+    let mut sources: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|error| panic!("Problem reading {}: {}", dir.display(), error))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "s"))
+        .collect();
+    sources.sort();
+    sources
+}
 
 fn main() {
     println!("cargo:rerun-if-changed=examples/");
+    println!("cargo:rerun-if-changed=src/asm.rs");
+    println!("cargo:rerun-if-changed=src/asm/");
+    println!("cargo:rerun-if-changed=src/cpu6502/operation.rs");
     // Define the subdirectory path relative to the project root
     let subdirectory = "examples/";
 
@@ -18,13 +44,29 @@ fn main() {
         panic!("Subdirectory {} does not exist", subdirectory);
     }
 
-    // Run `make` in the subdirectory
-    let status = Command::new("make")
-        .current_dir(subdir_path.clone())
-        .status()
-        .expect("Failed to run `make` command");
-
-    if !status.success() {
-        panic!("`make` command failed with status: {}", status);
+    // Same outputs as `ld65 -o name -Ln name.lbl --dbgfile name.dbg -C bios.cfg`
+    let mut dirs = vec![subdir_path.clone()];
+    for entry in fs::read_dir(&subdir_path).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            dirs.push(path);
+        }
+    }
+    for dir in dirs {
+        for source in sources(&dir) {
+            let config = dir.join("bios.cfg");
+            let output = source.with_extension("");
+            let labels = source.with_extension("lbl");
+            let dbgfile = source.with_extension("dbg");
+            if let Err(error) = src::asm::build(
+                &source,
+                Some(config.as_path()).filter(|path| path.exists()),
+                &output,
+                Some(&labels),
+                Some(&dbgfile),
+            ) {
+                panic!("Problem assembling {}: {}", source.display(), error);
+            }
+        }
     }
 }
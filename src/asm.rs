@@ -0,0 +1,982 @@
+// A two pass assembler for the subset of ca65 the examples/ sources use,
+// linked into an image with an ld65 style config (see asm/config.rs).
+// build.rs compiles this module too so the examples build without cc65,
+// which is why it only depends on std and reaches the opcode table through
+// `super::` rather than `crate::`.
+use super::cpu6502::operation::{self, AddressingMode};
+use config::Config;
+use expr::{eval, split_args, tokenize, Token, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub mod config;
+pub mod expr;
+
+enum Stmt {
+    Label(String),
+    Equate(String, Vec<Token>),
+    Directive(String, Vec<Token>),
+    Instruction(String, Vec<Token>),
+}
+
+struct Line {
+    number: usize,
+    stmt: Stmt,
+}
+
+enum Symbol {
+    // offset into a segment, placed by the linker
+    Label { segment: usize, offset: u32 },
+    // label after .org
+    Fixed(i64),
+    Equate(Vec<Token>),
+}
+
+struct Definition {
+    symbol: Symbol,
+    // statement that defined it, pass 1 only sees symbols defined earlier
+    index: usize,
+}
+
+struct Segment {
+    name: String,
+    bytes: Vec<u8>,
+    zero_page: bool,
+    // address and segment offset of the last .org
+    org: Option<(i64, u32)>,
+}
+
+// Bytes a source line put in a segment, for the dbgfile
+struct Span {
+    line: usize,
+    segment: usize,
+    offset: u32,
+    size: u32,
+    // element type of a data directive, code has none
+    data: Option<u8>,
+}
+
+// cc65 gentype encodings ca65 uses for the types of data spans
+const GT_BYTE: u8 = 0x09;
+const GT_WORD: u8 = 0x29;
+const GT_PTR: u8 = 0x2a;
+const GT_TYPE_ARRAY: u8 = 0x04;
+
+// An array of count elements: the array tag with the size of the count in
+// bits 5-6, the count little endian, then the element type
+fn array_type(count: u32, element: u8) -> String {
+    let used = (4 - count.leading_zeros() / 8).max(1) as usize;
+    let mut text = format!("{:02X}", GT_TYPE_ARRAY | ((used as u8 - 1) << 5));
+    for byte in &count.to_le_bytes()[..used] {
+        text.push_str(&format!("{:02X}", byte));
+    }
+    text.push_str(&format!("{:02X}", element));
+    text
+}
+
+pub struct Output {
+    pub image: Vec<u8>,
+    pub labels: Vec<(String, u16)>,
+    // ld65 --dbgfile style records, empty from assemble_at
+    pub dbgfile: String,
+}
+
+struct Assembler<'a> {
+    config: &'a Config,
+    lines: Vec<Line>,
+    symbols: HashMap<String, Definition>,
+    segments: Vec<Segment>,
+    current: usize,
+    index: usize,
+    // segment start addresses, None during pass 1
+    bases: Option<Vec<u32>>,
+    // addressing modes picked in pass 1 so pass 2 emits the same sizes
+    modes: HashMap<usize, AddressingMode>,
+    spans: Vec<Span>,
+}
+
+// Splits source into statements, expanding .define macros on the way
+fn parse(source: &str) -> Result<Vec<Line>, (usize, String)> {
+    let mut lines = Vec::new();
+    let mut defines: HashMap<String, Vec<Token>> = HashMap::new();
+    let expand = |tokens: Vec<Token>, defines: &HashMap<String, Vec<Token>>| -> Vec<Token> {
+        tokens
+            .into_iter()
+            .flat_map(|token| match &token {
+                Token::Ident(name) if defines.contains_key(name) => defines[name].clone(),
+                _ => vec![token],
+            })
+            .collect()
+    };
+    for (i, text) in source.lines().enumerate() {
+        let number = i + 1;
+        let tokens = tokenize(text).map_err(|error| (number, error))?;
+        if let [Token::Ident(directive), rest @ ..] = &tokens[..] {
+            if directive.eq_ignore_ascii_case(".define") {
+                match rest {
+                    [Token::Ident(name), body @ ..] => {
+                        let body = expand(body.to_vec(), &defines);
+                        defines.insert(name.clone(), body);
+                    }
+                    _ => return Err((number, ".define needs a name".to_string())),
+                }
+                continue;
+            }
+        }
+        let mut rest = &expand(tokens, &defines)[..];
+        loop {
+            let stmt = match rest {
+                [] => break,
+                [Token::Ident(name), Token::Punct(':'), tail @ ..] if !name.starts_with('.') => {
+                    rest = tail;
+                    lines.push(Line {
+                        number,
+                        stmt: Stmt::Label(name.clone()),
+                    });
+                    continue;
+                }
+                [Token::Ident(name), Token::Punct('='), tail @ ..] => {
+                    Stmt::Equate(name.clone(), tail.to_vec())
+                }
+                [Token::Ident(name), tail @ ..] if name.starts_with('.') => {
+                    Stmt::Directive(name.to_lowercase(), tail.to_vec())
+                }
+                [Token::Ident(name), tail @ ..] => {
+                    Stmt::Instruction(name.to_uppercase(), tail.to_vec())
+                }
+                [token, ..] => {
+                    return Err((
+                        number,
+                        format!(
+                            "expected a label, directive or instruction, got '{}'",
+                            token
+                        ),
+                    ))
+                }
+            };
+            lines.push(Line { number, stmt });
+            break;
+        }
+    }
+    Ok(lines)
+}
+
+fn is_register(token: &Token, register: &str) -> bool {
+    matches!(token, Token::Ident(name) if name.eq_ignore_ascii_case(register))
+}
+
+// The position of the `)` closing the `(` at tokens[0]
+fn closing_paren(tokens: &[Token]) -> Option<usize> {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Punct('(') => depth += 1,
+            Token::Punct(')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[derive(PartialEq)]
+enum Size {
+    Auto,
+    ZeroPage,
+    Absolute,
+}
+
+impl Assembler<'_> {
+    fn final_pass(&self) -> bool {
+        self.bases.is_some()
+    }
+
+    // Address of the next byte in the current segment, when it is known
+    fn here(&self) -> Option<i64> {
+        let segment = &self.segments[self.current];
+        let offset = segment.bytes.len() as u32;
+        match (segment.org, &self.bases) {
+            (Some((addr, at)), _) => Some(addr + (offset - at) as i64),
+            (None, Some(bases)) => Some((bases[self.current] + offset) as i64),
+            (None, None) => None,
+        }
+    }
+
+    fn value_of(&self, name: &str, depth: usize) -> Result<Value, String> {
+        // forward references in pass 1 are assumed to be 16 bit addresses
+        let unknown = Value {
+            value: 0,
+            wide: true,
+        };
+        if name == "*" {
+            return Ok(self.here().map_or(unknown, Value::constant));
+        }
+        let definition = match self.symbols.get(name) {
+            Some(def) if self.final_pass() || def.index < self.index => def,
+            _ if !self.final_pass() => return Ok(unknown),
+            _ => return Err(format!("symbol '{}' is undefined", name)),
+        };
+        match &definition.symbol {
+            Symbol::Label { segment, offset } => Ok(Value {
+                value: match &self.bases {
+                    Some(bases) => (bases[*segment] + offset) as i64,
+                    None => 0,
+                },
+                wide: !self.segments[*segment].zero_page,
+            }),
+            Symbol::Fixed(value) => Ok(Value::constant(*value)),
+            Symbol::Equate(_) if depth > 32 => {
+                Err(format!("symbol '{}' is defined in terms of itself", name))
+            }
+            Symbol::Equate(tokens) => self.eval_at(tokens, depth + 1),
+        }
+    }
+
+    fn eval_at(&self, tokens: &[Token], depth: usize) -> Result<Value, String> {
+        eval(tokens, &mut |name| self.value_of(name, depth))
+    }
+
+    fn eval(&self, tokens: &[Token]) -> Result<Value, String> {
+        self.eval_at(tokens, 0)
+    }
+
+    // A value pass 1 already needs, eg a .res count
+    fn constant(&self, tokens: &[Token], what: &str) -> Result<i64, String> {
+        let value = self.eval(tokens)?;
+        if value.wide {
+            return Err(format!("{} must be a constant", what));
+        }
+        Ok(value.value)
+    }
+
+    fn byte(&self, value: Value) -> Result<u8, String> {
+        if self.final_pass() && !(-0x80..=0xFF).contains(&value.value) {
+            return Err(format!(
+                "range error, {} does not fit in a byte",
+                value.value
+            ));
+        }
+        Ok(value.value as u8)
+    }
+
+    fn word(&self, value: Value) -> Result<u16, String> {
+        if self.final_pass() && !(-0x8000..=0xFFFF).contains(&value.value) {
+            return Err(format!(
+                "range error, {} does not fit in a word",
+                value.value
+            ));
+        }
+        Ok(value.value as u16)
+    }
+
+    fn define(&mut self, name: &str, symbol: Symbol) -> Result<(), String> {
+        if self.final_pass() {
+            return Ok(());
+        }
+        if self.symbols.contains_key(name) {
+            return Err(format!("symbol '{}' is already defined", name));
+        }
+        let index = self.index;
+        self.symbols
+            .insert(name.to_string(), Definition { symbol, index });
+        Ok(())
+    }
+
+    fn select_segment(&mut self, name: &str, zero_page: bool) {
+        self.current = match self.segments.iter().position(|s| s.name == name) {
+            Some(index) => index,
+            None => {
+                let configured = self.config.segment(name).is_some_and(|s| s.zero_page);
+                self.segments.push(Segment {
+                    name: name.to_string(),
+                    bytes: Vec::new(),
+                    zero_page: zero_page || configured || name == "ZEROPAGE",
+                    org: None,
+                });
+                self.segments.len() - 1
+            }
+        };
+    }
+
+    fn directive(&mut self, name: &str, args: &[Token]) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        match name {
+            ".segment" => match args {
+                [Token::Str(segment)] => self.select_segment(segment, false),
+                [Token::Str(segment), Token::Punct(':'), Token::Ident(kind)] => {
+                    let zero_page = kind.eq_ignore_ascii_case("zeropage");
+                    self.select_segment(segment, zero_page)
+                }
+                _ => return Err(".segment needs a quoted name".to_string()),
+            },
+            ".byte" | ".byt" => {
+                for arg in split_args(args) {
+                    match arg {
+                        [Token::Str(text)] => bytes.extend(text.bytes()),
+                        _ => bytes.push(self.byte(self.eval(arg)?)?),
+                    }
+                }
+            }
+            ".word" | ".addr" => {
+                for arg in split_args(args) {
+                    bytes.extend(self.word(self.eval(arg)?)?.to_le_bytes());
+                }
+            }
+            ".res" => {
+                let args = split_args(args);
+                let count = match args.first() {
+                    Some(count) => self.constant(count, ".res count")?,
+                    None => return Err(".res needs a count".to_string()),
+                };
+                let fill = match args.get(1) {
+                    Some(fill) => self.byte(self.eval(fill)?)?,
+                    None => 0,
+                };
+                bytes.resize(count.max(0) as usize, fill);
+            }
+            ".org" => {
+                let addr = self.constant(args, ".org address")?;
+                let segment = &mut self.segments[self.current];
+                segment.org = Some((addr, segment.bytes.len() as u32));
+            }
+            ".setcpu" => match args {
+                [Token::Str(cpu)] if cpu == "6502" => {}
+                _ => return Err("only .setcpu \"6502\" is supported".to_string()),
+            },
+            _ => return Err(format!("unsupported directive {}", name)),
+        }
+        Ok(bytes)
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operand: &[Token]) -> Result<Vec<u8>, String> {
+        let opcodes: Vec<(u8, AddressingMode)> = (0..=0xFF)
+            .filter_map(|opcode| operation::lookup_opcode(opcode).map(|op| (opcode, op)))
+            .filter(|(_, op)| format!("{:?}", op.instruction_type) == mnemonic)
+            .map(|(opcode, op)| (opcode, op.mode))
+            .collect();
+        if opcodes.is_empty() {
+            return Err(format!("unknown instruction '{}'", mnemonic));
+        }
+        let opcode_for = |mode: AddressingMode| {
+            opcodes
+                .iter()
+                .find(|(_, m)| *m == mode)
+                .map(|(opcode, _)| *opcode)
+        };
+        let has = |mode: AddressingMode| opcode_for(mode).is_some();
+
+        // candidate zero page and absolute modes for the operand syntax
+        let (zero_page, absolute, expr, size) = match operand {
+            [] if has(AddressingMode::Implied) => {
+                (None, Some(AddressingMode::Implied), operand, Size::Auto)
+            }
+            [] => (None, Some(AddressingMode::Accumulator), operand, Size::Auto),
+            [a] if is_register(a, "A") => (
+                None,
+                Some(AddressingMode::Accumulator),
+                &operand[..0],
+                Size::Auto,
+            ),
+            [Token::Punct('#'), value @ ..] => {
+                (Some(AddressingMode::Immediate), None, value, Size::Auto)
+            }
+            [Token::Punct('('), inner @ .., Token::Punct(','), x, Token::Punct(')')]
+                if is_register(x, "X") && closing_paren(operand) == Some(operand.len() - 1) =>
+            {
+                (
+                    Some(AddressingMode::ZeroPageIndirectIndexedX),
+                    Some(AddressingMode::AbsoluteIndirectX),
+                    inner,
+                    Size::Auto,
+                )
+            }
+            [Token::Punct('('), inner @ .., Token::Punct(')'), Token::Punct(','), y]
+                if is_register(y, "Y") && closing_paren(operand) == Some(operand.len() - 3) =>
+            {
+                (
+                    Some(AddressingMode::ZeroPageIndirectIndexedY),
+                    Some(AddressingMode::AbsoluteIndirectY),
+                    inner,
+                    Size::Auto,
+                )
+            }
+            [Token::Punct('('), inner @ .., Token::Punct(')')]
+                if closing_paren(operand) == Some(operand.len() - 1) =>
+            {
+                (
+                    None,
+                    Some(AddressingMode::AbsoluteIndirect),
+                    inner,
+                    Size::Auto,
+                )
+            }
+            _ => {
+                let (size, rest) = match operand {
+                    [Token::Ident(prefix), Token::Punct(':'), rest @ ..] if prefix == "z" => {
+                        (Size::ZeroPage, rest)
+                    }
+                    [Token::Ident(prefix), Token::Punct(':'), rest @ ..] if prefix == "a" => {
+                        (Size::Absolute, rest)
+                    }
+                    _ => (Size::Auto, operand),
+                };
+                match rest {
+                    [value @ .., Token::Punct(','), x] if is_register(x, "X") => (
+                        Some(AddressingMode::ZeroPageX),
+                        Some(AddressingMode::AbsoluteXIndexed),
+                        value,
+                        size,
+                    ),
+                    [value @ .., Token::Punct(','), y] if is_register(y, "Y") => (
+                        Some(AddressingMode::ZeroPageY),
+                        Some(AddressingMode::AbsoluteYIndexed),
+                        value,
+                        size,
+                    ),
+                    _ if has(AddressingMode::Relative) => {
+                        (None, Some(AddressingMode::Relative), rest, size)
+                    }
+                    _ => (
+                        Some(AddressingMode::ZeroPage),
+                        Some(AddressingMode::Absolute),
+                        rest,
+                        size,
+                    ),
+                }
+            }
+        };
+        let value = match absolute {
+            Some(AddressingMode::Implied | AddressingMode::Accumulator) => Value::constant(0),
+            _ => self.eval(expr)?,
+        };
+
+        let mode = match self.modes.get(&self.index) {
+            Some(mode) => *mode,
+            None => {
+                let zero_page = zero_page.filter(|mode| has(*mode));
+                let absolute = absolute.filter(|mode| has(*mode));
+                let mode = match (zero_page, absolute) {
+                    (Some(zp), Some(abs)) => {
+                        if size == Size::ZeroPage || (size == Size::Auto && value.is_byte()) {
+                            Some(zp)
+                        } else {
+                            Some(abs)
+                        }
+                    }
+                    (Some(_), None) if size == Size::Absolute => None,
+                    (Some(zp), None) => Some(zp),
+                    (None, Some(_)) if size == Size::ZeroPage => None,
+                    (None, Some(abs)) => Some(abs),
+                    (None, None) => None,
+                }
+                .ok_or(format!("addressing mode not allowed for {}", mnemonic))?;
+                self.modes.insert(self.index, mode);
+                mode
+            }
+        };
+
+        let mut bytes = vec![opcode_for(mode).unwrap()];
+        match mode {
+            AddressingMode::Implied | AddressingMode::Accumulator => {}
+            AddressingMode::Relative => {
+                let offset = match self.here() {
+                    Some(here) if self.final_pass() => value.value - (here + 2),
+                    _ => 0,
+                };
+                if offset > 0x7F {
+                    return Err(format!("branch out of range by {} bytes", offset - 0x7F));
+                }
+                if offset < -0x80 {
+                    return Err(format!("branch out of range by {} bytes", -0x80 - offset));
+                }
+                bytes.push(offset as u8);
+            }
+            AddressingMode::Immediate => bytes.push(self.byte(value)?),
+            _ if operation::lookup_opcode(bytes[0])
+                .unwrap()
+                .instruction_byte_length
+                == 2 =>
+            {
+                if self.final_pass() && !(0..=0xFF).contains(&value.value) {
+                    return Err(format!(
+                        "range error, ${:x} is not a zero page address",
+                        value.value
+                    ));
+                }
+                bytes.push(value.value as u8);
+            }
+            _ => bytes.extend(self.word(value)?.to_le_bytes()),
+        }
+        Ok(bytes)
+    }
+
+    fn pass(&mut self) -> Result<(), (usize, String)> {
+        for segment in &mut self.segments {
+            segment.bytes.clear();
+            segment.org = None;
+        }
+        self.spans.clear();
+        self.select_segment("CODE", false);
+        for index in 0..self.lines.len() {
+            self.index = index;
+            let line = &self.lines[index];
+            let number = line.number;
+            let data = match &line.stmt {
+                Stmt::Directive(name, _) => match name.as_str() {
+                    ".byte" | ".byt" | ".res" => Some(GT_BYTE),
+                    ".word" => Some(GT_WORD),
+                    ".addr" => Some(GT_PTR),
+                    _ => None,
+                },
+                _ => None,
+            };
+            let result = match &line.stmt {
+                Stmt::Label(name) => {
+                    let name = name.clone();
+                    let symbol = match self
+                        .here()
+                        .filter(|_| self.segments[self.current].org.is_some())
+                    {
+                        Some(addr) => Symbol::Fixed(addr),
+                        None => Symbol::Label {
+                            segment: self.current,
+                            offset: self.segments[self.current].bytes.len() as u32,
+                        },
+                    };
+                    self.define(&name, symbol).map(|_| Vec::new())
+                }
+                Stmt::Equate(name, tokens) => {
+                    let (name, tokens) = (name.clone(), tokens.clone());
+                    self.define(&name, Symbol::Equate(tokens))
+                        .map(|_| Vec::new())
+                }
+                Stmt::Directive(name, args) => {
+                    let (name, args) = (name.clone(), args.clone());
+                    self.directive(&name, &args)
+                }
+                Stmt::Instruction(mnemonic, operand) => {
+                    let (mnemonic, operand) = (mnemonic.clone(), operand.clone());
+                    self.instruction(&mnemonic, &operand)
+                }
+            };
+            match result {
+                Ok(bytes) => {
+                    let segment = &mut self.segments[self.current];
+                    if !bytes.is_empty() {
+                        self.spans.push(Span {
+                            line: number,
+                            segment: self.current,
+                            offset: segment.bytes.len() as u32,
+                            size: bytes.len() as u32,
+                            data,
+                        });
+                    }
+                    segment.bytes.extend(bytes);
+                }
+                Err(error) => return Err((number, error)),
+            }
+        }
+        Ok(())
+    }
+
+    fn link(&self, bases: &[u32]) -> Vec<u8> {
+        let mut image = Vec::new();
+        for area in &self.config.memory {
+            let mut bytes = vec![area.fill_value; area.size as usize];
+            let mut used = 0;
+            for (segment, base) in self.segments.iter().zip(bases) {
+                match self.config.segment(&segment.name) {
+                    Some(config) if config.load == area.name && !config.bss => {}
+                    _ => continue,
+                }
+                let start = (base - area.start) as usize;
+                bytes[start..start + segment.bytes.len()].copy_from_slice(&segment.bytes);
+                used = used.max(start + segment.bytes.len());
+            }
+            if !area.fill {
+                bytes.truncate(used);
+            }
+            image.extend(bytes);
+        }
+        image
+    }
+
+    // The records of an ld65 --dbgfile that DebugInfo reads: the source file,
+    // segments, a span and line per source line that emitted bytes, the
+    // symbols and the types of data spans. Scopes and modules are left out.
+    fn dbgfile(&self, file_name: &str, source: &str, bases: &[u32]) -> String {
+        let mut out = "version\tmajor=2,minor=0\n".to_string();
+        out.push_str(&format!(
+            "file\tid=0,name=\"{}\",size={},mtime=0x00000000,mod=0\n",
+            file_name,
+            source.len()
+        ));
+        for (id, (segment, base)) in self.segments.iter().zip(bases).enumerate() {
+            out.push_str(&format!(
+                "seg\tid={},name=\"{}\",start=0x{:06X},size=0x{:04X},addrsize={}\n",
+                id,
+                segment.name,
+                base,
+                segment.bytes.len(),
+                if segment.zero_page {
+                    "zeropage"
+                } else {
+                    "absolute"
+                }
+            ));
+        }
+        let mut types: Vec<String> = Vec::new();
+        for (id, span) in self.spans.iter().enumerate() {
+            out.push_str(&format!(
+                "span\tid={},seg={},start={},size={}",
+                id, span.segment, span.offset, span.size
+            ));
+            if let Some(element) = span.data {
+                let element_size = ((element >> 5) & 3) as u32 + 1;
+                let value = array_type(span.size / element_size, element);
+                let type_id = match types.iter().position(|known| *known == value) {
+                    Some(type_id) => type_id,
+                    None => {
+                        types.push(value);
+                        types.len() - 1
+                    }
+                };
+                out.push_str(&format!(",type={}", type_id));
+            }
+            out.push('\n');
+        }
+        for (id, span) in self.spans.iter().enumerate() {
+            out.push_str(&format!(
+                "line\tid={},file=0,line={},span={}\n",
+                id, span.line, id
+            ));
+        }
+        let mut names: Vec<&String> = self.symbols.keys().collect();
+        names.sort();
+        let mut id = 0;
+        for name in names {
+            let value = match self.value_of(name, 0) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            let (kind, seg) = match self.symbols[name].symbol {
+                Symbol::Label { segment, .. } => ("lab", format!(",seg={}", segment)),
+                Symbol::Fixed(_) => ("lab", String::new()),
+                Symbol::Equate(_) => ("equ", String::new()),
+            };
+            out.push_str(&format!(
+                "sym\tid={},name=\"{}\",addrsize={},val=0x{:X}{},type={}\n",
+                id,
+                name,
+                if value.wide { "absolute" } else { "zeropage" },
+                value.value,
+                seg,
+                kind
+            ));
+            id += 1;
+        }
+        for (id, value) in types.iter().enumerate() {
+            out.push_str(&format!("type\tid={},val=\"{}\"\n", id, value));
+        }
+        out
+    }
+}
+
+pub fn assemble(file_name: &str, source: &str, config: &Config) -> Result<Output, String> {
+    let at = |(line, error): (usize, String)| format!("{}:{}: {}", file_name, line, error);
+    let mut assembler = Assembler {
+        config,
+        lines: parse(source).map_err(at)?,
+        symbols: HashMap::new(),
+        segments: Vec::new(),
+        current: 0,
+        index: 0,
+        bases: None,
+        modes: HashMap::new(),
+        spans: Vec::new(),
+    };
+    assembler.pass().map_err(at)?;
+
+    // empty segments the config does not know about are dropped
+    let sizes: Vec<(String, u32)> = assembler
+        .segments
+        .iter()
+        .filter(|segment| !segment.bytes.is_empty() || config.segment(&segment.name).is_some())
+        .map(|segment| (segment.name.clone(), segment.bytes.len() as u32))
+        .collect();
+    let placed = config
+        .layout(&sizes)
+        .map_err(|error| format!("{}: {}", file_name, error))?;
+    let bases: Vec<u32> = assembler
+        .segments
+        .iter()
+        .map(|segment| {
+            sizes
+                .iter()
+                .position(|(name, _)| *name == segment.name)
+                .map_or(0, |index| placed[index])
+        })
+        .collect();
+    assembler.bases = Some(bases.clone());
+    assembler.pass().map_err(at)?;
+
+    let mut labels: Vec<(String, u16)> = assembler
+        .symbols
+        .keys()
+        .filter_map(|name| {
+            let value = assembler.value_of(name, 0).ok()?.value;
+            let addr = u16::try_from(value).ok()?;
+            Some((name.clone(), addr))
+        })
+        .collect();
+    labels.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+    Ok(Output {
+        image: assembler.link(&bases),
+        labels,
+        dbgfile: assembler.dbgfile(file_name, source, &bases),
+    })
+}
+
+// Assembles source straight to addr for the debugger's line assembler,
+// with `known` symbols predefined. Returns the bytes and the labels the
+// source defined.
+pub fn assemble_at(source: &str, addr: u16, known: &[(String, u16)]) -> Result<Output, String> {
+    let mut lines = parse(source).map_err(|(_, error)| error)?;
+    lines.insert(
+        0,
+        Line {
+            number: 0,
+            stmt: Stmt::Directive(".org".to_string(), vec![Token::Number(addr as i64)]),
+        },
+    );
+    let config = Config::default();
+    let mut assembler = Assembler {
+        config: &config,
+        lines,
+        symbols: HashMap::new(),
+        segments: Vec::new(),
+        current: 0,
+        index: 0,
+        bases: None,
+        modes: HashMap::new(),
+        spans: Vec::new(),
+    };
+    for (name, value) in known {
+        assembler.symbols.insert(
+            name.clone(),
+            Definition {
+                symbol: Symbol::Fixed(*value as i64),
+                index: 0,
+            },
+        );
+    }
+    assembler.pass().map_err(|(_, error)| error)?;
+    if assembler.segments.len() > 1 {
+        return Err(".segment is not supported here".to_string());
+    }
+    assembler.bases = Some(vec![0]);
+    assembler.pass().map_err(|(_, error)| error)?;
+
+    let mut labels = Vec::new();
+    for (name, definition) in &assembler.symbols {
+        if let Symbol::Fixed(value) = definition.symbol {
+            if !known.iter().any(|(known, _)| known == name) {
+                labels.push((name.clone(), value as u16));
+            }
+        }
+    }
+    Ok(Output {
+        image: assembler.segments.swap_remove(0).bytes,
+        labels,
+        dbgfile: String::new(),
+    })
+}
+
+// Leaves files that would not change alone, so build.rs does not keep
+// retriggering itself through rerun-if-changed
+fn write_if_changed(path: &Path, contents: &[u8]) -> Result<(), String> {
+    if fs::read(path).is_ok_and(|old| old == contents) {
+        return Ok(());
+    }
+    fs::write(path, contents).map_err(|error| format!("{}: {}", path.display(), error))
+}
+
+// What `ca65 -g source.s && ld65 -C config -o output -Ln labels
+// --dbgfile dbgfile source.o` would produce
+pub fn build(
+    source: &Path,
+    config: Option<&Path>,
+    output: &Path,
+    labels: Option<&Path>,
+    dbgfile: Option<&Path>,
+) -> Result<usize, String> {
+    let read = |path: &Path| {
+        fs::read_to_string(path).map_err(|error| format!("{}: {}", path.display(), error))
+    };
+    let config = match config {
+        Some(path) => {
+            config::parse(&read(path)?).map_err(|error| format!("{}: {}", path.display(), error))?
+        }
+        None => Config::default(),
+    };
+    let name = source.file_name().unwrap_or_default().to_string_lossy();
+    let assembled = assemble(&name, &read(source)?, &config)?;
+    write_if_changed(output, &assembled.image)?;
+    if let Some(path) = labels {
+        let text: String = assembled
+            .labels
+            .iter()
+            .map(|(name, addr)| format!("al {:06X} .{}\n", addr, name))
+            .collect();
+        write_if_changed(path, text.as_bytes())?;
+    }
+    if let Some(path) = dbgfile {
+        write_if_changed(path, assembled.dbgfile.as_bytes())?;
+    }
+    Ok(assembled.image.len())
+}
+
+// `rust6502 asm`. Like build.rs does for examples/, a bios.cfg next to the
+// source is the default config and the output is the source without its
+// extension.
+pub fn run(
+    source: &str,
+    config: Option<&str>,
+    output: Option<&str>,
+    labels: Option<&str>,
+    dbgfile: Option<&str>,
+) {
+    let source = Path::new(source);
+    let config: Option<PathBuf> = match config {
+        Some(config) => Some(PathBuf::from(config)),
+        None => Some(source.with_file_name("bios.cfg")).filter(|path| path.exists()),
+    };
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None => source.with_extension(""),
+    };
+    let labels = labels.map(Path::new);
+    match build(
+        source,
+        config.as_deref(),
+        &output,
+        labels,
+        dbgfile.map(Path::new),
+    ) {
+        Ok(size) => println!("Wrote {} ({} bytes)", output.display(), size),
+        Err(error) => panic!("Problem assembling: {}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::{assemble, assemble_at, config};
+    use crate::debug_info::{DebugInfo, SourceLine};
+    use crate::disasm::source::{to_ca65, trace, Image};
+    use crate::symbols::SymbolTable;
+    use std::fs;
+    use std::path::Path;
+
+    fn example(path: &str) -> (String, Vec<u8>, config::Config) {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let source = root.join(path).with_extension("s");
+        let config = source.with_file_name("bios.cfg");
+        (
+            fs::read_to_string(source).unwrap(),
+            fs::read(root.join(path)).unwrap(),
+            config::parse(&fs::read_to_string(config).unwrap()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_examples_match_cc65_builds() {
+        for path in ["hello_world", "count_up", "wozmon/wozmon"] {
+            let (source, binary, config) = example(path);
+            let output = assemble(path, &source, &config).unwrap();
+            assert!(
+                output.image == binary,
+                "{} differs from the ld65 build",
+                path
+            );
+        }
+        let (source, _, config) = example("hello_world");
+        let labels = assemble("hello_world", &source, &config).unwrap().labels;
+        assert!(labels.contains(&("index".to_string(), 0x0f)));
+        assert!(labels.contains(&("print".to_string(), 0x0611)));
+    }
+
+    #[test]
+    fn test_dbgfile() {
+        let (source, _, config) = example("hello_world");
+        let output = assemble("hello_world.s", &source, &config).unwrap();
+        let info = DebugInfo::parse(&output.dbgfile).unwrap();
+        assert_eq!(info.file_name(0), "hello_world.s");
+        assert_eq!(info.line_at(0x0602), Some(SourceLine { file: 0, line: 13 }));
+        // line 14 is blank, the next line with code is used
+        assert_eq!(info.addr_of_line("hello_world.s", 14), Some(0x0604));
+        assert!(info
+            .symbols
+            .iter()
+            .any(|sym| sym.name == "print" && sym.value == 0x0611 && sym.is_label));
+        // the string and the vectors are typed data even where they decode,
+        // `.word $0000` as two BRKs
+        let lines: Vec<usize> = info
+            .code_lines()
+            .iter()
+            .map(|(line, _)| line.line)
+            .collect();
+        assert!(lines.contains(&12) && lines.contains(&33));
+        assert!(!lines.iter().any(|line| [7, 9, 36, 37, 38].contains(line)));
+        assert!(output.dbgfile.contains("type\tid=0,val=\"040F09\"\n"));
+    }
+
+    #[test]
+    fn test_disassembly_round_trips() {
+        for (path, entry) in [("hello_world", 0x0600), ("wozmon/wozmon", 0xff00)] {
+            let (_, binary, _) = example(path);
+            let image = Image::new(&binary, 0).unwrap();
+            let listing = trace(&image, &[entry], &SymbolTable::default());
+            let source = to_ca65(&image, &listing, path);
+            let output = assemble(path, &source, &config::Config::default()).unwrap();
+            assert!(output.image == binary, "{} does not round trip", path);
+        }
+    }
+
+    #[test]
+    fn test_assemble_at() {
+        let known = vec![("DSP".to_string(), 0xfe00)];
+        let output = assemble_at("loop: STA DSP", 0x0600, &known).unwrap();
+        assert_eq!(output.image, vec![0x8d, 0x00, 0xfe]);
+        assert_eq!(output.labels, vec![("loop".to_string(), 0x0600)]);
+        let output = assemble_at("BNE *-2", 0x0603, &known).unwrap();
+        assert_eq!(output.image, vec![0xd0, 0xfc]);
+        assert!(assemble_at("LDA #", 0x0600, &known).is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let config = config::Config::default();
+        let error = |source: &str| assemble("t.s", source, &config).err().unwrap();
+        assert_eq!(
+            error("  LDA #$100"),
+            "t.s:1: range error, 256 does not fit in a byte"
+        );
+        assert_eq!(error("x:\nx:"), "t.s:2: symbol 'x' is already defined");
+        assert_eq!(
+            error("  JMP nowhere"),
+            "t.s:1: symbol 'nowhere' is undefined"
+        );
+        assert_eq!(
+            error("  STX $1234,X"),
+            "t.s:1: addressing mode not allowed for STX"
+        );
+        assert_eq!(error("  FOO"), "t.s:1: unknown instruction 'FOO'");
+        let far = "  BNE far\n  .res 200\nfar:";
+        assert_eq!(error(far), "t.s:1: branch out of range by 73 bytes");
+    }
+}
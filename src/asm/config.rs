@@ -0,0 +1,295 @@
+// The MEMORY and SEGMENTS blocks of an ld65 linker config such as
+// examples/bios.cfg, and the layout of segments into memory areas.
+//
+// MEMORY {
+//   ROM: start = $0600, size = $f9fa, type = ro, fill = yes;
+// }
+// SEGMENTS {
+//   CODE: load = "ROM", type = ro;
+// }
+
+pub struct MemoryArea {
+    pub name: String,
+    pub start: u32,
+    pub size: u32,
+    pub fill: bool,
+    pub fill_value: u8,
+}
+
+pub struct Segment {
+    pub name: String,
+    pub load: String,
+    pub zero_page: bool,
+    pub bss: bool,
+    pub start: Option<u32>,
+    pub align: u32,
+}
+
+pub struct Config {
+    pub memory: Vec<MemoryArea>,
+    pub segments: Vec<Segment>,
+}
+
+// What ld65 -t none gives: the whole address space as one area that is
+// written out up to its last byte
+impl Default for Config {
+    fn default() -> Config {
+        let segment = |name: &str| Segment {
+            name: name.to_string(),
+            load: "MAIN".to_string(),
+            zero_page: name == "ZEROPAGE",
+            bss: false,
+            start: None,
+            align: 1,
+        };
+        Config {
+            memory: vec![MemoryArea {
+                name: "MAIN".to_string(),
+                start: 0,
+                size: 0x10000,
+                fill: false,
+                fill_value: 0,
+            }],
+            segments: ["ZEROPAGE", "CODE", "RODATA", "DATA"]
+                .iter()
+                .map(|name| segment(name))
+                .collect(),
+        }
+    }
+}
+
+impl Config {
+    pub fn segment(&self, name: &str) -> Option<&Segment> {
+        self.segments.iter().find(|segment| segment.name == name)
+    }
+
+    // Start address of each named segment given the sizes the assembler
+    // produced, packed into their load areas in config order
+    pub fn layout(&self, sizes: &[(String, u32)]) -> Result<Vec<u32>, String> {
+        let mut bases = vec![0; sizes.len()];
+        for area in &self.memory {
+            let mut at = area.start;
+            for segment in self.segments.iter().filter(|s| s.load == area.name) {
+                let index = match sizes.iter().position(|(name, _)| *name == segment.name) {
+                    Some(index) => index,
+                    None => continue,
+                };
+                if let Some(start) = segment.start {
+                    if start < at {
+                        return Err(format!(
+                            "segment '{}' start ${:04x} overlaps the segment before it",
+                            segment.name, start
+                        ));
+                    }
+                    at = start;
+                }
+                at = at.div_ceil(segment.align) * segment.align;
+                bases[index] = at;
+                at += sizes[index].1;
+            }
+            if at > area.start + area.size {
+                return Err(format!(
+                    "memory area '{}' overflows by {} bytes",
+                    area.name,
+                    at - area.start - area.size
+                ));
+            }
+        }
+        for (name, _) in sizes {
+            match self.segment(name) {
+                Some(segment) if self.memory.iter().any(|area| area.name == segment.load) => {}
+                Some(segment) => {
+                    return Err(format!(
+                        "segment '{}' loads into unknown memory area '{}'",
+                        name, segment.load
+                    ))
+                }
+                None => return Err(format!("segment '{}' is not in the config", name)),
+            }
+        }
+        Ok(bases)
+    }
+}
+
+// Attribute values are numbers, bare words or quoted strings
+enum Value {
+    Number(u32),
+    Word(String),
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    if let Some(hex) = text.strip_prefix('$') {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = text.strip_prefix('%') {
+        u32::from_str_radix(bin, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn tokens(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '{' | '}' | ':' | '=' | ',' | ';' => tokens.push(c.to_string()),
+            '"' => {
+                let mut string = String::from('"');
+                for c in chars.by_ref() {
+                    if c == '"' {
+                        break;
+                    }
+                    string.push(c);
+                }
+                tokens.push(string);
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || "$%_.".contains(*c)) {
+                    word.push(c);
+                }
+                tokens.push(word);
+            }
+        }
+    }
+    tokens
+}
+
+type Entry = (String, Vec<(String, Value)>);
+
+// `NAME: key = value, key = value;` entries of one block
+fn parse_block(tokens: &[String]) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+    for entry in tokens.split(|token| token == ";") {
+        if entry.is_empty() {
+            continue;
+        }
+        let (name, attributes) = match entry {
+            [name, colon, rest @ ..] if colon == ":" => (name.clone(), rest),
+            _ => {
+                return Err(format!(
+                    "expected 'NAME: attributes;', got '{}'",
+                    entry.join(" ")
+                ))
+            }
+        };
+        let mut values = Vec::new();
+        for attribute in attributes.split(|token| token == ",") {
+            let (key, value) = match attribute {
+                [key, equals, value] if equals == "=" => (key.to_lowercase(), value),
+                _ => {
+                    return Err(format!(
+                        "bad attribute '{}' for {}",
+                        attribute.join(" "),
+                        name
+                    ))
+                }
+            };
+            let value = match value.strip_prefix('"') {
+                Some(string) => Value::Word(string.to_string()),
+                None => match parse_number(value) {
+                    Some(number) => Value::Number(number),
+                    None => Value::Word(value.clone()),
+                },
+            };
+            values.push((key, value));
+        }
+        entries.push((name, values));
+    }
+    Ok(entries)
+}
+
+fn number(name: &str, key: &str, value: &Value) -> Result<u32, String> {
+    match value {
+        Value::Number(number) => Ok(*number),
+        Value::Word(word) => Err(format!("{} for {} is not a number: '{}'", key, name, word)),
+    }
+}
+
+fn word<'a>(name: &str, key: &str, value: &'a Value) -> Result<&'a str, String> {
+    match value {
+        Value::Word(word) => Ok(word),
+        Value::Number(number) => Err(format!("{} for {} is not a name: {}", key, name, number)),
+    }
+}
+
+pub fn parse(text: &str) -> Result<Config, String> {
+    let tokens = tokens(text);
+    let mut config = Config {
+        memory: Vec::new(),
+        segments: Vec::new(),
+    };
+    let mut rest = &tokens[..];
+    while let [section, open, tail @ ..] = rest {
+        if open != "{" {
+            return Err(format!("expected '{{' after {}", section));
+        }
+        let close = tail
+            .iter()
+            .position(|token| token == "}")
+            .ok_or(format!("{} block is not closed", section))?;
+        let entries = parse_block(&tail[..close])?;
+        rest = &tail[close + 1..];
+        match section.to_uppercase().as_str() {
+            "MEMORY" => {
+                for (name, values) in entries {
+                    let mut area = MemoryArea {
+                        name: name.clone(),
+                        start: 0,
+                        size: 0,
+                        fill: false,
+                        fill_value: 0,
+                    };
+                    for (key, value) in &values {
+                        match key.as_str() {
+                            "start" => area.start = number(&name, key, value)?,
+                            "size" => area.size = number(&name, key, value)?,
+                            "fill" => area.fill = word(&name, key, value)? == "yes",
+                            "fillval" => area.fill_value = number(&name, key, value)? as u8,
+                            "type" | "file" => {}
+                            _ => return Err(format!("unsupported attribute {} for {}", key, name)),
+                        }
+                    }
+                    config.memory.push(area);
+                }
+            }
+            "SEGMENTS" => {
+                for (name, values) in entries {
+                    let mut segment = Segment {
+                        name: name.clone(),
+                        load: String::new(),
+                        zero_page: false,
+                        bss: false,
+                        start: None,
+                        align: 1,
+                    };
+                    for (key, value) in &values {
+                        match key.as_str() {
+                            "load" => segment.load = word(&name, key, value)?.to_string(),
+                            "type" => {
+                                let kind = word(&name, key, value)?;
+                                segment.zero_page = kind == "zp";
+                                segment.bss = kind == "bss";
+                            }
+                            "start" => segment.start = Some(number(&name, key, value)?),
+                            "align" => segment.align = number(&name, key, value)?.max(1),
+                            "optional" | "define" => {}
+                            _ => return Err(format!("unsupported attribute {} for {}", key, name)),
+                        }
+                    }
+                    if segment.load.is_empty() {
+                        return Err(format!("segment {} has no load area", name));
+                    }
+                    config.segments.push(segment);
+                }
+            }
+            _ => return Err(format!("unsupported config block {}", section)),
+        }
+    }
+    if let Some(token) = rest.first() {
+        return Err(format!("unexpected '{}' at the end of the config", token));
+    }
+    Ok(config)
+}
@@ -0,0 +1,234 @@
+// Tokens of one source line and the ca65 expressions built from them:
+// `$fe00`, `%1010`, `12`, `'c'`, symbols, `*` for the current address,
+// unary `<` `>` `-` `~` and binary `* / & + - | ^`.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Ident(String),
+    Number(i64),
+    Str(String),
+    Punct(char),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Str(text) => write!(f, "\"{}\"", text),
+            Token::Punct(c) => write!(f, "{}", c),
+        }
+    }
+}
+
+pub fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            ';' => break,
+            c if c.is_whitespace() => continue,
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => text.push(c),
+                        None => return Err("string is not closed".to_string()),
+                    }
+                }
+                Token::Str(text)
+            }
+            // no escapes, as ca65 without .feature string_escapes
+            '\'' => match (chars.next(), chars.next()) {
+                (Some(c), Some('\'')) => Token::Number(c as i64),
+                _ => return Err("character literal should be one character, eg 'c'".to_string()),
+            },
+            '$' | '%' => {
+                let radix = if c == '$' { 16 } else { 2 };
+                let mut digits = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_digit(radix)) {
+                    digits.push(c);
+                }
+                match i64::from_str_radix(&digits, radix) {
+                    Ok(value) => Token::Number(value),
+                    Err(_) => return Err(format!("invalid number '{}{}'", c, digits)),
+                }
+            }
+            c if c.is_ascii_digit() => {
+                let mut digits = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+                    digits.push(c);
+                }
+                match digits.parse() {
+                    Ok(value) => Token::Number(value),
+                    Err(_) => return Err(format!("invalid number '{}'", digits)),
+                }
+            }
+            c if c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '@' => {
+                let mut name = c.to_string();
+                while let Some(c) =
+                    chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '@')
+                {
+                    name.push(c);
+                }
+                Token::Ident(name)
+            }
+            c if ":=,#()<>+-*/&|^~".contains(c) => Token::Punct(c),
+            c => return Err(format!("unexpected character '{}'", c)),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+// A value and whether it is an address that must stay 16 bits wide, ie it
+// comes from a label outside the zero page or from a symbol not yet defined
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Value {
+    pub value: i64,
+    pub wide: bool,
+}
+
+impl Value {
+    pub fn constant(value: i64) -> Value {
+        Value { value, wide: false }
+    }
+
+    // Fits the zero page / an immediate byte
+    pub fn is_byte(&self) -> bool {
+        !self.wide && (0..=0xFF).contains(&self.value)
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    at: usize,
+    lookup: &'a mut dyn FnMut(&str) -> Result<Value, String>,
+}
+
+impl Parser<'_> {
+    fn next_punct(&mut self, options: &str) -> Option<char> {
+        match self.tokens.get(self.at) {
+            Some(Token::Punct(c)) if options.contains(*c) => {
+                self.at += 1;
+                Some(*c)
+            }
+            _ => None,
+        }
+    }
+
+    fn sum(&mut self) -> Result<Value, String> {
+        let mut left = self.product()?;
+        while let Some(op) = self.next_punct("+-|^") {
+            let right = self.product()?;
+            left = Value {
+                value: match op {
+                    '+' => left.value + right.value,
+                    '-' => left.value - right.value,
+                    '|' => left.value | right.value,
+                    _ => left.value ^ right.value,
+                },
+                wide: left.wide || right.wide,
+            };
+        }
+        Ok(left)
+    }
+
+    fn product(&mut self) -> Result<Value, String> {
+        let mut left = self.unary()?;
+        while let Some(op) = self.next_punct("*/&") {
+            let right = self.unary()?;
+            let value = match op {
+                '*' => left.value * right.value,
+                '&' => left.value & right.value,
+                _ if right.value == 0 => return Err("division by zero".to_string()),
+                _ => left.value / right.value,
+            };
+            left = Value {
+                value,
+                wide: left.wide || right.wide,
+            };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Value, String> {
+        match self.next_punct("<>-~") {
+            Some('<') => Ok(Value::constant(self.unary()?.value & 0xFF)),
+            Some('>') => Ok(Value::constant((self.unary()?.value >> 8) & 0xFF)),
+            Some('-') => {
+                let value = self.unary()?;
+                Ok(Value {
+                    value: -value.value,
+                    ..value
+                })
+            }
+            Some(_) => {
+                let value = self.unary()?;
+                Ok(Value {
+                    value: !value.value,
+                    ..value
+                })
+            }
+            None => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Value, String> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        match token {
+            Some(Token::Number(value)) => Ok(Value::constant(value)),
+            Some(Token::Ident(name)) => (self.lookup)(&name),
+            Some(Token::Punct('*')) => (self.lookup)("*"),
+            Some(Token::Punct('(')) => {
+                let value = self.sum()?;
+                match self.next_punct(")") {
+                    Some(_) => Ok(value),
+                    None => Err("expected ')'".to_string()),
+                }
+            }
+            Some(token) => Err(format!("unexpected '{}' in expression", token)),
+            None => Err("expression expected".to_string()),
+        }
+    }
+}
+
+pub fn eval(
+    tokens: &[Token],
+    lookup: &mut dyn FnMut(&str) -> Result<Value, String>,
+) -> Result<Value, String> {
+    let mut parser = Parser {
+        tokens,
+        at: 0,
+        lookup,
+    };
+    let value = parser.sum()?;
+    match tokens.get(parser.at) {
+        Some(token) => Err(format!("unexpected '{}' after expression", token)),
+        None => Ok(value),
+    }
+}
+
+// Splits on top level commas, `.byte 1, (2), 3`
+pub fn split_args(tokens: &[Token]) -> Vec<&[Token]> {
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Punct('(') => depth += 1,
+            Token::Punct(')') => depth -= 1,
+            Token::Punct(',') if depth == 0 => {
+                args.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if !tokens.is_empty() {
+        args.push(&tokens[start..]);
+    }
+    args
+}
@@ -0,0 +1,163 @@
+use crate::cpu6502::memory::{Access, AccessKind};
+use crate::cpu6502::Cpu6502;
+use crate::debug_info::DebugInfo;
+use std::fs;
+
+pub mod heatmap;
+
+// Bits of the raw coverage bitmap, one byte per address from $0000
+pub const EXECUTED: u8 = 0x01;
+pub const READ: u8 = 0x02;
+pub const WRITTEN: u8 = 0x04;
+
+// How often each address was executed as an opcode, read as data and
+// written. Operand bytes are fetched, not read, and count as neither.
+pub struct Coverage {
+    pub executed: Vec<u64>,
+    pub read: Vec<u64>,
+    pub written: Vec<u64>,
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage {
+            executed: vec![0; 0x10000],
+            read: vec![0; 0x10000],
+            written: vec![0; 0x10000],
+        }
+    }
+}
+
+impl Coverage {
+    // After the instruction at pc ran and made accesses
+    pub fn instruction(&mut self, pc: u16, accesses: &[Access]) {
+        self.executed[pc as usize] += 1;
+        self.accesses(accesses);
+    }
+
+    // Data accesses alone, eg the stack pushes and vector read of an interrupt
+    pub fn accesses(&mut self, accesses: &[Access]) {
+        for access in accesses {
+            match access.kind {
+                AccessKind::Read(_) => self.read[access.addr as usize] += 1,
+                AccessKind::Write { .. } => self.written[access.addr as usize] += 1,
+            }
+        }
+    }
+
+    pub fn bitmap(&self) -> Vec<u8> {
+        (0..0x10000)
+            .map(|addr| {
+                let mut bits = 0;
+                if self.executed[addr] > 0 {
+                    bits |= EXECUTED;
+                }
+                if self.read[addr] > 0 {
+                    bits |= READ;
+                }
+                if self.written[addr] > 0 {
+                    bits |= WRITTEN;
+                }
+                bits
+            })
+            .collect()
+    }
+
+    // lcov tracefile with a DA record per source line that produced code,
+    // hit as often as the first instruction of the line was executed
+    pub fn lcov(&self, info: &DebugInfo) -> String {
+        let mut text = String::from("TN:\n");
+        let lines = info.code_lines();
+        let mut files: Vec<usize> = lines.iter().map(|(source, _)| source.file).collect();
+        files.dedup();
+        for file in files {
+            text += &format!("SF:{}\n", info.source_path(file).display());
+            let mut found = 0;
+            let mut hit = 0;
+            for (source, addr) in lines.iter().filter(|(source, _)| source.file == file) {
+                let count = self.executed[*addr as usize];
+                text += &format!("DA:{},{}\n", source.line, count);
+                found += 1;
+                hit += (count > 0) as usize;
+            }
+            text += &format!("LF:{}\nLH:{}\nend_of_record\n", found, hit);
+        }
+        text
+    }
+
+    // Writes the files asked for with --coverage and --coverage-bitmap
+    pub fn export(&self, cpu: &Cpu6502) {
+        if let Some(path) = &cpu.cmdline_args.coverage {
+            // clap makes --coverage require --dbgfile
+            let info = cpu.debug_info.as_ref().unwrap();
+            if let Err(error) = fs::write(path, self.lcov(info)) {
+                panic!("Problem writing coverage {}: {:?}", path, error);
+            }
+            println!("Wrote lcov coverage to {}", path);
+        }
+        if let Some(path) = &cpu.cmdline_args.coverage_bitmap {
+            if let Err(error) = fs::write(path, self.bitmap()) {
+                panic!("Problem writing coverage bitmap {}: {:?}", path, error);
+            }
+            println!("Wrote coverage bitmap to {}", path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coverage::{EXECUTED, READ, WRITTEN};
+    use crate::cpu6502::cpu_with_program;
+    use crate::debug_info::DebugInfo;
+
+    const COUNT_DBG: &str = "version\tmajor=2,minor=0
+file\tid=0,name=\"count.s\",size=200,mtime=0x65BFD4B1,mod=0
+seg\tid=0,name=\"CODE\",start=0x000600,size=0x000A,addrsize=absolute,type=ro
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=2
+span\tid=2,seg=0,start=4,size=2
+span\tid=3,seg=0,start=6,size=2
+span\tid=4,seg=0,start=8,size=2,type=0
+type\tid=0,val=\"040209\"
+line\tid=0,file=0,line=3,span=0
+line\tid=1,file=0,line=4,span=1
+line\tid=2,file=0,line=5,span=2
+line\tid=3,file=0,line=7,span=3
+line\tid=4,file=0,line=9,span=4
+";
+
+    #[test]
+    fn test_lcov_and_bitmap() {
+        let program = [
+            0xa9, 0x05, // 0600 LDA #5       line 3
+            0x85, 0x24, // 0602 STA $24      line 4
+            0xd0, 0x02, // 0604 BNE done     line 5
+            0xa9, 0x00, // 0606 LDA #0       line 7, skipped
+            0x41, 0x42, // 0608 .byte "AB"   line 9, data that decodes as EOR ($42,X)
+        ];
+        let mut cpu = cpu_with_program(&["--coverage-bitmap", "unused.map"], 0x0600, &program);
+        for _ in 0..3 {
+            cpu.step();
+        }
+        let coverage = cpu.coverage.as_ref().unwrap();
+        let bitmap = coverage.bitmap();
+        assert_eq!(bitmap[0x0600], EXECUTED);
+        assert_eq!(bitmap[0x0601], 0);
+        assert_eq!(bitmap[0x0024], WRITTEN);
+        assert_eq!(bitmap[0x0606], 0);
+
+        let info = DebugInfo::parse(COUNT_DBG).unwrap();
+        assert_eq!(
+            coverage.lcov(&info),
+            "TN:\nSF:count.s\nDA:3,1\nDA:4,1\nDA:5,1\nDA:7,0\nLF:4\nLH:3\nend_of_record\n"
+        );
+
+        cpu.program_counter = 0x0610;
+        cpu.set_byte_wrap(0x0610, 0xa5); // LDA $24
+        cpu.set_byte_wrap(0x0611, 0x24);
+        cpu.step();
+        let coverage = cpu.coverage.as_ref().unwrap();
+        assert_eq!(coverage.bitmap()[0x0024], READ | WRITTEN);
+        assert_eq!(coverage.read[0x0024], 1);
+    }
+}
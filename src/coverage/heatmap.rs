@@ -0,0 +1,187 @@
+use crate::coverage::Coverage;
+use crate::cpu6502::memory::MemMap;
+use colored::Colorize;
+use serde_json::json;
+use std::fs;
+
+// How an address was used in a run, strongest use first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Class {
+    Io,
+    Executed,
+    Written,
+    Read,
+    Untouched,
+}
+
+impl Class {
+    fn name(self) -> &'static str {
+        match self {
+            Class::Io => "io",
+            Class::Executed => "executed",
+            Class::Written => "written",
+            Class::Read => "read",
+            Class::Untouched => "untouched",
+        }
+    }
+
+    fn colour(self) -> (f64, f64, f64) {
+        match self {
+            Class::Io => (1.0, 0.85, 0.0),
+            Class::Executed => (0.0, 1.0, 0.3),
+            Class::Written => (1.0, 0.2, 0.2),
+            Class::Read => (0.3, 0.5, 1.0),
+            Class::Untouched => (0.0, 0.0, 0.0),
+        }
+    }
+}
+
+pub fn classify(coverage: &Coverage, addr: usize) -> Class {
+    if !matches!(MemMap::from_index(addr), MemMap::NOMAP) {
+        Class::Io
+    } else if coverage.executed[addr] > 0 {
+        Class::Executed
+    } else if coverage.written[addr] > 0 {
+        Class::Written
+    } else if coverage.read[addr] > 0 {
+        Class::Read
+    } else {
+        Class::Untouched
+    }
+}
+
+fn accesses(coverage: &Coverage, addr: usize) -> u64 {
+    coverage.executed[addr] + coverage.read[addr] + coverage.written[addr]
+}
+
+// One row per page and one column per byte in the page. Each character
+// cell is two pages tall, the upper half-block's foreground is the even
+// page and its background the odd one. Brighter means more accesses.
+pub fn render(coverage: &Coverage) {
+    let most = (0..0x10000)
+        .map(|addr| accesses(coverage, addr))
+        .max()
+        .unwrap_or(0);
+    let scale = ((most + 1) as f64).ln().max(1.0);
+    let colour = |addr: usize| {
+        let (r, g, b) = classify(coverage, addr).colour();
+        // untouched stays dark but visible against the terminal background
+        let level = match accesses(coverage, addr) {
+            0 => 0.0,
+            count => 0.35 + 0.65 * ((count + 1) as f64).ln() / scale,
+        };
+        let channel = |c: f64| (24.0 + c * level * 231.0) as u8;
+        (channel(r), channel(g), channel(b))
+    };
+    println!("Memory use by page, $00 to $ff across, two pages per line");
+    for page in (0..0x100).step_by(2) {
+        let mut line = format!("${:02x} ", page);
+        for low in 0..0x100 {
+            let (r, g, b) = colour(page << 8 | low);
+            let (br, bg, bb) = colour((page + 1) << 8 | low);
+            line += &"▀".truecolor(r, g, b).on_truecolor(br, bg, bb).to_string();
+        }
+        println!("{}", line);
+    }
+    let legend: Vec<String> = [
+        Class::Executed,
+        Class::Read,
+        Class::Written,
+        Class::Io,
+        Class::Untouched,
+    ]
+    .iter()
+    .map(|class| {
+        let (r, g, b) = class.colour();
+        let on = |c: f64| (24.0 + c * 231.0) as u8;
+        format!("{} {}", "██".truecolor(on(r), on(g), on(b)), class.name())
+    })
+    .collect();
+    println!("{}", legend.join("  "));
+    for (name, page) in [("zero page", 0x00), ("stack", 0x01), ("I/O page", 0xfe)] {
+        let free = (page << 8..(page + 1) << 8)
+            .filter(|&addr| classify(coverage, addr) == Class::Untouched)
+            .count();
+        println!(
+            "{:<10} ${:02x}00: {} of 256 bytes used",
+            name,
+            page,
+            256 - free
+        );
+    }
+}
+
+pub fn to_csv(coverage: &Coverage) -> String {
+    let mut text = String::from("address,class,executed,read,written\n");
+    for addr in 0..0x10000 {
+        text += &format!(
+            "${:04x},{},{},{},{}\n",
+            addr,
+            classify(coverage, addr).name(),
+            coverage.executed[addr],
+            coverage.read[addr],
+            coverage.written[addr]
+        );
+    }
+    text
+}
+
+pub fn to_json(coverage: &Coverage) -> String {
+    let addresses: Vec<_> = (0..0x10000)
+        .map(|addr| {
+            json!({
+                "address": addr,
+                "class": classify(coverage, addr).name(),
+                "executed": coverage.executed[addr],
+                "read": coverage.read[addr],
+                "written": coverage.written[addr],
+            })
+        })
+        .collect();
+    json!({ "addresses": addresses }).to_string()
+}
+
+// CSV unless the file name ends in .json
+pub fn export(coverage: &Coverage, path: &str) {
+    let text = if path.ends_with(".json") {
+        to_json(coverage)
+    } else {
+        to_csv(coverage)
+    };
+    if let Err(error) = fs::write(path, text) {
+        panic!("Problem writing memory map {}: {:?}", path, error);
+    }
+    println!("Wrote memory map to {}", path);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coverage::heatmap::{classify, to_csv, to_json, Class};
+    use crate::coverage::Coverage;
+
+    #[test]
+    fn test_classes_and_export() {
+        let mut coverage = Coverage::default();
+        coverage.executed[0x0600] = 3;
+        coverage.read[0x0601] = 1;
+        coverage.read[0x0024] = 2;
+        coverage.written[0x0024] = 1;
+        coverage.written[0xfe00] = 5;
+        assert_eq!(classify(&coverage, 0x0600), Class::Executed);
+        assert_eq!(classify(&coverage, 0x0601), Class::Read);
+        assert_eq!(classify(&coverage, 0x0024), Class::Written);
+        assert_eq!(classify(&coverage, 0xfe00), Class::Io);
+        assert_eq!(classify(&coverage, 0xfe01), Class::Io);
+        assert_eq!(classify(&coverage, 0x0200), Class::Untouched);
+
+        let csv = to_csv(&coverage);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 0x10001);
+        assert_eq!(lines[0x25], "$0024,written,0,2,1");
+        assert_eq!(lines[0xfe01], "$fe00,io,0,0,5");
+
+        let json: serde_json::Value = serde_json::from_str(&to_json(&coverage)).unwrap();
+        assert_eq!(json["addresses"][0x0600]["class"], "executed");
+        assert_eq!(json["addresses"][0x0600]["executed"], 3);
+    }
+}
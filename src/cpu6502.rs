@@ -1,9 +1,22 @@
+use crate::coverage::Coverage;
+use crate::debug_info::DebugInfo;
+use crate::debugger::Debugger;
+use crate::disasm::{self, Disassembled};
+use crate::loader::{elf, patch, woz_hex};
+use crate::profile::Profile;
+use crate::symbols::{self, SymbolTable};
+use crate::trace::record::Recording;
+use crate::trace::Trace;
 use crate::utils::pause::pause_for_input;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use colored::Colorize;
 use crossterm::terminal::disable_raw_mode;
 use log::debug;
-use std::{fs, usize};
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 pub mod memory;
 pub mod operation;
 pub mod status_reg;
@@ -19,11 +32,30 @@ use std::time::{Duration, Instant};
 
 const MEM_SIZE: usize = 65536;
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[derive(Parser, Debug, Clone)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    after_help = "The on-exit outputs (--profile, --flamegraph, --coverage, --coverage-bitmap, \
+--heatmap, --heatmap-export, --export-hex, --dump-state-exit and the --trace/--record flush) \
+are written when the run ends: Ctrl+C (SIGINT), quitting the debugger, Ctrl+C with --keyboard, \
+or an invalid opcode outside the debugger, which then exits with status 1. \
+A second Ctrl+C exits without writing them."
+)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     // 6502 hex file to run
-    #[arg(help = "Input file", required = true)]
+    #[arg(
+        help = "Input file",
+        required_unless_present = "dap",
+        default_value = ""
+    )]
     pub binary_file: String,
 
     // Print all mem even if zeroed
@@ -41,7 +73,7 @@ pub struct Args {
 
     // Step through program
     #[arg(
-        help = "Step through instruction by instruction",
+        help = "Step through instruction by instruction at a debugger prompt, see --tui for a full screen view",
         short,
         long,
         default_value_t = false
@@ -66,6 +98,69 @@ pub struct Args {
     )]
     pub instrumentation: bool,
 
+    // nestest.log style line per instruction, for diffing against reference traces
+    #[arg(
+        help = "Write a nestest.log style line for each executed instruction to FILE",
+        long,
+        value_name = "FILE"
+    )]
+    pub trace: Option<String>,
+
+    // Binary recording for long runs, read back with trace-query
+    #[arg(
+        help = "Record a compact binary trace to FILE, query it with trace-query",
+        long,
+        value_name = "FILE"
+    )]
+    pub record: Option<String>,
+
+    // Where the 6502 program spends its time, by instruction and subroutine
+    #[arg(
+        help = "Report the hottest instructions and subroutines on exit",
+        long,
+        default_value_t = false
+    )]
+    pub profile: bool,
+
+    // Call paths weighted by cycles for inferno or flamegraph.pl
+    #[arg(
+        help = "Write collapsed call stacks weighted by cycles to FILE on exit, for flame graphs",
+        long,
+        value_name = "FILE"
+    )]
+    pub flamegraph: Option<String>,
+
+    // Which bytes ran, were read or were written, for test coverage
+    #[arg(
+        help = "Write lcov coverage of the --dbgfile source lines to FILE on exit",
+        long,
+        value_name = "FILE",
+        requires = "dbgfile"
+    )]
+    pub coverage: Option<String>,
+
+    #[arg(
+        help = "Write a byte per address to FILE on exit: 1 executed, 2 read, 4 written",
+        long,
+        value_name = "FILE"
+    )]
+    pub coverage_bitmap: Option<String>,
+
+    // How every address was used: executed, read, written, I/O or untouched
+    #[arg(
+        help = "Show a 256x256 heatmap of memory use on exit",
+        long,
+        default_value_t = false
+    )]
+    pub heatmap: bool,
+
+    #[arg(
+        help = "Write how each address was used to FILE on exit, JSON for .json, otherwise CSV",
+        long,
+        value_name = "FILE"
+    )]
+    pub heatmap_export: Option<String>,
+
     // Enable keyboard input
     #[arg(
         help = "Enable keyboard interaction",
@@ -74,6 +169,255 @@ pub struct Args {
         default_value_t = false
     )]
     pub keyboard: bool,
+
+    // Full screen debugger instead of printing the state every instruction
+    #[arg(
+        help = "Full screen debugger with registers, disassembly, memory and console panes, in place of the per instruction memory dump",
+        short,
+        long,
+        default_value_t = false
+    )]
+    pub tui: bool,
+
+    // Debug Adapter Protocol on stdin/stdout, the program comes from the launch request
+    #[arg(
+        help = "Serve the Debug Adapter Protocol on stdin/stdout for editors",
+        long,
+        default_value_t = false
+    )]
+    pub dap: bool,
+
+    // VICE binary monitor protocol for existing front-ends
+    #[arg(
+        help = "Serve the VICE binary monitor protocol on ADDR (default 127.0.0.1:6502)",
+        long,
+        value_name = "ADDR",
+        num_args = 0..=1,
+        default_missing_value = "127.0.0.1:6502"
+    )]
+    pub monitor: Option<String>,
+
+    // ld65 -Ln / VICE label file
+    #[arg(help = "Label file (ld65 -Ln or VICE 'al C:xxxx .name' format)", long)]
+    pub labels: Option<String>,
+
+    // ld65 --dbgfile output for source level debugging
+    #[arg(help = "ld65 debug info file (--dbgfile) for the binary", long)]
+    pub dbgfile: Option<String>,
+
+    // Debugger breakpoints and watchpoints
+    #[arg(
+        help = "Break at an address ($0621), symbol or source line (file.s:12), optionally with 'if COND' and 'hit N'",
+        long = "break",
+        value_name = "LOCATION"
+    )]
+    pub breakpoints: Vec<String>,
+
+    #[arg(
+        help = "Print a message at LOCATION and keep running, eg '$0621 \"index={mem[$0210]}\"'",
+        long,
+        value_name = "LOGPOINT"
+    )]
+    pub log: Vec<String>,
+
+    #[arg(
+        help = "Break after a write to ADDR[-END], or only writes of VAL with ADDR[-END]=VAL",
+        long,
+        value_name = "SPEC"
+    )]
+    pub watch: Vec<String>,
+
+    #[arg(help = "Break after a read of ADDR[-END]", long, value_name = "SPEC")]
+    pub rwatch: Vec<String>,
+
+    #[arg(
+        help = "Break after any read or write of ADDR[-END]",
+        long,
+        value_name = "SPEC"
+    )]
+    pub awatch: Vec<String>,
+
+    // BRK runs like on a 6502 so BRK and RTI catchpoints see a real
+    // interrupt entry, this keeps the old pause for programs that use BRK as
+    // a breakpoint
+    #[arg(
+        help = "BRK pushes PC+2 and P with B set and jumps through $FFFE, this instead prints the state, waits for a key and carries on after the BRK",
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["tui", "dap", "monitor"]
+    )]
+    pub brk_pause: bool,
+
+    #[arg(
+        help = "Instructions kept in the undo log for stepping backwards once debugging, 0 turns it off",
+        long,
+        default_value_t = 100_000
+    )]
+    pub history: usize,
+
+    #[arg(
+        help = "Break on an event: brk, irq, nmi, rti, invalid, stack, io or rom=START-END",
+        long,
+        value_name = "EVENT"
+    )]
+    pub catch: Vec<String>,
+
+    // Woz Monitor style listings loaded on top of the binary
+    #[arg(
+        help = "Load a Woz Monitor / addr: bytes hex listing on top of the binary",
+        long,
+        value_name = "FILE"
+    )]
+    pub load_hex: Vec<String>,
+
+    // Write memory out as a Woz Monitor listing on exit
+    #[arg(
+        help = "Write --export-range out as a Woz Monitor listing on exit",
+        long,
+        value_name = "FILE",
+        requires = "export_range"
+    )]
+    pub export_hex: Option<String>,
+
+    #[arg(
+        help = "Inclusive address range for --export-hex, eg $0300-$03FF",
+        long,
+        value_name = "START-END"
+    )]
+    pub export_range: Option<String>,
+
+    // IPS/BPS patches applied to the loaded image before running
+    #[arg(
+        help = "Apply an IPS or BPS patch to the loaded image before running",
+        long,
+        value_name = "FILE"
+    )]
+    pub patch: Vec<String>,
+}
+
+// Tools besides running an image straight through
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    #[command(about = "Disassemble an image into ca65 source, tracing code from its vectors")]
+    Disasm(DisasmArgs),
+    #[command(about = "Assemble ca65 source and link it like ld65, no cc65 needed")]
+    Asm(AsmArgs),
+    #[command(about = "Assemble and run one instruction at a time, showing what each changed")]
+    Repl(ReplArgs),
+    #[command(about = "Answer questions about a --record recording")]
+    TraceQuery(TraceQueryArgs),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct TraceQueryArgs {
+    #[arg(help = "Recording made with --record")]
+    pub recording: String,
+
+    #[arg(help = "Label file (ld65 -Ln or VICE format) naming addresses", long)]
+    pub labels: Option<String>,
+
+    #[command(subcommand)]
+    pub query: Query,
+}
+
+// Addresses are $hex, decimal or a label
+#[derive(Subcommand, Debug, Clone)]
+pub enum Query {
+    #[command(about = "Instructions, interrupts, calls and cycles in the recording")]
+    Summary,
+    #[command(about = "Writes to ADDR, eg writes '$0024' --before 5000 --last")]
+    Writes {
+        addr: String,
+        #[arg(
+            help = "Only writes by instructions numbered below N",
+            long,
+            value_name = "N"
+        )]
+        before: Option<u64>,
+        #[arg(help = "Only the last matching write", long)]
+        last: bool,
+    },
+    #[command(about = "Every JSR to ADDR with the registers at the call")]
+    Calls { addr: String },
+    #[command(about = "Registers at a cycle count or before an instruction number")]
+    Regs {
+        #[arg(long, required_unless_present = "instruction")]
+        cycle: Option<u64>,
+        #[arg(long, conflicts_with = "cycle")]
+        instruction: Option<u64>,
+    },
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct AsmArgs {
+    #[arg(help = "ca65 source file")]
+    pub source: String,
+
+    #[arg(
+        help = "ld65 linker config, default bios.cfg next to the source",
+        short = 'C',
+        long,
+        value_name = "FILE"
+    )]
+    pub config: Option<String>,
+
+    #[arg(
+        help = "Image to write, default the source name without its extension",
+        short,
+        long,
+        value_name = "FILE"
+    )]
+    pub output: Option<String>,
+
+    #[arg(help = "Write an ld65 -Ln style label file", long, value_name = "FILE")]
+    pub labels: Option<String>,
+
+    #[arg(
+        help = "Write an ld65 --dbgfile style debug info file, for --dbgfile and --coverage",
+        long,
+        value_name = "FILE"
+    )]
+    pub dbgfile: Option<String>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ReplArgs {
+    #[arg(help = "Image to load first, PC starts at its reset vector instead of $0600")]
+    pub image: Option<String>,
+
+    #[arg(help = "Label file (ld65 -Ln or VICE format) naming addresses", long)]
+    pub labels: Option<String>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct DisasmArgs {
+    #[arg(help = "Raw image, eg a ROM dump or one of the examples/ binaries")]
+    pub image: String,
+
+    #[arg(
+        help = "Address the image loads at, default puts its last byte at $FFFF",
+        long,
+        value_name = "ADDR"
+    )]
+    pub origin: Option<String>,
+
+    #[arg(
+        help = "Trace code from ADDR as well as the NMI, RESET and IRQ vectors",
+        long,
+        value_name = "ADDR"
+    )]
+    pub entry: Vec<String>,
+
+    #[arg(help = "Label file (ld65 -Ln or VICE format) naming addresses", long)]
+    pub labels: Option<String>,
+
+    #[arg(
+        help = "Write the source to FILE instead of stdout",
+        short,
+        long,
+        value_name = "FILE"
+    )]
+    pub output: Option<String>,
 }
 
 pub struct Cpu6502 {
@@ -87,19 +431,77 @@ pub struct Cpu6502 {
     pub cmdline_args: Args,
     pub start_time: Instant,
     pub instructions_executed: u128,
+    pub cycles: u64,
+    pub symbols: SymbolTable,
+    pub entry_point: Option<u16>,
+    pub debug_info: Option<DebugInfo>,
+    // data accesses made by the last instruction, operand fetches excluded
+    pub last_accesses: Vec<memory::Access>,
+    // set when the last instruction or interrupt moved SP past $00 or $FF
+    pub stack_wrapped: bool,
+    pub pending_interrupt: Option<Interrupt>,
+    pub profile: Option<Profile>,
+    pub coverage: Option<Coverage>,
+    pub trace: Option<Trace>,
+    // opened by reset, the header holds the registers the run starts from
+    pub recording: Option<Recording<BufWriter<File>>>,
+    // set by Ctrl+C, the run stops so the on-exit outputs still get written
+    pub interrupted: Arc<AtomicBool>,
+}
+
+// Everything but memory, as saved by the debugger's undo log
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub pc: u16,
+    pub p: u8,
+    pub cycles: u64,
+    pub instructions: u128,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Interrupt {
+    Irq,
+    Nmi,
 }
 
 pub fn init_cpu6502(args: Args) -> Cpu6502 {
+    let profile = (args.profile || args.flamegraph.is_some()).then(Profile::default);
+    let coverage = (args.coverage.is_some()
+        || args.coverage_bitmap.is_some()
+        || args.heatmap
+        || args.heatmap_export.is_some())
+    .then(Coverage::default);
+    let trace = args.trace.as_deref().map(Trace::create);
+    let mut memory = memory::Mem::init_mem();
+    if coverage.is_some() {
+        memory.enable_access_log();
+    }
     Cpu6502 {
         instructions_executed: 0,
+        cycles: 0,
         start_time: Instant::now(),
         cmdline_args: args,
-        memory: memory::Mem::init_mem(),
+        memory,
         accumulator: 0,
         x_index: 0,
         y_index: 0,
         program_counter: 0,
         stack_pointer: 0xFF,
+        symbols: SymbolTable::default(),
+        entry_point: None,
+        debug_info: None,
+        last_accesses: Vec::new(),
+        stack_wrapped: false,
+        pending_interrupt: None,
+        profile,
+        coverage,
+        trace,
+        recording: None,
+        interrupted: Arc::new(AtomicBool::new(false)),
         status_flags: status_reg::StatusFlags {
             n: false,
             v: false,
@@ -113,11 +515,24 @@ pub fn init_cpu6502(args: Args) -> Cpu6502 {
     }
 }
 
+// Test CPU with `args` after the binary name, program loaded at `at` and PC on it
+#[cfg(test)]
+pub fn cpu_with_program(args: &[&str], at: u16, program: &[u8]) -> Cpu6502 {
+    let args = ["rust6502", "unused.bin"].iter().chain(args);
+    let mut cpu = init_cpu6502(Args::parse_from(args));
+    cpu.memory.load_segment(at as usize, program);
+    cpu.program_counter = at;
+    cpu
+}
+
 enum Index {
     X,
     Y,
 }
 
+// Only the tests use it so far, it is kept for the decimal mode TODO in adc
+// and sbc
+#[allow(dead_code)]
 fn bcd_to_u8(byte: u8) -> Option<u8> {
     let low_nibble = byte & 0xF;
     let high_nibble = (byte >> 4) & 0xF;
@@ -133,17 +548,22 @@ impl Cpu6502 {
         if self.cmdline_args.no_print {
             return;
         }
+        self.print_registers();
+        self.memory.dump_memory(
+            self.cmdline_args.print_all_mem,
+            self.program_counter,
+            self.stack_pointer,
+            &self.symbols,
+        );
+    }
+
+    pub fn print_registers(&mut self) {
         self.status_flags.print_status_flags_readable();
         println!("X  = 0x{:#>02x}, {}", self.x_index, self.x_index);
         println!("Y  = 0x{:#>02x}, {}", self.y_index, self.y_index);
         println!("A  = 0x{:#>02x}, {}", self.accumulator, self.accumulator);
         println!("{} = 0x{:#>04x}", "PC".blue(), self.program_counter);
         println!("{} = 0x{:#>02x}", "SP".yellow(), self.stack_pointer);
-        self.memory.dump_memory(
-            self.cmdline_args.print_all_mem,
-            self.program_counter,
-            self.stack_pointer,
-        );
     }
 
     pub fn load_file_into_memory(&mut self) {
@@ -153,75 +573,229 @@ impl Cpu6502 {
             Ok(code) => code,
             Err(error) => panic!("Problem opening the file: {:?}", error),
         };
-        self.memory.set_all(code);
+        if elf::is_elf(&code) {
+            self.load_elf(&code);
+        } else if woz_hex::is_hex_listing(&code) {
+            self.load_hex_listing(&String::from_utf8_lossy(&code));
+        } else {
+            self.memory.set_all(code);
+        }
+
+        for path in self.cmdline_args.load_hex.clone() {
+            match fs::read_to_string(&path) {
+                Ok(text) => self.load_hex_listing(&text),
+                Err(error) => panic!("Problem opening hex listing {}: {:?}", path, error),
+            }
+        }
+
+        for path in self.cmdline_args.patch.clone() {
+            self.apply_patch(&path);
+        }
+    }
+
+    fn apply_patch(&mut self, path: &str) {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(error) => panic!("Problem opening patch {}: {:?}", path, error),
+        };
+        let ranges = match patch::apply(&bytes, self.memory.raw_mut()) {
+            Ok(ranges) => ranges,
+            Err(error) => panic!("Problem applying patch {}: {}", path, error),
+        };
+        println!("Applied {} ({} range(s))", path, ranges.len());
+        for (start, len) in ranges.iter().filter(|(_, len)| *len > 0) {
+            println!(
+                "  patched 0x{:#>04x}-0x{:#>04x} ({} bytes)",
+                start,
+                start + len - 1,
+                len
+            );
+        }
+    }
+
+    fn load_hex_listing(&mut self, text: &str) {
+        let listing = match woz_hex::parse(text) {
+            Ok(listing) => listing,
+            Err(error) => panic!("Problem loading hex listing: {}", error),
+        };
+        for segment in &listing.segments {
+            self.memory
+                .load_segment(segment.addr as usize, &segment.data);
+            println!(
+                "Loaded 0x{:#>04x}-0x{:#>04x} from hex listing",
+                segment.addr,
+                segment.addr as usize + segment.data.len() - 1
+            );
+        }
+        if listing.run.is_some() {
+            self.entry_point = listing.run;
+        }
+    }
+
+    pub fn export_hex(&self) {
+        let (path, range) = match (
+            &self.cmdline_args.export_hex,
+            &self.cmdline_args.export_range,
+        ) {
+            (Some(path), Some(range)) => (path, range),
+            _ => return,
+        };
+        let (start, end) = match range
+            .split_once('-')
+            .or_else(|| range.split_once('.'))
+            .ok_or(format!("expected START-END, got '{}'", range))
+            .and_then(|(start, end)| {
+                Ok((self.resolve_location(start)?, self.resolve_location(end)?))
+            }) {
+            Ok((start, end)) if start <= end => (start, end),
+            Ok(_) => panic!("Bad export range: start is after end"),
+            Err(error) => panic!("Bad export range: {}", error),
+        };
+        let bytes: Vec<u8> = (start..=end)
+            .map(|addr| self.memory.get_byte(addr as usize))
+            .collect();
+        if let Err(error) = fs::write(path, woz_hex::format(start, &bytes)) {
+            panic!("Problem writing hex listing {}: {:?}", path, error);
+        }
+        println!("Wrote 0x{:#>04x}-0x{:#>04x} to {}", start, end, path);
+    }
+
+    fn load_elf(&mut self, code: &[u8]) {
+        let image = match elf::parse(code) {
+            Ok(image) => image,
+            Err(error) => panic!("Problem loading ELF file: {}", error),
+        };
+        for segment in &image.segments {
+            self.memory
+                .load_segment(segment.addr as usize, &segment.data);
+        }
+        for (name, addr) in image.symbols {
+            self.symbols.insert(name, addr);
+        }
+        self.entry_point = Some(image.entry);
+        println!(
+            "Loaded {} ELF segment(s) and {} symbol(s), entry point 0x{:#>04x}",
+            image.segments.len(),
+            self.symbols.len(),
+            image.entry
+        );
+    }
+
+    pub fn load_debug_info(&mut self) {
+        if let Some(path) = self.cmdline_args.labels.clone() {
+            let labels = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| symbols::parse_label_file(&text));
+            match labels {
+                Ok(labels) => {
+                    for (name, addr) in labels {
+                        self.symbols.insert(name, addr);
+                    }
+                }
+                Err(error) => panic!("Problem loading label file {}: {}", path, error),
+            }
+        }
+
+        let path = match &self.cmdline_args.dbgfile {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let info = match DebugInfo::load(&path) {
+            Ok(info) => info,
+            Err(error) => panic!("Problem loading debug info {}: {}", path, error),
+        };
+        // labels are preferred over equates when both name the same address
+        for sym in info.symbols.iter().filter(|sym| sym.is_label) {
+            self.symbols.insert(sym.name.clone(), sym.value);
+        }
+        for sym in info.symbols.iter().filter(|sym| !sym.is_label) {
+            self.symbols.insert(sym.name.clone(), sym.value);
+        }
+        self.debug_info = Some(info);
+    }
+
+    pub fn resolve_location(&self, location: &str) -> Result<u16, String> {
+        let location = location.trim();
+        let hex = location
+            .strip_prefix('$')
+            .or_else(|| location.strip_prefix("0x"));
+        if let Some(hex) = hex {
+            return u16::from_str_radix(hex, 16)
+                .map_err(|_| format!("invalid address '{}'", location));
+        }
+        if location.bytes().all(|b| b.is_ascii_digit()) {
+            return location
+                .parse::<u16>()
+                .map_err(|_| format!("invalid address '{}'", location));
+        }
+        if let Some((file, line)) = location.rsplit_once(':') {
+            let line: usize = line
+                .parse()
+                .map_err(|_| format!("invalid line number in '{}'", location))?;
+            let info = self
+                .debug_info
+                .as_ref()
+                .ok_or(format!("'{}' needs debug info, pass --dbgfile", location))?;
+            return info
+                .addr_of_line(file, line)
+                .ok_or(format!("no code generated at or after {}", location));
+        }
+        self.symbols
+            .addr_of(location)
+            .ok_or(format!("unknown location or symbol '{}'", location))
+    }
+
+    fn source_location(&mut self, addr: u16) -> Option<String> {
+        let info = self.debug_info.as_mut()?;
+        let source = info.line_at(addr)?;
+        let mut location = format!("{}:{}", info.file_name(source.file), source.line);
+        if let Some(scope) = info.scope_at(addr) {
+            location = format!("{} in {}", location, scope);
+        }
+        if let Some(text) = info.source_text(source) {
+            location = format!("{}: {}", location, text);
+        }
+        Some(location)
     }
 
     fn get_next_byte(&mut self) -> u8 {
         *self.memory.get(self.program_counter as usize).unwrap()
     }
 
-    fn print_instruction(&mut self, instruction: &operation::InstructionMetadata) {
-        // STX (ZeroPageY) operand
+    // Decodes the instruction at addr from the current memory contents
+    pub fn disassemble(&self, addr: u16) -> Disassembled {
+        disasm::decode(|at| self.memory.get_byte(at as usize), addr)
+    }
+
+    pub fn print_instruction(&mut self) {
         if self.cmdline_args.no_print {
             return;
         }
 
-        let operand = match instruction.mode {
-            operation::AddressingMode::AbsoluteXIndexed => format!(
-                "${:#>04x},X",
-                self.get_addr(operation::AddressingMode::AbsoluteXIndexed)
-            ),
-            operation::AddressingMode::Relative => {
-                format!(
-                    "${:#>04x}",
-                    self.get_addr(operation::AddressingMode::Relative)
-                )
-            }
-            operation::AddressingMode::Implied => String::new(),
-            operation::AddressingMode::Absolute => {
-                format!("${:#>04x}", self.get_addr(instruction.mode))
-            }
-            operation::AddressingMode::AbsoluteIndirect => {
-                format!(
-                    "(${:#>04x})",
-                    self.get_addr(operation::AddressingMode::Absolute)
-                )
-            }
-            operation::AddressingMode::Immediate => {
-                let addr = self.get_addr(instruction.mode);
-                format!("#${:#>02x}", self.memory.get_byte(addr))
-            }
-            operation::AddressingMode::ZeroPage => {
-                format!("${:#>02x}", self.get_addr(instruction.mode))
-            }
-            operation::AddressingMode::ZeroPageX => format!(
-                "${:#>02x},X",
-                self.get_addr(instruction.mode) - self.x_index as usize
-            ),
-            operation::AddressingMode::ZeroPageY => format!(
-                "${:#>02x},Y",
-                self.get_addr(instruction.mode) - self.y_index as usize
-            ),
-            operation::AddressingMode::ZeroPageIndirectIndexedX => format!(
-                "$({:#>02x},X)",
-                self.get_addr(instruction.mode) - self.x_index as usize
-            ),
-            operation::AddressingMode::ZeroPageIndirectIndexedY => format!(
-                "$({:#>02x},Y)",
-                self.get_addr(instruction.mode) - self.y_index as usize
-            ),
-            _ => todo!(
-                "Add format for addressing mode {:?} in print_instruction()",
-                instruction.mode
-            ),
+        let instruction = self.disassemble(self.program_counter);
+        if let Some(name) = self.symbols.name_at(self.program_counter) {
+            println!("\n{}:", name.cyan());
+        }
+        let source = match self.source_location(self.program_counter) {
+            Some(location) => format!("  ; {}", location).dimmed().to_string(),
+            None => String::new(),
         };
         println!(
-            "\nNEXT INSTRUCTION: {} {}",
-            format!("{:?}", instruction.instruction_type).green(),
-            operand
+            "\nNEXT INSTRUCTION: {} {}{}",
+            instruction.mnemonic().green(),
+            instruction.operand(Some(&self.symbols)),
+            source
         );
     }
 
+    // Label for an address when one is known, otherwise the address in hex
+    pub fn symbolic(&self, addr: u16, width: usize) -> String {
+        match self.symbols.name_at(addr) {
+            Some(name) => name.to_string(),
+            None => format!("${:0width$x}", addr, width = width),
+        }
+    }
+
     fn get_abs_addr(&self) -> usize {
         let ll = self.memory.get_byte((self.program_counter + 1) as usize) as usize;
         let hh = self.memory.get_byte((self.program_counter + 2) as usize) as usize;
@@ -253,13 +827,12 @@ impl Cpu6502 {
                 let hh = self.memory.get_byte(ptrptr + 1) as usize;
                 (hh << 8) | ll
             }
+            // Y indexes the address the zero page pointer holds, not the pointer
             Index::Y => {
-                let ptrptr: usize = self.memory.get_byte((self.program_counter + 1) as usize)
-                    as usize
-                    + self.y_index as usize;
-                let ll = self.memory.get_byte(ptrptr) as usize;
-                let hh = self.memory.get_byte(ptrptr + 1) as usize;
-                (hh << 8) | ll
+                let ptr = self.memory.get_byte((self.program_counter + 1) as usize);
+                let ll = self.memory.get_byte(ptr as usize) as usize;
+                let hh = self.memory.get_byte(ptr.wrapping_add(1) as usize) as usize;
+                (((hh << 8) | ll) + self.y_index as usize) & 0xffff
             }
         }
     }
@@ -302,6 +875,21 @@ impl Cpu6502 {
         }
     }
 
+    fn index_crosses_page(&self, mode: operation::AddressingMode) -> bool {
+        let (base, index) = match mode {
+            operation::AddressingMode::AbsoluteXIndexed => (self.get_abs_addr(), self.x_index),
+            operation::AddressingMode::AbsoluteYIndexed => (self.get_abs_addr(), self.y_index),
+            operation::AddressingMode::ZeroPageIndirectIndexedY => {
+                let ptr = self.memory.get_byte(self.program_counter as usize + 1);
+                let ll = self.memory.get_byte(ptr as usize) as usize;
+                let hh = self.memory.get_byte(ptr.wrapping_add(1) as usize) as usize;
+                ((hh << 8) | ll, self.y_index)
+            }
+            _ => return false,
+        };
+        (base & 0xFF) + index as usize > 0xFF
+    }
+
     pub fn set_byte_wrap(&mut self, index: usize, val: u8) {
         self.memory.set_byte(index, val);
         if self.cmdline_args.keyboard && index == memory::MemMap::CHROUT as usize {
@@ -793,13 +1381,27 @@ impl Cpu6502 {
     }
 
     fn brk(&mut self, _mode: operation::AddressingMode) {
-        let np = self.cmdline_args.no_print;
-        self.cmdline_args.no_print = false;
-        disable_raw_mode().unwrap();
-        self.print_state();
-        enable_raw_mode().unwrap();
-        pause_for_input();
-        self.cmdline_args.no_print = np;
+        if self.cmdline_args.brk_pause {
+            let np = self.cmdline_args.no_print;
+            self.cmdline_args.no_print = false;
+            disable_raw_mode().unwrap();
+            self.print_state();
+            enable_raw_mode().unwrap();
+            pause_for_input();
+            self.cmdline_args.no_print = np;
+            self.program_counter = self.program_counter.wrapping_add(1);
+            return;
+        }
+        // the byte after BRK is padding, handlers find it at the pushed PC - 1
+        let pc = self.program_counter.wrapping_add(2);
+        self.push_stack((pc >> 8) as u8);
+        self.push_stack(pc as u8);
+        // pushed with B set, which is how handlers tell a BRK from an IRQ
+        let flags = self.status_flags.as_u8() | 0x30;
+        self.push_stack(flags);
+        self.status_flags.i = true;
+        self.program_counter =
+            (self.memory.get_byte(0xffff) as u16) << 8 | self.memory.get_byte(0xfffe) as u16;
     }
 
     fn ora(&mut self, mode: operation::AddressingMode) {
@@ -814,7 +1416,14 @@ impl Cpu6502 {
     }
 
     fn rti(&mut self, _mode: operation::AddressingMode) {
-        todo!("Need to implement rti");
+        // B only exists on the stack copy, U always reads back as set
+        let flags = self.pop_stack();
+        self.status_flags.set_from_u8(flags);
+        self.status_flags.b = false;
+        self.status_flags.u = true;
+        let ll = self.pop_stack() as u16;
+        let hh = self.pop_stack() as u16;
+        self.program_counter = (hh << 8) | ll;
     }
 
     fn rol(&mut self, mode: operation::AddressingMode) {
@@ -859,10 +1468,12 @@ impl Cpu6502 {
     fn push_stack(&mut self, value: u8) {
         let stack_addr = 0x0100 | (self.stack_pointer as u16);
         self.set_byte_wrap(stack_addr as usize, value);
+        self.stack_wrapped |= self.stack_pointer == 0x00;
         self.stack_pointer = self.stack_pointer.wrapping_sub(1);
     }
 
     fn pop_stack(&mut self) -> u8 {
+        self.stack_wrapped |= self.stack_pointer == 0xFF;
         self.stack_pointer = self.stack_pointer.wrapping_add(1);
         let stack_addr = 0x100 | (self.stack_pointer as u16);
         self.memory.get_byte(stack_addr as usize)
@@ -904,124 +1515,136 @@ impl Cpu6502 {
         true
     }
 
-    pub fn run(&mut self) {
+    pub fn reset(&mut self) {
         let rvec: u16 =
             (self.memory.get_byte(0xfffd) as u16) << 8 | self.memory.get_byte(0xfffc) as u16;
-        self.program_counter = rvec;
+        // ELF images carry their own entry point, raw images use the reset vector
+        self.program_counter = self.entry_point.unwrap_or(rvec);
+        if self.recording.is_none() {
+            if let Some(path) = self.cmdline_args.record.clone() {
+                self.memory.enable_access_log();
+                self.recording = Some(Recording::create(&path, self));
+            }
+        }
+    }
+
+    // Writes out what --trace and --record still hold, once the run is over
+    pub fn flush_traces(&mut self) {
+        if let Some(trace) = &mut self.trace {
+            trace.flush();
+        }
+        if let Some(recording) = &mut self.recording {
+            recording.flush();
+        }
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.accumulator,
+            x: self.x_index,
+            y: self.y_index,
+            sp: self.stack_pointer,
+            pc: self.program_counter,
+            p: self.status_flags.as_u8(),
+            cycles: self.cycles,
+            instructions: self.instructions_executed,
+        }
+    }
 
+    pub fn restore_registers(&mut self, registers: &Registers) {
+        self.accumulator = registers.a;
+        self.x_index = registers.x;
+        self.y_index = registers.y;
+        self.stack_pointer = registers.sp;
+        self.program_counter = registers.pc;
+        self.status_flags.set_from_u8(registers.p);
+        self.cycles = registers.cycles;
+        self.instructions_executed = registers.instructions;
+    }
+
+    // Enters the handler of a pending interrupt, IRQs wait while I is set
+    pub fn service_interrupt(&mut self) -> Option<Interrupt> {
+        let interrupt = self.pending_interrupt?;
+        if interrupt == Interrupt::Irq && self.status_flags.i {
+            return None;
+        }
+        self.pending_interrupt = None;
+        self.stack_wrapped = false;
+        self.memory.start_recording();
+
+        let pc = self.program_counter;
+        self.push_stack((pc >> 8) as u8);
+        self.push_stack(pc as u8);
+        // pushed with B clear, which is how handlers tell an IRQ from a BRK
+        let flags = self.status_flags.as_u8() & !0x10 | 0x20;
+        self.push_stack(flags);
+        self.status_flags.i = true;
+
+        let vector = match interrupt {
+            Interrupt::Irq => 0xfffe,
+            Interrupt::Nmi => 0xfffa,
+        };
+        self.program_counter =
+            (self.memory.get_byte(vector + 1) as u16) << 8 | self.memory.get_byte(vector) as u16;
+        self.cycles += 7;
+        self.memory.stop_recording(&mut self.last_accesses);
+        if let Some(coverage) = &mut self.coverage {
+            coverage.accesses(&self.last_accesses);
+        }
+        if self.profile.is_some() {
+            let after = self.registers();
+            if let Some(profile) = &mut self.profile {
+                profile.interrupt(interrupt, 7, &after);
+            }
+        }
+        if let Some(mut recording) = self.recording.take() {
+            recording.interrupt(self, pc);
+            self.recording = Some(recording);
+        }
+        Some(interrupt)
+    }
+
+    // Runs until the user quits or Ctrl+C, or fails on an invalid opcode when
+    // there is no debugger to stop in
+    pub fn run(&mut self) -> Result<(), String> {
+        self.reset();
+        if self.cmdline_args.tui {
+            crate::debugger::tui::run(self);
+            return Ok(());
+        }
+        if let Some(addr) = self.cmdline_args.monitor.clone() {
+            crate::debugger::monitor::run(self, &addr);
+            return Ok(());
+        }
+
+        let mut debugger = Debugger::from_args(self);
         let mut reader = EventStream::new();
         let mut timer = Timer::new(Duration::from_millis(1));
         loop {
+            if self.interrupted.load(Ordering::Relaxed) {
+                return Ok(());
+            }
             if self.cmdline_args.keyboard && timer.has_expired() {
                 timer.reset();
                 let success = self.handle_keyboard(&mut reader);
                 if !success {
                     print!("Disabled Raw mode and exiting\r\n");
-                    return;
+                    return Ok(());
                 }
             }
 
             self.print_state();
             let cur_opcode = self.get_next_byte();
-            let instruction: operation::InstructionMetadata =
-                operation::get_opcode_metadata(cur_opcode);
-            self.print_instruction(&instruction);
-
-            if self.cmdline_args.step_debug {
-                pause_for_input();
+            // invalid opcodes are left for the debugger to catch before step panics
+            if operation::lookup_opcode(cur_opcode).is_some() {
+                self.print_instruction();
             }
 
-            match instruction.instruction_type {
-                operation::Instruction::ADC => self.adc(instruction.mode),
-                operation::Instruction::SBC => self.sbc(instruction.mode),
-                operation::Instruction::STX => self.stx(instruction.mode),
-                operation::Instruction::STY => self.sty(instruction.mode),
-                operation::Instruction::LDX => self.ldx(instruction.mode),
-                operation::Instruction::LDY => self.ldy(instruction.mode),
-                operation::Instruction::CPX => self.cpx(instruction.mode),
-                operation::Instruction::CPY => self.cpy(instruction.mode),
-                operation::Instruction::DEC => self.dec(instruction.mode),
-                operation::Instruction::DEX => self.dex(),
-                operation::Instruction::DEY => self.dey(),
-                operation::Instruction::EOR => self.eor(instruction.mode),
-                operation::Instruction::JMP => self.jmp(instruction.mode),
-                operation::Instruction::NOP => {}
-                operation::Instruction::LDA => self.lda(instruction.mode),
-                operation::Instruction::STA => self.sta(instruction.mode),
-                operation::Instruction::JSR => self.jsr(instruction.mode),
-                operation::Instruction::RTS => self.rts(),
-                operation::Instruction::CMP => self.cmp(instruction.mode),
-                operation::Instruction::BPL => self.bpl(instruction.mode),
-                operation::Instruction::BEQ => self.beq(instruction.mode),
-                operation::Instruction::BNE => self.bne(instruction.mode),
-                operation::Instruction::BMI => self.bmi(instruction.mode),
-                operation::Instruction::BVS => self.bvs(instruction.mode),
-                operation::Instruction::BVC => self.bvc(instruction.mode),
-                operation::Instruction::INC => self.inc(instruction.mode),
-                operation::Instruction::INX => self.inx(),
-                operation::Instruction::INY => self.iny(),
-                operation::Instruction::TXS => self.txs(),
-                operation::Instruction::TSX => self.tsx(),
-                operation::Instruction::TXA => self.txa(),
-                operation::Instruction::TYA => self.tya(),
-                operation::Instruction::PHA => self.pha(),
-                operation::Instruction::PLA => self.pla(),
-                operation::Instruction::PLP => self.plp(),
-                operation::Instruction::BCS => self.bcs(instruction.mode),
-                operation::Instruction::BCC => self.bcc(instruction.mode),
-                operation::Instruction::TAX => self.tax(),
-                operation::Instruction::TAY => self.tay(),
-                operation::Instruction::LSR => self.lsr(instruction.mode),
-                operation::Instruction::ROR => self.ror(instruction.mode),
-                operation::Instruction::ROL => self.rol(instruction.mode),
-                operation::Instruction::PHP => self.php(),
-                operation::Instruction::ASL => self.asl(instruction.mode),
-                operation::Instruction::AND => self.and(instruction.mode),
-                operation::Instruction::BIT => self.bit(instruction.mode),
-                operation::Instruction::BRK => self.brk(instruction.mode),
-                operation::Instruction::ORA => self.ora(instruction.mode),
-                operation::Instruction::RTI => self.rti(instruction.mode),
-                operation::Instruction::CLC => {
-                    self.status_flags.set_flag(status_reg::Flag::Carry, false)
-                }
-                operation::Instruction::CLD => self
-                    .status_flags
-                    .set_flag(status_reg::Flag::DecimalMode, false),
-                operation::Instruction::CLI => self
-                    .status_flags
-                    .set_flag(status_reg::Flag::Interrupt, false),
-                operation::Instruction::CLV => self
-                    .status_flags
-                    .set_flag(status_reg::Flag::Overflow, false),
-                operation::Instruction::SEC => {
-                    self.status_flags.set_flag(status_reg::Flag::Carry, true)
-                }
-                operation::Instruction::SED => self
-                    .status_flags
-                    .set_flag(status_reg::Flag::DecimalMode, true),
-                operation::Instruction::SEI => self
-                    .status_flags
-                    .set_flag(status_reg::Flag::Interrupt, true),
-            }
-            // increment program counter by instruction length
-            if !matches!(
-                instruction.instruction_type,
-                operation::Instruction::JMP
-                    | operation::Instruction::JSR
-                    | operation::Instruction::RTS
-                    | operation::Instruction::BEQ
-                    | operation::Instruction::BCS
-                    | operation::Instruction::BNE
-                    | operation::Instruction::BCC
-                    | operation::Instruction::BVC
-                    | operation::Instruction::BVS
-                    | operation::Instruction::BPL
-                    | operation::Instruction::BMI
-            ) {
-                self.program_counter += instruction.instruction_byte_length as u16;
+            if !debugger.before_instruction(self) {
+                return Ok(());
             }
+            debugger.execute_instruction(self)?;
 
-            self.instructions_executed += 1;
             if self.cmdline_args.instrumentation && (self.instructions_executed % 10000000 == 0) {
                 let duration = self.start_time.elapsed().as_nanos();
                 // we have been executing for this long
@@ -1039,6 +1662,150 @@ impl Cpu6502 {
         }
     }
 
+    // Executes the instruction at the program counter
+    pub fn step(&mut self) {
+        if let Some(mut trace) = self.trace.take() {
+            trace.record(self);
+            self.trace = Some(trace);
+        }
+        let pc = self.program_counter;
+        let cur_opcode = self.get_next_byte();
+        let instruction: operation::InstructionMetadata =
+            operation::get_opcode_metadata(cur_opcode);
+        self.stack_wrapped = false;
+        let mut cycles = instruction.base_cycles();
+        if instruction.has_page_penalty() && self.index_crosses_page(instruction.mode) {
+            cycles += 1;
+        }
+        self.memory.start_recording();
+
+        match instruction.instruction_type {
+            operation::Instruction::ADC => self.adc(instruction.mode),
+            operation::Instruction::SBC => self.sbc(instruction.mode),
+            operation::Instruction::STX => self.stx(instruction.mode),
+            operation::Instruction::STY => self.sty(instruction.mode),
+            operation::Instruction::LDX => self.ldx(instruction.mode),
+            operation::Instruction::LDY => self.ldy(instruction.mode),
+            operation::Instruction::CPX => self.cpx(instruction.mode),
+            operation::Instruction::CPY => self.cpy(instruction.mode),
+            operation::Instruction::DEC => self.dec(instruction.mode),
+            operation::Instruction::DEX => self.dex(),
+            operation::Instruction::DEY => self.dey(),
+            operation::Instruction::EOR => self.eor(instruction.mode),
+            operation::Instruction::JMP => self.jmp(instruction.mode),
+            operation::Instruction::NOP => {}
+            operation::Instruction::LDA => self.lda(instruction.mode),
+            operation::Instruction::STA => self.sta(instruction.mode),
+            operation::Instruction::JSR => self.jsr(instruction.mode),
+            operation::Instruction::RTS => self.rts(),
+            operation::Instruction::CMP => self.cmp(instruction.mode),
+            operation::Instruction::BPL => self.bpl(instruction.mode),
+            operation::Instruction::BEQ => self.beq(instruction.mode),
+            operation::Instruction::BNE => self.bne(instruction.mode),
+            operation::Instruction::BMI => self.bmi(instruction.mode),
+            operation::Instruction::BVS => self.bvs(instruction.mode),
+            operation::Instruction::BVC => self.bvc(instruction.mode),
+            operation::Instruction::INC => self.inc(instruction.mode),
+            operation::Instruction::INX => self.inx(),
+            operation::Instruction::INY => self.iny(),
+            operation::Instruction::TXS => self.txs(),
+            operation::Instruction::TSX => self.tsx(),
+            operation::Instruction::TXA => self.txa(),
+            operation::Instruction::TYA => self.tya(),
+            operation::Instruction::PHA => self.pha(),
+            operation::Instruction::PLA => self.pla(),
+            operation::Instruction::PLP => self.plp(),
+            operation::Instruction::BCS => self.bcs(instruction.mode),
+            operation::Instruction::BCC => self.bcc(instruction.mode),
+            operation::Instruction::TAX => self.tax(),
+            operation::Instruction::TAY => self.tay(),
+            operation::Instruction::LSR => self.lsr(instruction.mode),
+            operation::Instruction::ROR => self.ror(instruction.mode),
+            operation::Instruction::ROL => self.rol(instruction.mode),
+            operation::Instruction::PHP => self.php(),
+            operation::Instruction::ASL => self.asl(instruction.mode),
+            operation::Instruction::AND => self.and(instruction.mode),
+            operation::Instruction::BIT => self.bit(instruction.mode),
+            operation::Instruction::BRK => self.brk(instruction.mode),
+            operation::Instruction::ORA => self.ora(instruction.mode),
+            operation::Instruction::RTI => self.rti(instruction.mode),
+            operation::Instruction::CLC => {
+                self.status_flags.set_flag(status_reg::Flag::Carry, false)
+            }
+            operation::Instruction::CLD => self
+                .status_flags
+                .set_flag(status_reg::Flag::DecimalMode, false),
+            operation::Instruction::CLI => self
+                .status_flags
+                .set_flag(status_reg::Flag::Interrupt, false),
+            operation::Instruction::CLV => self
+                .status_flags
+                .set_flag(status_reg::Flag::Overflow, false),
+            operation::Instruction::SEC => {
+                self.status_flags.set_flag(status_reg::Flag::Carry, true)
+            }
+            operation::Instruction::SED => self
+                .status_flags
+                .set_flag(status_reg::Flag::DecimalMode, true),
+            operation::Instruction::SEI => self
+                .status_flags
+                .set_flag(status_reg::Flag::Interrupt, true),
+        }
+        // increment program counter by instruction length
+        if !matches!(
+            instruction.instruction_type,
+            operation::Instruction::JMP
+                | operation::Instruction::BRK
+                | operation::Instruction::JSR
+                | operation::Instruction::RTS
+                | operation::Instruction::BEQ
+                | operation::Instruction::BCS
+                | operation::Instruction::BNE
+                | operation::Instruction::BCC
+                | operation::Instruction::BVC
+                | operation::Instruction::BVS
+                | operation::Instruction::BPL
+                | operation::Instruction::BMI
+                | operation::Instruction::RTI
+        ) {
+            self.program_counter += instruction.instruction_byte_length as u16;
+        }
+
+        // taken branches cost a cycle, two when landing on another page
+        let next_pc = pc.wrapping_add(instruction.instruction_byte_length as u16);
+        if matches!(instruction.mode, operation::AddressingMode::Relative)
+            && self.program_counter != next_pc
+        {
+            cycles += 1 + (self.program_counter & 0xFF00 != next_pc & 0xFF00) as u64;
+        }
+        self.cycles += cycles;
+
+        self.memory.stop_recording(&mut self.last_accesses);
+        // reads of the instruction's own operand bytes are fetches, not data
+        let len = instruction.instruction_byte_length as u16;
+        self.last_accesses.retain(|access| {
+            !matches!(access.kind, memory::AccessKind::Read(_))
+                || access.addr.wrapping_sub(pc) >= len
+        });
+
+        self.instructions_executed += 1;
+        if let Some(coverage) = &mut self.coverage {
+            coverage.instruction(pc, &self.last_accesses);
+        }
+        if self.profile.is_some() {
+            let after = self.registers();
+            let kind = instruction.instruction_type;
+            if let Some(profile) = &mut self.profile {
+                profile.instruction(pc, kind, cycles, &after);
+            }
+        }
+        if let Some(mut recording) = self.recording.take() {
+            let call = matches!(instruction.instruction_type, operation::Instruction::JSR);
+            recording.instruction(self, pc, call);
+            self.recording = Some(recording);
+        }
+    }
+
     fn adc(&mut self, mode: operation::AddressingMode) {
         // TODO: Decimal mode if status register
         // has decimal mode flag set need to treat
@@ -1114,7 +1881,7 @@ impl Timer {
 
 #[cfg(test)]
 mod tests {
-    use crate::cpu6502::bcd_to_u8;
+    use crate::cpu6502::{bcd_to_u8, cpu_with_program};
 
     #[test]
     fn test_bcd_to_u8_valid_input() {
@@ -1148,4 +1915,51 @@ mod tests {
             "0x0F is not a valid BCD and should return None"
         );
     }
+
+    #[test]
+    fn test_brk_enters_irq_vector() {
+        let mut cpu = cpu_with_program(&[], 0x0600, &[0x00]); // BRK
+        cpu.set_byte_wrap(0xfffe, 0x00);
+        cpu.set_byte_wrap(0xffff, 0x80);
+        cpu.stack_pointer = 0xff;
+        cpu.status_flags.set_from_u8(0x21);
+        cpu.step();
+
+        assert_eq!(cpu.program_counter, 0x8000);
+        assert_eq!(cpu.stack_pointer, 0xfc);
+        assert!(cpu.status_flags.i);
+        // return address skips the padding byte, B is set in the pushed P
+        assert_eq!(cpu.memory.get_byte(0x01ff), 0x06);
+        assert_eq!(cpu.memory.get_byte(0x01fe), 0x02);
+        assert_eq!(cpu.memory.get_byte(0x01fd), 0x31);
+    }
+
+    #[test]
+    fn test_ldy_absolute_x() {
+        // LDY $0200,X
+        let mut cpu = cpu_with_program(&[], 0x0600, &[0xbc, 0x00, 0x02]);
+        cpu.set_byte_wrap(0x0205, 0x42);
+        cpu.x_index = 5;
+        cpu.y_index = 5;
+        cpu.step();
+
+        assert_eq!(cpu.y_index, 0x42);
+        assert_eq!(cpu.program_counter, 0x0603);
+    }
+
+    #[test]
+    fn test_sbc_indirect_y() {
+        // SBC ($24),Y
+        let mut cpu = cpu_with_program(&[], 0x0600, &[0xf1, 0x24]);
+        cpu.memory.load_segment(0x0024, &[0x00, 0x03]);
+        cpu.set_byte_wrap(0x0304, 0x10);
+        cpu.accumulator = 0x50;
+        cpu.y_index = 4;
+        cpu.status_flags.c = true;
+        cpu.step();
+
+        assert_eq!(cpu.accumulator, 0x40);
+        assert!(cpu.status_flags.c);
+        assert_eq!(cpu.program_counter, 0x0602);
+    }
 }
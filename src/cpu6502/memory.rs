@@ -1,6 +1,8 @@
 use crate::cpu6502::MEM_SIZE;
+use crate::symbols::SymbolTable;
 use colored::Colorize;
 use log::debug;
+use std::cell::{Cell, RefCell};
 use std::io::{self, Write};
 
 const BACKSPACE: u8 = 0x08;
@@ -22,23 +24,81 @@ impl MemMap {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read(u8),
+    Write { old: u8, new: u8 },
+}
+
+// A single data access made by an instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Access {
+    pub addr: u16,
+    pub kind: AccessKind,
+}
+
 pub struct Mem {
     memory: Vec<u8>,
+    // logging costs a push per access, so it stays off until something
+    // that reads last_accesses turns it on
+    log_accesses: bool,
+    // get_byte only borrows, so the access log lives behind a RefCell
+    recording: Cell<bool>,
+    accesses: RefCell<Vec<Access>>,
+    // CHROUT output is collected here instead of printed while Some
+    console: Option<Vec<u8>>,
 }
 impl Mem {
     pub fn init_mem() -> Mem {
         Mem {
             memory: vec![0; MEM_SIZE],
+            log_accesses: false,
+            recording: Cell::new(false),
+            accesses: RefCell::new(Vec::new()),
+            console: None,
+        }
+    }
+
+    // For watchpoints, catchpoints, the undo log, coverage and --record
+    pub fn enable_access_log(&mut self) {
+        self.log_accesses = true;
+    }
+
+    // Starts logging get_byte/set_byte calls, used around a single instruction
+    // so that reads made for display are never mistaken for program accesses
+    pub fn start_recording(&self) {
+        if self.log_accesses {
+            self.accesses.borrow_mut().clear();
+            self.recording.set(true);
         }
     }
 
+    pub fn stop_recording(&self, into: &mut Vec<Access>) {
+        self.recording.set(false);
+        into.clear();
+        into.append(&mut self.accesses.borrow_mut());
+    }
+
     pub fn set_byte(&mut self, index: usize, val: u8) {
         debug!("set addr 0x{:#>04x} to 0x{:#>02x}", index, val);
+        if self.recording.get() {
+            self.accesses.get_mut().push(Access {
+                addr: index as u16,
+                kind: AccessKind::Write {
+                    old: self.memory[index],
+                    new: val,
+                },
+            });
+        }
         self.memory[index] = val;
         if let MemMap::CHROUT = MemMap::from_index(index) {
             if val == 0x0 {
                 return;
             }
+            if let Some(console) = &mut self.console {
+                console.push(val);
+                return;
+            }
             if val == BACKSPACE {
                 print!("\u{0008}");
                 print!(" ");
@@ -54,8 +114,27 @@ impl Mem {
         }
     }
 
+    pub fn capture_console(&mut self) {
+        self.console = Some(Vec::new());
+    }
+
+    // CHROUT bytes written since the last call
+    pub fn take_console(&mut self) -> Vec<u8> {
+        self.console
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
     pub fn get_byte(&self, index: usize) -> u8 {
-        self.memory[index]
+        let val = self.memory[index];
+        if self.recording.get() {
+            self.accesses.borrow_mut().push(Access {
+                addr: index as u16,
+                kind: AccessKind::Read(val),
+            });
+        }
+        val
     }
 
     pub fn get(&self, index: usize) -> Option<&u8> {
@@ -66,33 +145,13 @@ impl Mem {
         self.set_byte(index, self.memory[index].wrapping_sub(1));
     }
 
-    pub fn dump_memory(&self, print_all: bool, pc: u16, sp: u8) {
+    pub fn dump_memory(&self, print_all: bool, pc: u16, sp: u8, symbols: &SymbolTable) {
         let mut new_zero_line: bool = true;
         println!("Memory: 0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f");
         for i in (0..MEM_SIZE).step_by(0x10) {
             let slice = &self.memory[i..i + 0x10];
             if slice.iter().any(|&x| x > 0) || print_all {
-                print!("0x{i:#>04x}: ");
-                for (offset, byte) in slice.iter().enumerate() {
-                    if i + offset == pc as usize {
-                        print!("{}", format!("{:02x}", byte).blue().underline());
-                    } else if i + offset == sp as usize | 0x0100 {
-                        print!("{}", format!("{:02x}", byte).yellow().underline());
-                    } else {
-                        print!("{byte:02x}");
-                    }
-                    print!(" ");
-                }
-
-                for &byte in slice {
-                    if byte.is_ascii() && byte.is_ascii_graphic() {
-                        let c: char = byte as char;
-                        print!("{c}")
-                    } else {
-                        print!(".")
-                    }
-                }
-                println!();
+                self.print_row(i, 0x10, pc, sp, symbols);
                 new_zero_line = false;
             } else if !new_zero_line {
                 println!("*");
@@ -101,7 +160,60 @@ impl Mem {
         }
     }
 
+    // Dumps [start, end] without skipping zeroed rows
+    pub fn dump_range(&self, start: usize, end: usize, pc: u16, sp: u8, symbols: &SymbolTable) {
+        println!("Memory: 0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f");
+        let mut row = start & !0xf;
+        while row <= end {
+            self.print_row(row, 0x10.min(MEM_SIZE - row), pc, sp, symbols);
+            row += 0x10;
+        }
+    }
+
+    fn print_row(&self, i: usize, len: usize, pc: u16, sp: u8, symbols: &SymbolTable) {
+        let slice = &self.memory[i..i + len];
+        print!("0x{i:#>04x}: ");
+        for (offset, byte) in slice.iter().enumerate() {
+            if i + offset == pc as usize {
+                print!("{}", format!("{:02x}", byte).blue().underline());
+            } else if i + offset == sp as usize | 0x0100 {
+                print!("{}", format!("{:02x}", byte).yellow().underline());
+            } else {
+                print!("{byte:02x}");
+            }
+            print!(" ");
+        }
+
+        for &byte in slice {
+            if byte.is_ascii() && byte.is_ascii_graphic() {
+                let c: char = byte as char;
+                print!("{c}")
+            } else {
+                print!(".")
+            }
+        }
+
+        // name any labels that fall in this row
+        let labels: Vec<String> = symbols
+            .in_range(i as u16, (i + len) as u32)
+            .map(|(addr, name)| format!("{}=${:04x}", name, addr))
+            .collect();
+        if !labels.is_empty() {
+            print!("  {}", labels.join(" ").cyan());
+        }
+        println!();
+    }
+
     pub fn set_all(&mut self, new_mem: Vec<u8>) {
         self.memory = new_mem;
     }
+
+    pub fn raw_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    pub fn load_segment(&mut self, index: usize, data: &[u8]) {
+        // copies bytes in place without triggering memory mapped io
+        self.memory[index..index + data.len()].copy_from_slice(data);
+    }
 }
@@ -58,7 +58,7 @@ pub enum Instruction {
     ROL,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum AddressingMode {
     Accumulator,
     Implied,
@@ -170,10 +170,10 @@ const fn create_instruction_metadata(opcode: u8) -> InstructionMetadata {
         0xe5 => InstructionMetadata::new(AddressingMode::ZeroPage, Instruction::SBC),
         0xf5 => InstructionMetadata::new(AddressingMode::ZeroPageX, Instruction::SBC),
         0xe1 => {
-            InstructionMetadata::new(AddressingMode::ZeroPageIndirectIndexedX, Instruction::ADC)
+            InstructionMetadata::new(AddressingMode::ZeroPageIndirectIndexedX, Instruction::SBC)
         }
         0xf1 => {
-            InstructionMetadata::new(AddressingMode::ZeroPageIndirectIndexedY, Instruction::ADC)
+            InstructionMetadata::new(AddressingMode::ZeroPageIndirectIndexedY, Instruction::SBC)
         }
 
         // BCC
@@ -189,9 +189,9 @@ const fn create_instruction_metadata(opcode: u8) -> InstructionMetadata {
         // LDY
         0xa0 => InstructionMetadata::new(AddressingMode::Immediate, Instruction::LDY),
         0xac => InstructionMetadata::new(AddressingMode::Absolute, Instruction::LDY),
-        0xbc => InstructionMetadata::new(AddressingMode::AbsoluteYIndexed, Instruction::LDY),
+        0xbc => InstructionMetadata::new(AddressingMode::AbsoluteXIndexed, Instruction::LDY),
         0xa4 => InstructionMetadata::new(AddressingMode::ZeroPage, Instruction::LDY),
-        0xb4 => InstructionMetadata::new(AddressingMode::ZeroPageY, Instruction::LDY),
+        0xb4 => InstructionMetadata::new(AddressingMode::ZeroPageX, Instruction::LDY),
 
         // STX
         0x8e => InstructionMetadata::new(AddressingMode::Absolute, Instruction::STX),
@@ -436,14 +436,87 @@ const OPCODE_METADATA: [InstructionMetadata; 256] = {
     table
 };
 
-pub fn get_opcode_metadata(opcode: u8) -> InstructionMetadata {
-    // get operation from const lookup table
-    let operation = OPCODE_METADATA[opcode as usize];
+const fn is_store(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::STA | Instruction::STX | Instruction::STY
+    )
+}
+
+// read-modify-write instructions spend extra cycles writing the result back
+const fn is_read_modify_write(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::ASL
+            | Instruction::LSR
+            | Instruction::ROL
+            | Instruction::ROR
+            | Instruction::INC
+            | Instruction::DEC
+    )
+}
 
-    if let Instruction::NOP = operation.instruction_type {
-        if let AddressingMode::ZeroPage = operation.mode {
-            todo!("Missing instruction metadata for opcode 0x{:#>02x}", opcode)
+impl InstructionMetadata {
+    // Cycles taken before page crossing and branch penalties
+    pub const fn base_cycles(&self) -> u64 {
+        let store = is_store(self.instruction_type);
+        let rmw = is_read_modify_write(self.instruction_type);
+        match self.mode {
+            AddressingMode::Accumulator | AddressingMode::Immediate => 2,
+            AddressingMode::Relative => 2,
+            AddressingMode::Implied => match self.instruction_type {
+                Instruction::BRK => 7,
+                Instruction::RTI | Instruction::RTS => 6,
+                Instruction::PHA | Instruction::PHP => 3,
+                Instruction::PLA | Instruction::PLP => 4,
+                _ => 2,
+            },
+            AddressingMode::ZeroPage if rmw => 5,
+            AddressingMode::ZeroPage => 3,
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY if rmw => 6,
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => 4,
+            AddressingMode::Absolute => match self.instruction_type {
+                Instruction::JMP => 3,
+                Instruction::JSR => 6,
+                _ if rmw => 6,
+                _ => 4,
+            },
+            AddressingMode::AbsoluteXIndexed | AddressingMode::AbsoluteYIndexed if rmw => 7,
+            AddressingMode::AbsoluteXIndexed | AddressingMode::AbsoluteYIndexed if store => 5,
+            AddressingMode::AbsoluteXIndexed | AddressingMode::AbsoluteYIndexed => 4,
+            AddressingMode::AbsoluteIndirect => 5,
+            AddressingMode::AbsoluteIndirectX | AddressingMode::AbsoluteIndirectY => 6,
+            AddressingMode::ZeroPageIndirectIndexedX => 6,
+            AddressingMode::ZeroPageIndirectIndexedY if store => 6,
+            AddressingMode::ZeroPageIndirectIndexedY => 5,
         }
     }
-    operation
+
+    // Indexed reads take a cycle longer when the index crosses a page
+    pub const fn has_page_penalty(&self) -> bool {
+        matches!(
+            self.mode,
+            AddressingMode::AbsoluteXIndexed
+                | AddressingMode::AbsoluteYIndexed
+                | AddressingMode::ZeroPageIndirectIndexedY
+        ) && !is_store(self.instruction_type)
+            && !is_read_modify_write(self.instruction_type)
+    }
+}
+
+// Metadata for an opcode, None for opcodes without an entry in the table
+pub fn lookup_opcode(opcode: u8) -> Option<InstructionMetadata> {
+    let operation = OPCODE_METADATA[opcode as usize];
+    if operation.instruction_byte_length == 0 {
+        return None;
+    }
+    Some(operation)
+}
+
+pub fn get_opcode_metadata(opcode: u8) -> InstructionMetadata {
+    // get operation from const lookup table
+    match lookup_opcode(opcode) {
+        Some(operation) => operation,
+        None => todo!("Missing instruction metadata for opcode 0x{:#>02x}", opcode),
+    }
 }
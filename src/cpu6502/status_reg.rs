@@ -57,7 +57,18 @@ impl StatusFlags {
         }
     }
 
-    pub fn as_u8(&mut self) -> u8 {
+    pub fn set_from_u8(&mut self, val: u8) {
+        self.n = val & 1 << 7 != 0;
+        self.v = val & 1 << 6 != 0;
+        self.u = val & 1 << 5 != 0;
+        self.b = val & 1 << 4 != 0;
+        self.d = val & 1 << 3 != 0;
+        self.i = val & 1 << 2 != 0;
+        self.z = val & 1 << 1 != 0;
+        self.c = val & 1 != 0;
+    }
+
+    pub fn as_u8(&self) -> u8 {
         let mut result = 0b00000000;
 
         result |= (self.n as u8) << 7;
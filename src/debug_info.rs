@@ -0,0 +1,375 @@
+// Reader for the debug info file ld65 writes with `--dbgfile`. The file is a
+// list of records such as
+//
+//   file    id=0,name="hello_world.s",size=798,mtime=0x65bfd4b1,mod=0
+//   seg     id=0,name="CODE",start=0x000600,size=0x0031,addrsize=absolute,...
+//   span    id=0,seg=0,start=0,size=2
+//   line    id=0,file=0,line=12,span=0
+//   scope   id=0,name="",mod=0,size=49,span=0+1+2
+//   sym     id=0,name="print",addrsize=absolute,scope=0,def=9,val=0x61C,type=lab
+//
+// and is used to map addresses back to `.s` source lines and labels.
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SourceLine {
+    pub file: usize,
+    pub line: usize,
+}
+
+struct Span {
+    seg: usize,
+    start: usize,
+    size: usize,
+    // ca65 gives spans from .byte, .word and friends a type, code has none
+    data: bool,
+}
+
+struct LineRecord {
+    source: SourceLine,
+    kind: usize,
+    spans: Vec<usize>,
+}
+
+struct ScopeRecord {
+    name: String,
+    spans: Vec<usize>,
+}
+
+pub struct DebugSymbol {
+    pub name: String,
+    pub value: u16,
+    pub is_label: bool,
+}
+
+#[derive(Default)]
+pub struct DebugInfo {
+    files: HashMap<usize, String>,
+    segs: HashMap<usize, usize>,
+    spans: HashMap<usize, Span>,
+    lines: Vec<LineRecord>,
+    scopes: Vec<ScopeRecord>,
+    pub symbols: Vec<DebugSymbol>,
+    addr_to_line: HashMap<u16, (usize, SourceLine)>,
+    source_dir: PathBuf,
+    source_cache: HashMap<usize, Vec<String>>,
+}
+
+// Splits `id=0,name="a,b",span=1+2` into key/value pairs, honouring quotes
+fn parse_fields(text: &str) -> HashMap<&str, &str> {
+    let mut fields = HashMap::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let eq = match rest.find('=') {
+            Some(eq) => eq,
+            None => break,
+        };
+        let key = rest[..eq].trim();
+        let value_start = &rest[eq + 1..];
+        let (value, next) = if let Some(quoted) = value_start.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            let after = &quoted[(end + 1).min(quoted.len())..];
+            (&quoted[..end], after.strip_prefix(',').unwrap_or(after))
+        } else {
+            match value_start.find(',') {
+                Some(comma) => (&value_start[..comma], &value_start[comma + 1..]),
+                None => (value_start, ""),
+            }
+        };
+        fields.insert(key, value);
+        rest = next;
+    }
+    fields
+}
+
+fn parse_number(text: &str) -> Result<usize, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse::<usize>(),
+    };
+    parsed.map_err(|_| format!("invalid number '{}'", text))
+}
+
+fn parse_id_list(text: &str) -> Result<Vec<usize>, String> {
+    text.split('+').map(parse_number).collect()
+}
+
+fn required(fields: &HashMap<&str, &str>, key: &str) -> Result<usize, String> {
+    match fields.get(key) {
+        Some(value) => parse_number(value),
+        None => Err(format!("missing '{}' field", key)),
+    }
+}
+
+impl DebugInfo {
+    pub fn load(path: &str) -> Result<DebugInfo, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut info = DebugInfo::parse(&text)?;
+        // source paths in the dbgfile are relative to where ca65 ran, which
+        // for the assembler's own dbgfiles is the directory they are written to
+        info.source_dir = Path::new(path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        Ok(info)
+    }
+
+    pub fn parse(text: &str) -> Result<DebugInfo, String> {
+        let mut info = DebugInfo::default();
+        for (line_no, line) in text.lines().enumerate() {
+            let (record, rest) = match line.split_once(char::is_whitespace) {
+                Some(split) => split,
+                None => continue,
+            };
+            let fields = parse_fields(rest.trim());
+            info.parse_record(record, &fields)
+                .map_err(|e| format!("line {}: {}", line_no + 1, e))?;
+        }
+        info.build_address_map();
+        Ok(info)
+    }
+
+    fn parse_record(&mut self, record: &str, fields: &HashMap<&str, &str>) -> Result<(), String> {
+        match record {
+            "file" => {
+                let name = fields.get("name").ok_or("file without name")?;
+                self.files.insert(required(fields, "id")?, name.to_string());
+            }
+            "seg" => {
+                self.segs
+                    .insert(required(fields, "id")?, required(fields, "start")?);
+            }
+            "span" => {
+                self.spans.insert(
+                    required(fields, "id")?,
+                    Span {
+                        seg: required(fields, "seg")?,
+                        start: required(fields, "start")?,
+                        size: required(fields, "size")?,
+                        data: fields.contains_key("type"),
+                    },
+                );
+            }
+            "line" => {
+                // lines without spans did not produce any bytes
+                if let Some(spans) = fields.get("span") {
+                    self.lines.push(LineRecord {
+                        source: SourceLine {
+                            file: required(fields, "file")?,
+                            line: required(fields, "line")?,
+                        },
+                        kind: fields.get("type").map_or(Ok(0), |t| parse_number(t))?,
+                        spans: parse_id_list(spans)?,
+                    });
+                }
+            }
+            "scope" => {
+                if let Some(spans) = fields.get("span") {
+                    self.scopes.push(ScopeRecord {
+                        name: fields.get("name").unwrap_or(&"").to_string(),
+                        spans: parse_id_list(spans)?,
+                    });
+                }
+            }
+            "sym" => {
+                if let (Some(name), Some(val)) = (fields.get("name"), fields.get("val")) {
+                    let value = parse_number(val)?;
+                    if value <= 0xFFFF {
+                        self.symbols.push(DebugSymbol {
+                            name: name.to_string(),
+                            value: value as u16,
+                            is_label: fields.get("type") == Some(&"lab"),
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn span_range(&self, span_id: usize) -> Option<(usize, usize)> {
+        let span = self.spans.get(&span_id)?;
+        let seg_start = self.segs.get(&span.seg)?;
+        Some((seg_start + span.start, span.size))
+    }
+
+    fn build_address_map(&mut self) {
+        let mut map: HashMap<u16, (usize, SourceLine)> = HashMap::new();
+        for line in &self.lines {
+            for &span_id in &line.spans {
+                let (start, size) = match self.span_range(span_id) {
+                    Some(range) => range,
+                    None => continue,
+                };
+                for addr in start..(start + size).min(0x10000) {
+                    // plain assembler lines win over macro expansions
+                    let entry = map.entry(addr as u16).or_insert((line.kind, line.source));
+                    if line.kind < entry.0 {
+                        *entry = (line.kind, line.source);
+                    }
+                }
+            }
+        }
+        self.addr_to_line = map;
+    }
+
+    pub fn line_at(&self, addr: u16) -> Option<SourceLine> {
+        self.addr_to_line.get(&addr).map(|&(_, source)| source)
+    }
+
+    pub fn file_name(&self, file: usize) -> &str {
+        self.files.get(&file).map_or("?", |name| name.as_str())
+    }
+
+    // Innermost named scope covering an address, ie the `.proc` it is in
+    pub fn scope_at(&self, addr: u16) -> Option<&str> {
+        let addr = addr as usize;
+        self.scopes
+            .iter()
+            .filter(|scope| !scope.name.is_empty())
+            .filter_map(|scope| {
+                let size: usize = scope
+                    .spans
+                    .iter()
+                    .filter_map(|&id| self.span_range(id))
+                    .filter(|&(start, size)| addr >= start && addr < start + size)
+                    .map(|(_, size)| size)
+                    .min()?;
+                Some((size, scope.name.as_str()))
+            })
+            .min()
+            .map(|(_, name)| name)
+    }
+
+    // Source lines that produced instructions rather than data, each with
+    // the first address generated for it
+    pub fn code_lines(&self) -> Vec<(SourceLine, u16)> {
+        let mut lines: HashMap<(usize, usize), u16> = HashMap::new();
+        for record in &self.lines {
+            let start = record
+                .spans
+                .iter()
+                .filter(|id| self.spans.get(id).is_some_and(|span| !span.data))
+                .filter_map(|&id| self.span_range(id))
+                .map(|(start, _)| start as u16)
+                .min();
+            if let Some(start) = start {
+                let key = (record.source.file, record.source.line);
+                let addr = lines.entry(key).or_insert(start);
+                *addr = (*addr).min(start);
+            }
+        }
+        let mut lines: Vec<(SourceLine, u16)> = lines
+            .into_iter()
+            .map(|((file, line), addr)| (SourceLine { file, line }, addr))
+            .collect();
+        lines.sort_by_key(|(source, _)| (source.file, source.line));
+        lines
+    }
+
+    // Resolves `file.s:line` to the first address generated for that line,
+    // moving forward to the next line that produced code like gdb does
+    pub fn addr_of_line(&self, file: &str, line: usize) -> Option<u16> {
+        let wanted = Path::new(file).file_name()?;
+        self.lines
+            .iter()
+            .filter(|record| {
+                record.source.line >= line
+                    && self
+                        .files
+                        .get(&record.source.file)
+                        .and_then(|name| Path::new(name).file_name())
+                        == Some(wanted)
+            })
+            .filter_map(|record| {
+                let addr = record
+                    .spans
+                    .iter()
+                    .filter_map(|&id| self.span_range(id))
+                    .map(|(start, _)| start)
+                    .min()?;
+                Some((record.source.line, addr))
+            })
+            .min()
+            .map(|(_, addr)| addr as u16)
+    }
+
+    // Where a source file is on disk, next to the dbgfile
+    pub fn source_path(&self, file: usize) -> PathBuf {
+        self.source_dir.join(self.file_name(file))
+    }
+
+    // Source text for a line, read lazily from disk next to the dbgfile
+    pub fn source_text(&mut self, source: SourceLine) -> Option<&str> {
+        if !self.source_cache.contains_key(&source.file) {
+            let path = self.source_path(source.file);
+            let lines = fs::read_to_string(path)
+                .map(|text| text.lines().map(str::to_string).collect())
+                .unwrap_or_default();
+            self.source_cache.insert(source.file, lines);
+        }
+        self.source_cache
+            .get(&source.file)?
+            .get(source.line.checked_sub(1)?)
+            .map(|text| text.trim())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debug_info::{DebugInfo, SourceLine};
+
+    const HELLO_DBG: &str = "version\tmajor=2,minor=0
+file\tid=0,name=\"hello_world.s\",size=798,mtime=0x65BFD4B1,mod=0
+mod\tid=0,name=\"hello_world.o\",file=0
+seg\tid=0,name=\"CODE\",start=0x000600,size=0x0031,addrsize=absolute,type=ro,oname=\"hello_world\",ooffs=1536
+seg\tid=1,name=\"DATA\",start=0x000000,size=0x0010,addrsize=absolute,type=rw,oname=\"hello_world\",ooffs=0
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=2
+span\tid=2,seg=0,start=28,size=3
+span\tid=3,seg=0,start=0,size=49
+line\tid=0,file=0,line=13,span=0
+line\tid=1,file=0,line=14,span=1
+line\tid=2,file=0,line=24,span=2
+line\tid=3,file=0,line=20
+scope\tid=0,name=\"\",mod=0,size=49,span=3
+sym\tid=0,name=\"print\",addrsize=absolute,scope=0,def=2,ref=1,val=0x61C,seg=0,type=lab
+sym\tid=1,name=\"hello\",addrsize=absolute,scope=0,def=3,val=0x0,seg=1,type=lab
+";
+
+    #[test]
+    fn test_address_to_line() {
+        let info = DebugInfo::parse(HELLO_DBG).expect("dbgfile should parse");
+        let source = info.line_at(0x0603).expect("0x0603 should map to a line");
+        assert_eq!(source, SourceLine { file: 0, line: 14 });
+        assert_eq!(info.file_name(source.file), "hello_world.s");
+        assert_eq!(info.line_at(0x061c).map(|s| s.line), Some(24));
+        assert!(info.line_at(0x0700).is_none());
+    }
+
+    #[test]
+    fn test_line_to_address() {
+        let info = DebugInfo::parse(HELLO_DBG).expect("dbgfile should parse");
+        assert_eq!(info.addr_of_line("hello_world.s", 14), Some(0x0602));
+        // line 20 has no code so the next line with code is used
+        assert_eq!(
+            info.addr_of_line("examples/hello_world.s", 20),
+            Some(0x061c)
+        );
+        assert_eq!(info.addr_of_line("count_up.s", 14), None);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let info = DebugInfo::parse(HELLO_DBG).expect("dbgfile should parse");
+        let names: Vec<(&str, u16)> = info
+            .symbols
+            .iter()
+            .map(|sym| (sym.name.as_str(), sym.value))
+            .collect();
+        assert_eq!(names, vec![("print", 0x061c), ("hello", 0x0000)]);
+    }
+}
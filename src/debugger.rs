@@ -0,0 +1,962 @@
+use crate::asm;
+use crate::coverage::heatmap;
+use crate::cpu6502::memory::{AccessKind, MemMap};
+use crate::cpu6502::{operation, Cpu6502, Interrupt};
+use crate::utils::line_editor::LineEditor;
+use breakpoints::{parse_watch_spec, Breakpoints, WatchKind};
+use catchpoints::{Catchpoints, Event};
+use colored::Colorize;
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, is_raw_mode_enabled};
+use expr::Expr;
+use history::History;
+
+pub mod breakpoints;
+pub mod catchpoints;
+pub mod dap;
+pub mod expr;
+pub mod history;
+pub mod logpoints;
+pub mod monitor;
+pub mod repl;
+pub mod tui;
+
+const HELP: &str = "\
+Commands:
+  step [n]      (s)  execute n instructions, default 1
+  next          (n)  step over a JSR
+  finish        (f)  run until the current subroutine returns
+  continue      (c)  run until a breakpoint, watchpoint or catchpoint
+  back [n]      (bs) undo the last n instructions, default 1
+  reverse-continue
+                (rc) run backwards to the last breakpoint or watchpoint hit
+  break LOC [if COND] [hit N]
+                (b)  stop before the instruction at LOC runs, when COND
+                     holds, from the Nth hit on; without LOC COND is
+                     checked before every instruction
+  condition N [COND] change or remove the condition of breakpoint N
+  log LOC [if COND] [hit N] \"TEXT\"
+                     print TEXT each time LOC is reached and keep running,
+                     {EXPR} in TEXT is replaced by its value ({X:d} decimal,
+                     {A:c} character)
+  watch SPEC         stop after a write to SPEC
+  rwatch SPEC        stop after a read of SPEC
+  awatch SPEC        stop after a read or write of SPEC
+  catch [EVENT]      stop on an event, list the events without EVENT
+  uncatch EVENT      stop catching EVENT
+  info          (i)  list breakpoints, watchpoints and catchpoints
+  delete [N]         delete breakpoint N, or all of them
+  enable N / disable N
+  print EXPR    (p)  evaluate an expression, eg mem[$24] + X
+  display EXPR       print EXPR every time execution stops
+  undisplay [N]      remove display N, or all of them
+  regs          (r)  show registers and flags
+  set REG=VAL        set A, X, Y, SP, PC, P or a flag (N V D I Z C)
+  irq / nmi          raise an interrupt after the current instruction
+  mem START [END] (m) dump memory, 64 bytes if END is left out
+  poke ADDR VAL...   write bytes starting at ADDR
+  disasm [ADDR] [N] (d) disassemble N instructions, default from PC
+  assemble [ADDR] (a) assemble lines into memory from ADDR, default PC,
+                     until an empty line
+  profile [N]        show the N hottest instructions and subroutines so far,
+                     default 10, needs --profile
+  heatmap            show how memory has been used so far, needs --heatmap
+  quit          (q)  stop the emulator
+Enter on an empty line repeats the last command.
+Addresses take $hex, 0xhex, decimal, symbols and file.s:line.
+SPEC is ADDR or ADDR-END, with =VAL to only stop when VAL is transferred.
+Expressions use A X Y SP PC P cycles flags.C mem[ADDR] word[ADDR], symbols,
+numbers and the C operators, eg A == $0D && mem[$24] > 3.";
+
+enum RunMode {
+    // instructions left to execute before stopping again
+    Step(u64),
+    Continue,
+    StepOver { return_pc: u16, sp: u8 },
+    Finish { sp: u8 },
+}
+
+enum Resume {
+    Run,
+    Quit,
+}
+
+// Why a guarded step stopped, with the message to show
+pub enum Stop {
+    Breakpoint { id: usize, text: String },
+    Watchpoint { id: usize, text: String },
+    // a breakpoint condition that could not be evaluated
+    Error(String),
+    // catchpoints and invalid opcodes
+    Exception(String),
+}
+
+impl Stop {
+    pub fn text(&self) -> &str {
+        match self {
+            Stop::Breakpoint { text, .. }
+            | Stop::Watchpoint { text, .. }
+            | Stop::Error(text)
+            | Stop::Exception(text) => text,
+        }
+    }
+}
+
+// The checks every front-end runs around an instruction: breakpoints,
+// logpoints and opcode catchpoints before it, watchpoints and the other
+// catchpoints after it, and the undo log
+pub struct Guard {
+    pub breakpoints: Breakpoints,
+    pub catchpoints: Catchpoints,
+    pub history: History,
+    // the undo log only fills once the debugger has stopped or can stop,
+    // so plain runs do not copy the registers every instruction
+    pub debugging: bool,
+    // the breakpoint we stopped at must not fire again on resume
+    skip_breakpoint: bool,
+}
+
+impl Guard {
+    pub fn new(cpu: &Cpu6502) -> Guard {
+        Guard {
+            breakpoints: Breakpoints::default(),
+            catchpoints: Catchpoints::default(),
+            history: History::new(cpu.cmdline_args.history),
+            debugging: cpu.cmdline_args.step_debug,
+            skip_breakpoint: false,
+        }
+    }
+
+    // Guard with the --break, --log, --*watch and --catch specs from the command line
+    pub fn from_args(cpu: &mut Cpu6502) -> Guard {
+        let mut guard = Guard::new(cpu);
+        guard.breakpoints = Breakpoints::from_args(cpu);
+        for spec in &cpu.cmdline_args.catch {
+            if let Err(error) = guard.catchpoints.catch(spec, |text| parse_value(cpu, text)) {
+                panic!("Problem with catchpoint {}: {}", spec, error);
+            }
+        }
+        guard.debugging |= !guard.breakpoints.breaks.is_empty()
+            || !guard.breakpoints.watches.is_empty()
+            || guard.catchpoints.enabled().next().is_some();
+        guard.log_accesses(cpu);
+        guard
+    }
+
+    // Watchpoints, ROM and I/O catchpoints and the undo log all look at the
+    // accesses each instruction made
+    pub fn log_accesses(&self, cpu: &mut Cpu6502) {
+        if !self.breakpoints.watches.is_empty()
+            || self.catchpoints.is_enabled(Event::RomWrite)
+            || self.catchpoints.is_enabled(Event::Io)
+            || (self.debugging && self.history.capacity() > 0)
+        {
+            cpu.memory.enable_access_log();
+        }
+    }
+
+    // Called when a front-end carries on from a stop, the breakpoints at PC
+    // are skipped once and watchpoints added meanwhile start logging
+    pub fn resume(&mut self, cpu: &mut Cpu6502) {
+        self.skip_breakpoint = true;
+        self.debugging = true;
+        self.log_accesses(cpu);
+    }
+
+    // Runs the instruction at PC unless something stops it first, logpoint
+    // messages go to log
+    pub fn step(&mut self, cpu: &mut Cpu6502, log: &mut dyn FnMut(String)) -> Option<Stop> {
+        self.check(cpu, log).or_else(|| self.execute(cpu))
+    }
+
+    // Breakpoints, logpoints and opcode catchpoints at PC
+    pub fn check(&mut self, cpu: &Cpu6502, log: &mut dyn FnMut(String)) -> Option<Stop> {
+        if std::mem::take(&mut self.skip_breakpoint) {
+            return None;
+        }
+        let pc = cpu.program_counter;
+        match self.breakpoints.check_break(cpu, log) {
+            Some(Ok(id)) => {
+                let text = format!("Breakpoint {} hit at 0x{:#>04x}", id, pc);
+                return Some(Stop::Breakpoint { id, text });
+            }
+            Some(Err(error)) => return Some(Stop::Error(error)),
+            None => {}
+        }
+        let opcode = cpu.memory.get_byte(pc as usize);
+        let event = match operation::lookup_opcode(opcode) {
+            None => Event::Invalid,
+            Some(op) => match op.instruction_type {
+                operation::Instruction::BRK => Event::Brk,
+                operation::Instruction::RTI => Event::Rti,
+                _ => return None,
+            },
+        };
+        if !self.catchpoints.is_enabled(event) {
+            return None;
+        }
+        Some(Stop::Exception(match event {
+            Event::Invalid => format!(
+                "Catchpoint: undocumented or invalid opcode ${:02x} at 0x{:#>04x}",
+                opcode, pc
+            ),
+            Event::Brk => format!("Catchpoint: BRK at 0x{:#>04x}", pc),
+            _ => format!("Catchpoint: RTI at 0x{:#>04x}", pc),
+        }))
+    }
+
+    // Runs the instruction at PC and then any interrupt that is due, without
+    // the checks made before it. An invalid opcode is not run.
+    pub fn execute(&mut self, cpu: &mut Cpu6502) -> Option<Stop> {
+        let pc = cpu.program_counter;
+        let opcode = cpu.memory.get_byte(pc as usize);
+        if operation::lookup_opcode(opcode).is_none() {
+            let text = format!("Invalid opcode ${:02x} at 0x{:#>04x}", opcode, pc);
+            return Some(Stop::Exception(text));
+        }
+        let stop = self.instruction(cpu);
+        let entered = self.interrupt(cpu);
+        stop.or(entered)
+    }
+
+    // Runs the instruction at PC, which must be valid, leaving interrupts to
+    // interrupt() so a front-end can look at its accesses in between
+    pub fn instruction(&mut self, cpu: &mut Cpu6502) -> Option<Stop> {
+        let pc = cpu.program_counter;
+        let before = self.debugging.then(|| cpu.registers());
+        cpu.step();
+        if let Some(before) = before {
+            self.history.record(before, &cpu.last_accesses);
+        }
+        self.after_instruction(cpu, pc)
+    }
+
+    // Enters the handler of a pending interrupt, if one is due
+    pub fn interrupt(&mut self, cpu: &mut Cpu6502) -> Option<Stop> {
+        let before = self.debugging.then(|| cpu.registers());
+        let interrupt = cpu.service_interrupt()?;
+        if let Some(before) = before {
+            self.history.record(before, &cpu.last_accesses);
+        }
+        self.interrupt_entered(cpu, interrupt)
+    }
+
+    // Watchpoints and the catchpoints on what the instruction at pc did
+    fn after_instruction(&self, cpu: &Cpu6502, pc: u16) -> Option<Stop> {
+        if let Some((wp, access)) = self.breakpoints.check(&cpu.last_accesses) {
+            let text = format!(
+                "Watchpoint {}: {} {} at PC 0x{:#>04x}",
+                wp.id,
+                describe_access(access.kind),
+                cpu.symbolic(access.addr, 4),
+                pc
+            );
+            return Some(Stop::Watchpoint { id: wp.id, text });
+        }
+
+        if cpu.stack_wrapped && self.catchpoints.is_enabled(Event::StackWrap) {
+            return Some(Stop::Exception(format!(
+                "Catchpoint: stack pointer wrapped to ${:02x} at PC 0x{:#>04x}",
+                cpu.stack_pointer, pc
+            )));
+        }
+        let catch_rom = self.catchpoints.is_enabled(Event::RomWrite);
+        let catch_io = self.catchpoints.is_enabled(Event::Io);
+        if !catch_rom && !catch_io {
+            return None;
+        }
+        for access in &cpu.last_accesses {
+            let is_write = matches!(access.kind, AccessKind::Write { .. });
+            let event =
+                if catch_io && !matches!(MemMap::from_index(access.addr as usize), MemMap::NOMAP) {
+                    "I/O"
+                } else if catch_rom && is_write && self.catchpoints.in_rom(access.addr) {
+                    "ROM"
+                } else {
+                    continue;
+                };
+            return Some(Stop::Exception(format!(
+                "Catchpoint: {} {} {} at PC 0x{:#>04x}",
+                event,
+                describe_access(access.kind),
+                cpu.symbolic(access.addr, 4),
+                pc
+            )));
+        }
+        None
+    }
+
+    // The catchpoints on the CPU having just entered an interrupt handler
+    fn interrupt_entered(&self, cpu: &Cpu6502, interrupt: Interrupt) -> Option<Stop> {
+        let (event, name) = match interrupt {
+            Interrupt::Irq => (Event::Irq, "IRQ"),
+            Interrupt::Nmi => (Event::Nmi, "NMI"),
+        };
+        if self.catchpoints.is_enabled(event) {
+            return Some(Stop::Exception(format!(
+                "Catchpoint: {} entry, handler at {}",
+                name,
+                cpu.symbolic(cpu.program_counter, 4)
+            )));
+        }
+        if cpu.stack_wrapped && self.catchpoints.is_enabled(Event::StackWrap) {
+            return Some(Stop::Exception(format!(
+                "Catchpoint: stack pointer wrapped to ${:02x} entering the {} handler",
+                cpu.stack_pointer, name
+            )));
+        }
+        None
+    }
+}
+
+pub struct Debugger {
+    mode: RunMode,
+    editor: LineEditor,
+    last_command: String,
+    guard: Guard,
+    // a watchpoint or catchpoint fired after the last instruction
+    stop_pending: bool,
+    displays: Vec<Expr>,
+    // where `back` left us, breakpoints there must not fire on resume
+    rewound_to: Option<u16>,
+}
+
+impl Debugger {
+    // Debugger with the --break, --log, --*watch and --catch specs from the command line
+    pub fn from_args(cpu: &mut Cpu6502) -> Debugger {
+        Debugger {
+            mode: if cpu.cmdline_args.step_debug {
+                RunMode::Step(0)
+            } else {
+                RunMode::Continue
+            },
+            editor: LineEditor::default(),
+            last_command: String::new(),
+            guard: Guard::from_args(cpu),
+            stop_pending: false,
+            displays: Vec::new(),
+            rewound_to: None,
+        }
+    }
+
+    // Called before every instruction, returns false once the user quits
+    pub fn before_instruction(&mut self, cpu: &mut Cpu6502) -> bool {
+        let mut stop = match self.mode {
+            RunMode::Step(0) => true,
+            RunMode::Step(remaining) => {
+                self.mode = RunMode::Step(remaining - 1);
+                false
+            }
+            RunMode::Continue => false,
+            RunMode::StepOver { return_pc, sp } => {
+                cpu.program_counter == return_pc && cpu.stack_pointer == sp
+            }
+            // RTS popped the return address of the frame we were in
+            RunMode::Finish { sp } => cpu.stack_pointer as u16 >= sp as u16 + 2,
+        };
+
+        loop {
+            let pc = cpu.program_counter;
+            // \r\n as keyboard mode leaves the terminal raw
+            let log = &mut |line: String| print!("{}\r\n", line);
+            if let Some(hit) = self.guard.check(cpu, log) {
+                print_stop(&hit);
+                stop = true;
+            }
+            if std::mem::take(&mut self.stop_pending) {
+                stop = true;
+            }
+
+            if !stop {
+                return true;
+            }
+            match self.prompt(cpu) {
+                Resume::Run
+                    if cpu.program_counter == pc
+                        || self.rewound_to.take() == Some(cpu.program_counter) =>
+                {
+                    self.guard.resume(cpu);
+                    return true;
+                }
+                // `set PC=` moved us, check the new location before running it
+                Resume::Run => stop = false,
+                Resume::Quit => return false,
+            }
+        }
+    }
+
+    // Runs the instruction before_instruction let through, a watchpoint or
+    // catchpoint it sets off stops before the next one. An invalid opcode
+    // ends a run that nothing can stop, there is no prompt to go back to.
+    pub fn execute_instruction(&mut self, cpu: &mut Cpu6502) -> Result<(), String> {
+        if let Some(hit) = self.guard.execute(cpu) {
+            if !self.guard.debugging {
+                return Err(hit.text().to_string());
+            }
+            print_stop(&hit);
+            self.stop_pending = true;
+        }
+        Ok(())
+    }
+
+    fn prompt(&mut self, cpu: &mut Cpu6502) -> Resume {
+        self.rewound_to = None;
+        self.guard.debugging = true;
+        // keyboard mode keeps the terminal raw, the prompt wants it cooked
+        let was_raw = is_raw_mode_enabled().unwrap_or(false);
+        if was_raw {
+            disable_raw_mode().unwrap();
+        }
+        if cpu.cmdline_args.no_print {
+            let (_, text) = disassemble(cpu, cpu.program_counter);
+            println!("=> 0x{:#>04x}: {}", cpu.program_counter, text);
+        }
+        for (i, display) in self.displays.iter().enumerate() {
+            match display.eval(cpu) {
+                Ok(value) => println!("{}: {} = {}", i + 1, display, format_value(value)),
+                Err(error) => println!("{}: {} = {}", i + 1, display, error.red()),
+            }
+        }
+
+        let resume = loop {
+            let line = match self.editor.read_line("(6502) ") {
+                Some(line) => line,
+                None => break Resume::Quit,
+            };
+            let line = if line.trim().is_empty() {
+                self.last_command.clone()
+            } else {
+                line.trim().to_string()
+            };
+            if line.is_empty() {
+                continue;
+            }
+            self.last_command = line.clone();
+
+            match self.execute(cpu, &line) {
+                Ok(Some(resume)) => break resume,
+                Ok(None) => {}
+                Err(error) => println!("{}", error.red()),
+            }
+        };
+
+        if was_raw {
+            enable_raw_mode().unwrap();
+        }
+        // the commands may have added a watchpoint or catchpoint
+        self.guard.log_accesses(cpu);
+        resume
+    }
+
+    // Runs one command line, Some when execution should resume or stop
+    fn execute(&mut self, cpu: &mut Cpu6502, line: &str) -> Result<Option<Resume>, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+        match command {
+            "step" | "s" => {
+                let count = match args.first() {
+                    Some(count) => count
+                        .parse::<u64>()
+                        .map_err(|_| format!("invalid step count '{}'", count))?,
+                    None => 1,
+                };
+                self.mode = RunMode::Step(count.max(1) - 1);
+                Ok(Some(Resume::Run))
+            }
+            "next" | "n" => {
+                let opcode = cpu.memory.get_byte(cpu.program_counter as usize);
+                self.mode = match operation::lookup_opcode(opcode) {
+                    Some(op) if matches!(op.instruction_type, operation::Instruction::JSR) => {
+                        RunMode::StepOver {
+                            return_pc: cpu.program_counter.wrapping_add(3),
+                            sp: cpu.stack_pointer,
+                        }
+                    }
+                    _ => RunMode::Step(0),
+                };
+                Ok(Some(Resume::Run))
+            }
+            "finish" | "f" => {
+                self.mode = RunMode::Finish {
+                    sp: cpu.stack_pointer,
+                };
+                Ok(Some(Resume::Run))
+            }
+            "continue" | "c" => {
+                self.mode = RunMode::Continue;
+                Ok(Some(Resume::Run))
+            }
+            "back" | "bs" => {
+                let count = match args.first() {
+                    Some(count) => count
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid step count '{}'", count))?,
+                    None => 1,
+                };
+                let mut undone = 0;
+                while undone < count && self.guard.history.step_back(cpu).is_some() {
+                    undone += 1;
+                }
+                if undone < count {
+                    println!("Reached the start of the recorded history");
+                }
+                self.rewound(cpu);
+                Ok(None)
+            }
+            "reverse-continue" | "rc" => {
+                self.reverse_continue(cpu);
+                self.rewound(cpu);
+                Ok(None)
+            }
+            "profile" => {
+                let count = match args.first() {
+                    Some(count) => count
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid count '{}'", count))?,
+                    None => 10,
+                };
+                match &cpu.profile {
+                    Some(profile) => profile.report(cpu, count),
+                    None => return Err("profiling is off, run with --profile".to_string()),
+                }
+                Ok(None)
+            }
+            "heatmap" => {
+                match &cpu.coverage {
+                    Some(coverage) => heatmap::render(coverage),
+                    None => return Err("memory use is off, run with --heatmap".to_string()),
+                }
+                Ok(None)
+            }
+            "regs" | "r" => {
+                cpu.print_registers();
+                Ok(None)
+            }
+            "set" => {
+                set_register(cpu, &args.join(" "))?;
+                Ok(None)
+            }
+            "mem" | "m" => {
+                let start = cpu.resolve_location(args.first().ok_or("usage: mem START [END]")?)?;
+                let end = match args.get(1) {
+                    Some(end) => cpu.resolve_location(end)?,
+                    None => start.saturating_add(0x3f),
+                };
+                if end < start {
+                    return Err("END is before START".to_string());
+                }
+                cpu.memory.dump_range(
+                    start as usize,
+                    end as usize,
+                    cpu.program_counter,
+                    cpu.stack_pointer,
+                    &cpu.symbols,
+                );
+                Ok(None)
+            }
+            "poke" => {
+                let (addr, values) = args.split_first().ok_or("usage: poke ADDR VAL...")?;
+                if values.is_empty() {
+                    return Err("usage: poke ADDR VAL...".to_string());
+                }
+                let addr = cpu.resolve_location(addr)?;
+                for (offset, value) in values.iter().enumerate() {
+                    let value = parse_value(cpu, value)?;
+                    if value > 0xFF {
+                        return Err(format!("{} does not fit in a byte", value));
+                    }
+                    cpu.set_byte_wrap(addr.wrapping_add(offset as u16) as usize, value as u8);
+                }
+                Ok(None)
+            }
+            "disasm" | "d" => {
+                let mut addr = match args.first() {
+                    Some(addr) => cpu.resolve_location(addr)?,
+                    None => cpu.program_counter,
+                };
+                let count = match args.get(1) {
+                    Some(count) => count
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid count '{}'", count))?,
+                    None => 10,
+                };
+                for _ in 0..count {
+                    let (len, text) = disassemble(cpu, addr);
+                    let marker = if addr == cpu.program_counter {
+                        "=>"
+                    } else {
+                        "  "
+                    };
+                    println!("{} 0x{:#>04x}: {}", marker, addr, text);
+                    addr = addr.wrapping_add(len);
+                }
+                Ok(None)
+            }
+            "assemble" | "a" => {
+                let mut addr = match args.first() {
+                    Some(addr) => cpu.resolve_location(addr)?,
+                    None => cpu.program_counter,
+                };
+                // Enter on the empty line that ends it must not start it again
+                self.last_command.clear();
+                loop {
+                    let line = match self.editor.read_line(&format!("0x{:#>04x}: ", addr)) {
+                        Some(line) if !line.trim().is_empty() => line,
+                        _ => break,
+                    };
+                    let known: Vec<(String, u16)> = cpu
+                        .symbols
+                        .in_range(0, 0x10000)
+                        .map(|(addr, name)| (name.to_string(), addr))
+                        .collect();
+                    let output = match asm::assemble_at(&line, addr, &known) {
+                        Ok(output) => output,
+                        Err(error) => {
+                            println!("{}", error.red());
+                            continue;
+                        }
+                    };
+                    for (name, value) in output.labels {
+                        cpu.symbols.insert(name, value);
+                    }
+                    for (offset, byte) in output.image.iter().enumerate() {
+                        cpu.set_byte_wrap(addr.wrapping_add(offset as u16) as usize, *byte);
+                    }
+                    // bound by the bytes written, `.byte $20` decodes as a longer JSR
+                    let start = addr;
+                    let mut at = addr;
+                    addr = addr.wrapping_add(output.image.len() as u16);
+                    while (at.wrapping_sub(start) as usize) < output.image.len() {
+                        let (len, text) = disassemble(cpu, at);
+                        println!("   0x{:#>04x}: {}", at, text);
+                        at = at.wrapping_add(len);
+                    }
+                }
+                Ok(None)
+            }
+            "break" | "b" => {
+                if args.is_empty() {
+                    return Err("usage: break LOC [if COND] [hit N]".to_string());
+                }
+                let id = self
+                    .guard
+                    .breakpoints
+                    .add_break_spec(cpu, &args.join(" "))?;
+                println!("Breakpoint {} set", id);
+                Ok(None)
+            }
+            "log" => {
+                // the message is quoted, so take the rest of the line as is
+                let spec = line
+                    .split_once(char::is_whitespace)
+                    .map_or("", |(_, rest)| rest);
+                let id = self.guard.breakpoints.add_log_spec(cpu, spec)?;
+                println!("Logpoint {} set", id);
+                Ok(None)
+            }
+            "condition" => {
+                let (id, condition) = args.split_first().ok_or("usage: condition N [COND]")?;
+                let condition = if condition.is_empty() {
+                    None
+                } else {
+                    Some(Expr::parse(&condition.join(" "))?)
+                };
+                self.guard
+                    .breakpoints
+                    .set_condition(parse_id(id)?, condition)?;
+                Ok(None)
+            }
+            "print" | "p" => {
+                let value = Expr::parse(&args.join(" "))?.eval(cpu)?;
+                println!("{}", format_value(value));
+                Ok(None)
+            }
+            "display" => {
+                let display = Expr::parse(&args.join(" "))?;
+                println!(
+                    "{}: {} = {}",
+                    self.displays.len() + 1,
+                    display,
+                    format_value(display.eval(cpu)?)
+                );
+                self.displays.push(display);
+                Ok(None)
+            }
+            "undisplay" => {
+                match args.first() {
+                    Some(id) => {
+                        let id = parse_id(id)?;
+                        if id == 0 || id > self.displays.len() {
+                            return Err(format!("no display number {}", id));
+                        }
+                        self.displays.remove(id - 1);
+                    }
+                    None => self.displays.clear(),
+                }
+                Ok(None)
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let kind = match command {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let spec = args.join("");
+                if spec.is_empty() {
+                    return Err(format!("usage: {} ADDR[-END][=VAL]", command));
+                }
+                let spec = parse_watch_spec(&spec, |text| parse_value(cpu, text))?;
+                let id = self.guard.breakpoints.add_watch(kind, spec);
+                println!("Watchpoint {} set", id);
+                Ok(None)
+            }
+            "catch" => {
+                if args.is_empty() {
+                    for (name, _, text) in catchpoints::EVENTS {
+                        println!("  {:<8} {}", name, text);
+                    }
+                    return Ok(None);
+                }
+                let event = self
+                    .guard
+                    .catchpoints
+                    .catch(&args.join(" "), |text| parse_value(cpu, text))?;
+                println!("Catchpoint on {}", catchpoints::describe(event));
+                Ok(None)
+            }
+            "uncatch" => {
+                self.guard
+                    .catchpoints
+                    .uncatch(args.first().ok_or("usage: uncatch EVENT")?)?;
+                Ok(None)
+            }
+            "irq" => {
+                cpu.pending_interrupt = Some(Interrupt::Irq);
+                if cpu.status_flags.i {
+                    println!("IRQ pending, it is masked until the I flag is cleared");
+                }
+                Ok(None)
+            }
+            "nmi" => {
+                cpu.pending_interrupt = Some(Interrupt::Nmi);
+                Ok(None)
+            }
+            "info" | "i" => {
+                self.print_breakpoints(cpu);
+                Ok(None)
+            }
+            "delete" => {
+                match args.first() {
+                    Some(id) => self.guard.breakpoints.delete(parse_id(id)?)?,
+                    None => self.guard.breakpoints.delete_all(),
+                }
+                Ok(None)
+            }
+            "enable" | "disable" => {
+                let id = parse_id(args.first().ok_or(format!("usage: {} N", command))?)?;
+                self.guard
+                    .breakpoints
+                    .set_enabled(id, command == "enable")?;
+                Ok(None)
+            }
+            "quit" | "q" => Ok(Some(Resume::Quit)),
+            "help" | "h" | "?" => {
+                println!("{}", HELP);
+                Ok(None)
+            }
+            _ => Err(format!("unknown command '{}', try 'help'", command)),
+        }
+    }
+
+    // Steps back until an instruction hits a watchpoint or the PC lands on
+    // a breakpoint
+    fn reverse_continue(&mut self, cpu: &mut Cpu6502) {
+        while let Some(accesses) = self.guard.history.step_back(cpu) {
+            if let Some((wp, access)) = self.guard.breakpoints.check(&accesses) {
+                println!(
+                    "Watchpoint {}: {} {}",
+                    wp.id,
+                    describe_access(access.kind),
+                    cpu.symbolic(access.addr, 4)
+                );
+                return;
+            }
+            if let Some(id) = self.guard.breakpoints.stops_at(cpu) {
+                println!("Breakpoint {} hit at 0x{:#>04x}", id, cpu.program_counter);
+                return;
+            }
+        }
+        println!("Reached the start of the recorded history");
+    }
+
+    // Shows where stepping back ended up
+    fn rewound(&mut self, cpu: &Cpu6502) {
+        let (_, text) = disassemble(cpu, cpu.program_counter);
+        println!(
+            "=> 0x{:#>04x}: {}  ({} instructions of history left)",
+            cpu.program_counter,
+            text,
+            self.guard.history.len()
+        );
+        self.rewound_to = Some(cpu.program_counter);
+    }
+
+    fn print_breakpoints(&self, cpu: &Cpu6502) {
+        for event in self.guard.catchpoints.enabled() {
+            println!("  -  catch   {}", catchpoints::describe(event));
+        }
+        for &(start, end) in &self.guard.catchpoints.rom {
+            println!("  -  rom     ${:04x}-${:04x}", start, end);
+        }
+        if self.guard.breakpoints.breaks.is_empty() && self.guard.breakpoints.watches.is_empty() {
+            println!("No breakpoints or watchpoints.");
+            return;
+        }
+        let state = |enabled: bool| if enabled { "" } else { " (disabled)" };
+        for bp in &self.guard.breakpoints.breaks {
+            let mut spec = match bp.addr {
+                Some(addr) => cpu.symbolic(addr, 4),
+                None => "anywhere".to_string(),
+            };
+            if let Some(condition) = &bp.condition {
+                spec = format!("{} if {}", spec, condition);
+            }
+            if bp.stop_at_hit > 1 {
+                spec = format!("{} hit {}", spec, bp.stop_at_hit);
+            }
+            let kind = match &bp.log {
+                Some(log) => {
+                    spec = format!("{} \"{}\"", spec, log.text());
+                    "log"
+                }
+                None => "break",
+            };
+            println!(
+                "{:>3}  {:<6}  {}{}, hit {} times",
+                bp.id,
+                kind,
+                spec,
+                state(bp.enabled),
+                bp.hits
+            );
+        }
+        for wp in &self.guard.breakpoints.watches {
+            let kind = match wp.kind {
+                WatchKind::Read => "rwatch",
+                WatchKind::Write => "watch",
+                WatchKind::Access => "awatch",
+            };
+            let mut range = cpu.symbolic(wp.start, 4);
+            if wp.end != wp.start {
+                range = format!("{}-{}", range, cpu.symbolic(wp.end, 4));
+            }
+            if let Some(value) = wp.value {
+                range = format!("{}=${:02x}", range, value);
+            }
+            println!("{:>3}  {:<6}  {}{}", wp.id, kind, range, state(wp.enabled));
+        }
+    }
+}
+
+// Condition errors are red like the other debugger errors
+fn print_stop(stop: &Stop) {
+    match stop {
+        Stop::Error(error) => println!("\n{}", error.red()),
+        _ => println!("\n{}", stop.text()),
+    }
+}
+
+fn describe_access(kind: AccessKind) -> String {
+    match kind {
+        AccessKind::Read(val) => format!("read ${:02x} from", val),
+        AccessKind::Write { old, new } => format!("write ${:02x} (was ${:02x}) to", new, old),
+    }
+}
+
+fn format_value(value: i64) -> String {
+    format!("${:02x} ({})", value, value)
+}
+
+fn parse_id(text: &str) -> Result<usize, String> {
+    text.parse::<usize>()
+        .map_err(|_| format!("invalid breakpoint number '{}'", text))
+}
+
+// Numbers in the ca65 styles plus anything resolve_location accepts
+fn parse_value(cpu: &Cpu6502, text: &str) -> Result<u16, String> {
+    if let Some(bin) = text.strip_prefix('%') {
+        return u16::from_str_radix(bin, 2).map_err(|_| format!("invalid value '{}'", text));
+    }
+    let bytes = text.as_bytes();
+    if bytes.len() == 3 && bytes[0] == b'\'' && bytes[2] == b'\'' {
+        return Ok(bytes[1] as u16);
+    }
+    cpu.resolve_location(text)
+}
+
+fn set_register(cpu: &mut Cpu6502, assignment: &str) -> Result<(), String> {
+    let (register, value) = assignment
+        .split_once('=')
+        .ok_or("usage: set REG=VAL, eg set A=$10")?;
+    let register = register.trim().to_ascii_uppercase();
+    let value = parse_value(cpu, value.trim())?;
+    let byte = || -> Result<u8, String> {
+        u8::try_from(value).map_err(|_| format!("{} does not fit in a byte", value))
+    };
+    let flag = || -> Result<bool, String> {
+        match value {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err("flags take 0 or 1".to_string()),
+        }
+    };
+    match register.as_str() {
+        "A" => cpu.accumulator = byte()?,
+        "X" => cpu.x_index = byte()?,
+        "Y" => cpu.y_index = byte()?,
+        "SP" => cpu.stack_pointer = byte()?,
+        "PC" => cpu.program_counter = value,
+        "P" => cpu.status_flags.set_from_u8(byte()?),
+        "N" => cpu.status_flags.n = flag()?,
+        "V" => cpu.status_flags.v = flag()?,
+        "D" => cpu.status_flags.d = flag()?,
+        "I" => cpu.status_flags.i = flag()?,
+        "Z" => cpu.status_flags.z = flag()?,
+        "C" => cpu.status_flags.c = flag()?,
+        _ => return Err(format!("unknown register '{}'", register)),
+    }
+    Ok(())
+}
+
+// An address up to `lines` instructions before addr that decodes straight
+// into it. 6502 code cannot be decoded backwards, so try the furthest start
+// first and take the first that lines up.
+fn lead_in(cpu: &Cpu6502, addr: u16, lines: usize) -> u16 {
+    for back in (1..=lines as u16 * 3).rev() {
+        let start = addr.wrapping_sub(back);
+        let mut at = start;
+        let mut count = 0;
+        while at != addr && count < lines && addr.wrapping_sub(at) <= back {
+            at = at.wrapping_add(cpu.disassemble(at).len());
+            count += 1;
+        }
+        if at == addr {
+            return start;
+        }
+    }
+    addr
+}
+
+// One instruction at addr as raw bytes plus mnemonic, and its length
+fn disassemble(cpu: &Cpu6502, addr: u16) -> (u16, String) {
+    let decoded = cpu.disassemble(addr);
+    (
+        decoded.len(),
+        format!(
+            "{:<9} {} {}",
+            decoded.hex(),
+            decoded.mnemonic().green(),
+            decoded.operand(Some(&cpu.symbols))
+        ),
+    )
+}
@@ -0,0 +1,432 @@
+// Breakpoints stop before the instruction at an address runs, watchpoints
+// stop after an instruction touched a watched address.
+use crate::cpu6502::memory::{Access, AccessKind};
+use crate::cpu6502::Cpu6502;
+use crate::debugger::expr::Expr;
+use crate::debugger::logpoints::{self, Template};
+use crate::debugger::parse_value;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+pub struct Breakpoint {
+    pub id: usize,
+    // None checks the condition before every instruction
+    pub addr: Option<u16>,
+    pub condition: Option<Expr>,
+    // stop from this hit on, hits only count while the condition holds
+    pub stop_at_hit: u64,
+    pub hits: u64,
+    pub enabled: bool,
+    // logpoints print this and keep running instead of stopping
+    pub log: Option<Template>,
+}
+
+// `LOC`, `LOC if COND`, `if COND`, any of them with a trailing `hit N`
+pub struct BreakSpec {
+    pub location: Option<String>,
+    pub condition: Option<Expr>,
+    pub stop_at_hit: u64,
+    pub log: Option<Template>,
+}
+
+pub struct Watchpoint {
+    pub id: usize,
+    pub kind: WatchKind,
+    pub start: u16,
+    pub end: u16,
+    // only fire when this byte is transferred
+    pub value: Option<u8>,
+    pub enabled: bool,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: &Access) -> bool {
+        if !self.enabled || access.addr < self.start || access.addr > self.end {
+            return false;
+        }
+        let (is_write, byte) = match access.kind {
+            AccessKind::Read(val) => (false, val),
+            AccessKind::Write { new, .. } => (true, new),
+        };
+        let kind_matches = match self.kind {
+            WatchKind::Read => !is_write,
+            WatchKind::Write => is_write,
+            WatchKind::Access => true,
+        };
+        kind_matches && self.value.is_none_or(|value| value == byte)
+    }
+}
+
+#[derive(Default)]
+pub struct Breakpoints {
+    last_id: usize,
+    pub breaks: Vec<Breakpoint>,
+    pub watches: Vec<Watchpoint>,
+}
+
+impl Breakpoints {
+    // The --break, --log and --*watch specs from the command line
+    pub fn from_args(cpu: &Cpu6502) -> Breakpoints {
+        let mut breakpoints = Breakpoints::default();
+        for spec in &cpu.cmdline_args.breakpoints {
+            if let Err(error) = breakpoints.add_break_spec(cpu, spec) {
+                panic!("Problem with breakpoint {}: {}", spec, error);
+            }
+        }
+        for spec in &cpu.cmdline_args.log {
+            if let Err(error) = breakpoints.add_log_spec(cpu, spec) {
+                panic!("Problem with logpoint {}: {}", spec, error);
+            }
+        }
+        let watches = [
+            (WatchKind::Write, &cpu.cmdline_args.watch),
+            (WatchKind::Read, &cpu.cmdline_args.rwatch),
+            (WatchKind::Access, &cpu.cmdline_args.awatch),
+        ];
+        for (kind, specs) in watches {
+            for spec in specs {
+                match parse_watch_spec(spec, |text| parse_value(cpu, text)) {
+                    Ok(spec) => breakpoints.add_watch(kind, spec),
+                    Err(error) => panic!("Problem with watchpoint {}: {}", spec, error),
+                };
+            }
+        }
+        breakpoints
+    }
+
+    pub fn add_break_spec(&mut self, cpu: &Cpu6502, spec: &str) -> Result<usize, String> {
+        let spec = parse_break_spec(spec)?;
+        let addr = match &spec.location {
+            Some(location) => Some(cpu.resolve_location(location)?),
+            None => None,
+        };
+        Ok(self.add_break(addr, spec))
+    }
+
+    pub fn add_log_spec(&mut self, cpu: &Cpu6502, spec: &str) -> Result<usize, String> {
+        let (location, template) = logpoints::split_log_spec(spec)?;
+        let mut spec = parse_break_spec(location)?;
+        spec.log = Some(template);
+        let addr = match &spec.location {
+            Some(location) => Some(cpu.resolve_location(location)?),
+            None => None,
+        };
+        Ok(self.add_break(addr, spec))
+    }
+
+    pub fn add_break(&mut self, addr: Option<u16>, spec: BreakSpec) -> usize {
+        self.last_id += 1;
+        self.breaks.push(Breakpoint {
+            id: self.last_id,
+            addr,
+            condition: spec.condition,
+            stop_at_hit: spec.stop_at_hit,
+            hits: 0,
+            enabled: true,
+            log: spec.log,
+        });
+        self.last_id
+    }
+
+    pub fn add_watch(&mut self, kind: WatchKind, spec: WatchSpec) -> usize {
+        self.last_id += 1;
+        self.watches.push(Watchpoint {
+            id: self.last_id,
+            kind,
+            start: spec.start,
+            end: spec.end,
+            value: spec.value,
+            enabled: true,
+        });
+        self.last_id
+    }
+
+    // The id of the first breakpoint that stops before the next instruction,
+    // a failing condition stops as well so the user gets to see the error.
+    // Logpoints on the way hand their message to `log`.
+    pub fn check_break(
+        &mut self,
+        cpu: &Cpu6502,
+        log: &mut dyn FnMut(String),
+    ) -> Option<Result<usize, String>> {
+        let pc = cpu.program_counter;
+        for bp in self.breaks.iter_mut() {
+            if !bp.enabled || bp.addr.is_some_and(|addr| addr != pc) {
+                continue;
+            }
+            if let Some(condition) = &bp.condition {
+                match condition.eval(cpu) {
+                    Ok(0) => continue,
+                    Ok(_) => {}
+                    Err(error) => {
+                        return Some(Err(format!(
+                            "Breakpoint {}: error in condition '{}': {}",
+                            bp.id, condition, error
+                        )))
+                    }
+                }
+            }
+            bp.hits += 1;
+            if bp.hits < bp.stop_at_hit {
+                continue;
+            }
+            match &bp.log {
+                Some(template) => log(template.render(cpu)),
+                None => return Some(Ok(bp.id)),
+            }
+        }
+        None
+    }
+
+    // Like check_break but without counting hits or logging, for running
+    // backwards where the hits were already counted on the way forward
+    pub fn stops_at(&self, cpu: &Cpu6502) -> Option<usize> {
+        self.breaks
+            .iter()
+            .find(|bp| {
+                bp.enabled
+                    && bp.log.is_none()
+                    && bp.addr.is_none_or(|addr| addr == cpu.program_counter)
+                    && bp
+                        .condition
+                        .as_ref()
+                        .is_none_or(|condition| condition.eval(cpu).is_ok_and(|value| value != 0))
+            })
+            .map(|bp| bp.id)
+    }
+
+    pub fn set_condition(&mut self, id: usize, condition: Option<Expr>) -> Result<(), String> {
+        let bp = self
+            .breaks
+            .iter_mut()
+            .find(|bp| bp.id == id)
+            .ok_or(format!("no breakpoint number {}", id))?;
+        bp.condition = condition;
+        Ok(())
+    }
+
+    // The first watchpoint hit by an instruction's accesses
+    pub fn check(&self, accesses: &[Access]) -> Option<(&Watchpoint, Access)> {
+        if self.watches.is_empty() {
+            return None;
+        }
+        accesses.iter().find_map(|access| {
+            self.watches
+                .iter()
+                .find(|wp| wp.matches(access))
+                .map(|wp| (wp, *access))
+        })
+    }
+
+    // Removes the plain breakpoints at addr, or adds one if there were none.
+    // Returns the id of the new breakpoint.
+    pub fn toggle(&mut self, addr: u16) -> Option<usize> {
+        let before = self.breaks.len();
+        self.breaks
+            .retain(|bp| bp.addr != Some(addr) || bp.condition.is_some() || bp.log.is_some());
+        if self.breaks.len() != before {
+            return None;
+        }
+        let spec = BreakSpec {
+            location: None,
+            condition: None,
+            stop_at_hit: 1,
+            log: None,
+        };
+        Some(self.add_break(Some(addr), spec))
+    }
+
+    pub fn delete(&mut self, id: usize) -> Result<(), String> {
+        let before = self.breaks.len() + self.watches.len();
+        self.breaks.retain(|bp| bp.id != id);
+        self.watches.retain(|wp| wp.id != id);
+        if self.breaks.len() + self.watches.len() == before {
+            return Err(format!("no breakpoint number {}", id));
+        }
+        Ok(())
+    }
+
+    pub fn delete_all(&mut self) {
+        self.breaks.clear();
+        self.watches.clear();
+    }
+
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> Result<(), String> {
+        if let Some(bp) = self.breaks.iter_mut().find(|bp| bp.id == id) {
+            bp.enabled = enabled;
+        } else if let Some(wp) = self.watches.iter_mut().find(|wp| wp.id == id) {
+            wp.enabled = enabled;
+        } else {
+            return Err(format!("no breakpoint number {}", id));
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_break_spec(spec: &str) -> Result<BreakSpec, String> {
+    let mut spec = spec.trim();
+    let mut stop_at_hit = 1;
+    if let Some((rest, count)) = spec.rsplit_once(" hit ") {
+        stop_at_hit = count
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|&count| count > 0)
+            .ok_or(format!("invalid hit count '{}'", count.trim()))?;
+        spec = rest.trim();
+    }
+    let (location, condition) = match spec.strip_prefix("if ") {
+        Some(condition) => ("", Some(condition)),
+        None => match spec.split_once(" if ") {
+            Some((location, condition)) => (location, Some(condition)),
+            None => (spec, None),
+        },
+    };
+    let location = location.trim();
+    if location.is_empty() && condition.is_none() {
+        return Err("a breakpoint needs a location or a condition".to_string());
+    }
+    Ok(BreakSpec {
+        location: (!location.is_empty()).then(|| location.to_string()),
+        condition: condition.map(Expr::parse).transpose()?,
+        stop_at_hit,
+        log: None,
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub struct WatchSpec {
+    pub start: u16,
+    pub end: u16,
+    pub value: Option<u8>,
+}
+
+// ADDR, ADDR-END, or either followed by =VAL, eg `$0200-$027f=$0d`
+pub fn parse_watch_spec(
+    spec: &str,
+    resolve: impl Fn(&str) -> Result<u16, String>,
+) -> Result<WatchSpec, String> {
+    let (range, value) = match spec.split_once('=') {
+        Some((range, value)) => {
+            let value = resolve(value.trim())?;
+            let value =
+                u8::try_from(value).map_err(|_| format!("{} does not fit in a byte", value))?;
+            (range, Some(value))
+        }
+        None => (spec, None),
+    };
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (resolve(start.trim())?, resolve(end.trim())?),
+        None => {
+            let addr = resolve(range.trim())?;
+            (addr, addr)
+        }
+    };
+    if end < start {
+        return Err(format!("range end ${:04x} is before its start", end));
+    }
+    Ok(WatchSpec { start, end, value })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu6502::memory::{Access, AccessKind};
+    use crate::debugger::breakpoints::{
+        parse_break_spec, parse_watch_spec, Breakpoints, WatchKind, WatchSpec,
+    };
+
+    fn hex(text: &str) -> Result<u16, String> {
+        u16::from_str_radix(text.trim_start_matches('$'), 16).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_parse_break_spec() {
+        let plain = parse_break_spec("print").unwrap();
+        assert_eq!(plain.location.as_deref(), Some("print"));
+        assert!(plain.condition.is_none());
+        assert_eq!(plain.stop_at_hit, 1);
+
+        let full = parse_break_spec("$0621 if A == $0D hit 50").unwrap();
+        assert_eq!(full.location.as_deref(), Some("$0621"));
+        assert_eq!(full.condition.unwrap().to_string(), "A == $0D");
+        assert_eq!(full.stop_at_hit, 50);
+
+        let anywhere = parse_break_spec("if cycles > 100000").unwrap();
+        assert!(anywhere.location.is_none());
+
+        assert!(parse_break_spec("print hit 0").is_err());
+        assert!(parse_break_spec("print if A ==").is_err());
+    }
+
+    #[test]
+    fn test_parse_watch_spec() {
+        assert_eq!(
+            parse_watch_spec("$0200", hex).unwrap(),
+            WatchSpec {
+                start: 0x0200,
+                end: 0x0200,
+                value: None
+            }
+        );
+        assert_eq!(
+            parse_watch_spec("$0200-$027f=$8d", hex).unwrap(),
+            WatchSpec {
+                start: 0x0200,
+                end: 0x027f,
+                value: Some(0x8d)
+            }
+        );
+        assert!(parse_watch_spec("$0300-$0200", hex).is_err());
+        assert!(parse_watch_spec("$0200=$100", hex).is_err());
+    }
+
+    #[test]
+    fn test_watchpoint_kinds() {
+        let mut breakpoints = Breakpoints::default();
+        let spec = |value| WatchSpec {
+            start: 0x0200,
+            end: 0x020f,
+            value,
+        };
+        let write = breakpoints.add_watch(WatchKind::Write, spec(Some(0x0d)));
+        let read = breakpoints.add_watch(WatchKind::Read, spec(None));
+
+        let store = |new| Access {
+            addr: 0x0204,
+            kind: AccessKind::Write { old: 0, new },
+        };
+        assert!(breakpoints.check(&[store(0x41)]).is_none());
+        assert_eq!(breakpoints.check(&[store(0x0d)]).unwrap().0.id, write);
+
+        let load = Access {
+            addr: 0x020f,
+            kind: AccessKind::Read(0x41),
+        };
+        assert_eq!(breakpoints.check(&[load]).unwrap().0.id, read);
+        breakpoints.set_enabled(read, false).unwrap();
+        assert!(breakpoints.check(&[load]).is_none());
+
+        breakpoints.delete(write).unwrap();
+        assert!(breakpoints.delete(write).is_err());
+    }
+
+    #[test]
+    fn test_toggle() {
+        let mut breakpoints = Breakpoints::default();
+        let id = breakpoints.toggle(0x0621).unwrap();
+        assert_eq!(breakpoints.breaks[0].id, id);
+        assert_eq!(breakpoints.breaks[0].addr, Some(0x0621));
+
+        // conditional breakpoints are left alone
+        let spec = parse_break_spec("$0621 if X == 3").unwrap();
+        breakpoints.add_break(Some(0x0621), spec);
+        assert!(breakpoints.toggle(0x0621).is_none());
+        assert_eq!(breakpoints.breaks.len(), 1);
+        assert!(breakpoints.breaks[0].condition.is_some());
+    }
+}
@@ -0,0 +1,146 @@
+// Catchpoints stop on CPU events instead of addresses, each event is
+// switched on by name with `catch EVENT` or `--catch EVENT`.
+use crate::debugger::breakpoints::parse_watch_spec;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Brk,
+    Irq,
+    Nmi,
+    Rti,
+    Invalid,
+    StackWrap,
+    RomWrite,
+    Io,
+}
+
+pub const EVENTS: [(&str, Event, &str); 8] = [
+    ("brk", Event::Brk, "any BRK"),
+    ("irq", Event::Irq, "IRQ entry"),
+    ("nmi", Event::Nmi, "NMI entry"),
+    ("rti", Event::Rti, "any RTI"),
+    ("invalid", Event::Invalid, "undocumented or invalid opcodes"),
+    ("stack", Event::StackWrap, "the stack pointer wrapping"),
+    ("rom", Event::RomWrite, "writes into ROM ranges"),
+    ("io", Event::Io, "any access to CHROUT or CHRIN"),
+];
+
+#[derive(Default)]
+pub struct Catchpoints {
+    events: Vec<Event>,
+    // inclusive ranges that count as ROM for Event::RomWrite
+    pub rom: Vec<(u16, u16)>,
+}
+
+impl Catchpoints {
+    pub fn is_enabled(&self, event: Event) -> bool {
+        self.events.contains(&event)
+    }
+
+    // `EVENT`, or `rom START-END` (also `rom=START-END`) to add a ROM range
+    pub fn catch(
+        &mut self,
+        spec: &str,
+        resolve: impl Fn(&str) -> Result<u16, String>,
+    ) -> Result<Event, String> {
+        let spec = spec.trim();
+        let (name, range) = spec
+            .split_once([' ', '='])
+            .map_or((spec, ""), |(name, range)| (name, range.trim()));
+        let event = parse_event(name)?;
+        if event == Event::RomWrite {
+            if !range.is_empty() {
+                let range = parse_watch_spec(range, resolve)?;
+                if range.value.is_some() {
+                    return Err("ROM ranges do not take a value".to_string());
+                }
+                self.rom.push((range.start, range.end));
+            }
+            if self.rom.is_empty() {
+                return Err("usage: catch rom START-END".to_string());
+            }
+        } else if !range.is_empty() {
+            return Err(format!("'{}' does not take an argument", name));
+        }
+        if !self.is_enabled(event) {
+            self.events.push(event);
+        }
+        Ok(event)
+    }
+
+    pub fn uncatch(&mut self, name: &str) -> Result<(), String> {
+        let event = parse_event(name)?;
+        self.events.retain(|&enabled| enabled != event);
+        Ok(())
+    }
+
+    pub fn in_rom(&self, addr: u16) -> bool {
+        self.rom
+            .iter()
+            .any(|&(start, end)| (start..=end).contains(&addr))
+    }
+
+    pub fn enabled(&self) -> impl Iterator<Item = Event> + '_ {
+        self.events.iter().copied()
+    }
+}
+
+pub fn parse_event(name: &str) -> Result<Event, String> {
+    EVENTS
+        .iter()
+        .find(|(event_name, _, _)| event_name.eq_ignore_ascii_case(name))
+        .map(|&(_, event, _)| event)
+        .ok_or(format!(
+            "unknown event '{}', events are {}",
+            name,
+            EVENTS.map(|(name, _, _)| name).join(", ")
+        ))
+}
+
+pub fn describe(event: Event) -> &'static str {
+    EVENTS
+        .iter()
+        .find(|&&(_, other, _)| other == event)
+        .map_or("", |&(_, _, text)| text)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debugger::catchpoints::{Catchpoints, Event};
+
+    fn hex(text: &str) -> Result<u16, String> {
+        u16::from_str_radix(text.trim_start_matches('$'), 16).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_catch_events() {
+        let mut catchpoints = Catchpoints::default();
+        assert_eq!(catchpoints.catch("BRK", hex), Ok(Event::Brk));
+        assert!(catchpoints.is_enabled(Event::Brk));
+        assert!(!catchpoints.is_enabled(Event::Irq));
+        assert!(catchpoints.catch("brk $10", hex).is_err());
+        assert!(catchpoints.catch("reset", hex).is_err());
+
+        catchpoints.uncatch("brk").unwrap();
+        assert!(!catchpoints.is_enabled(Event::Brk));
+    }
+
+    #[test]
+    fn test_rom_ranges() {
+        let mut catchpoints = Catchpoints::default();
+        assert!(catchpoints.catch("rom", hex).is_err());
+        assert_eq!(
+            catchpoints.catch("rom=$ff00-$ffff", hex),
+            Ok(Event::RomWrite)
+        );
+        catchpoints.catch("rom $0600-$06ff", hex).unwrap();
+        assert!(catchpoints.in_rom(0xfffc));
+        assert!(catchpoints.in_rom(0x0600));
+        assert!(!catchpoints.in_rom(0x0200));
+        // the ranges are kept, a bare `rom` switches the catchpoint back on
+        catchpoints.uncatch("rom").unwrap();
+        assert!(!catchpoints.is_enabled(Event::RomWrite));
+        assert_eq!(catchpoints.catch("rom", hex), Ok(Event::RomWrite));
+        assert_eq!(catchpoints.rom.len(), 2);
+    }
+}
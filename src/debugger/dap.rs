@@ -0,0 +1,847 @@
+// Debug Adapter Protocol server (--dap) so editors can launch and debug
+// programs in the emulator. Messages are JSON behind a Content-Length header
+// on stdin/stdout, which means CHROUT output goes out as `output` events. The
+// launch request names the program and optionally its ld65 `dbgfile`, with
+// debug info breakpoints and stepping work on source lines.
+use crate::cpu6502::{init_cpu6502, operation, Args, Cpu6502};
+use crate::debug_info::SourceLine;
+use crate::debugger::breakpoints::{BreakSpec, WatchKind, WatchSpec};
+use crate::debugger::expr::Expr;
+use crate::debugger::logpoints::Template;
+use crate::debugger::{format_value, lead_in, parse_value, set_register, Guard, Stop};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+// instructions run between checks for new requests
+const BATCH: usize = 2000;
+// the CPU is the only thread
+const THREAD: i64 = 1;
+// variablesReference of each scope
+const REGISTERS: i64 = 1;
+const FLAGS: i64 = 2;
+const STACK: i64 = 3;
+const ZERO_PAGE: i64 = 4;
+
+enum Mode {
+    Paused,
+    Running,
+    // stop after one instruction
+    Step,
+    StepOver {
+        return_pc: u16,
+        sp: u8,
+    },
+    Finish {
+        sp: u8,
+    },
+    // stop at the start of another source line
+    Line {
+        from: Option<SourceLine>,
+        sp: Option<u8>,
+    },
+}
+
+struct Client {
+    out: Box<dyn Write>,
+    seq: i64,
+}
+
+impl Client {
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let text = message.to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", text.len(), text).unwrap();
+        self.out.flush().unwrap();
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+}
+
+struct Session {
+    client: Client,
+    cpu: Cpu6502,
+    guard: Guard,
+    // setBreakpoints replaces every breakpoint of a source, keyed by path
+    source_breaks: HashMap<String, Vec<usize>>,
+    instruction_breaks: Vec<usize>,
+    data_breaks: Vec<usize>,
+    mode: Mode,
+    stop_on_entry: bool,
+    done: bool,
+}
+
+pub fn run(args: Args) {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = io::stdin().lock();
+        while let Some(message) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                return;
+            }
+        }
+    });
+    let mut client = Client {
+        out: Box::new(io::stdout()),
+        seq: 0,
+    };
+
+    // nothing can be debugged before the launch request says what to run
+    let mut session = loop {
+        let request = match receiver.recv() {
+            Ok(request) => request,
+            Err(_) => return,
+        };
+        match request["command"].as_str().unwrap_or("") {
+            "initialize" => client.respond(&request, Ok(capabilities())),
+            "launch" => match launch(&args, &request["arguments"]) {
+                Ok(cpu) => {
+                    client.respond(&request, Ok(json!({})));
+                    client.event("initialized", json!({}));
+                    break Session::new(client, cpu, &request["arguments"]);
+                }
+                Err(error) => client.respond(&request, Err(error)),
+            },
+            "disconnect" => {
+                client.respond(&request, Ok(json!({})));
+                return;
+            }
+            command => {
+                let error = format!("'{}' needs a launched program", command);
+                client.respond(&request, Err(error));
+            }
+        }
+    };
+    session.serve(&receiver);
+    session.cpu.flush_traces();
+}
+
+// One Content-Length framed message, None once stdin is closed
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    loop {
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            if input.read_line(&mut line).ok()? == 0 {
+                return None;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length:") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+        let mut body = vec![0; length?];
+        input.read_exact(&mut body).ok()?;
+        if let Ok(message) = serde_json::from_slice(&body) {
+            return Some(message);
+        }
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsConditionalBreakpoints": true,
+        "supportsHitConditionalBreakpoints": true,
+        "supportsLogPoints": true,
+        "supportsInstructionBreakpoints": true,
+        "supportsDataBreakpoints": true,
+        "supportsDisassembleRequest": true,
+        "supportsReadMemoryRequest": true,
+        "supportsSetVariable": true,
+        "supportsSteppingGranularity": true,
+        "supportsEvaluateForHovers": true,
+        "supportsTerminateRequest": true,
+    })
+}
+
+// Loads `program`, and its `dbgfile` (default program.dbg when it exists) and
+// `labels` from the launch arguments
+fn launch(args: &Args, launch: &Value) -> Result<Cpu6502, String> {
+    let program = launch["program"]
+        .as_str()
+        .ok_or("launch needs a 'program'")?;
+    let mut args = Args {
+        binary_file: program.to_string(),
+        ..args.clone()
+    };
+    if let Some(dbgfile) = launch["dbgfile"].as_str() {
+        args.dbgfile = Some(dbgfile.to_string());
+    } else if args.dbgfile.is_none() {
+        let dbgfile = format!("{}.dbg", program);
+        args.dbgfile = Path::new(&dbgfile).exists().then_some(dbgfile);
+    }
+    if let Some(labels) = launch["labels"].as_str() {
+        args.labels = Some(labels.to_string());
+    }
+    // the loaders panic on missing files, which would take the session down
+    for path in [
+        Some(&args.binary_file),
+        args.dbgfile.as_ref(),
+        args.labels.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if !Path::new(path).is_file() {
+            return Err(format!("cannot open {}", path));
+        }
+    }
+    let mut cpu = init_cpu6502(args);
+    cpu.memory.capture_console();
+    cpu.load_file_into_memory();
+    cpu.load_debug_info();
+    cpu.reset();
+    Ok(cpu)
+}
+
+impl Session {
+    fn new(client: Client, mut cpu: Cpu6502, launch: &Value) -> Session {
+        Session {
+            client,
+            guard: Guard::from_args(&mut cpu),
+            cpu,
+            source_breaks: HashMap::new(),
+            instruction_breaks: Vec::new(),
+            data_breaks: Vec::new(),
+            mode: Mode::Paused,
+            stop_on_entry: launch["stopOnEntry"].as_bool().unwrap_or(false),
+            done: false,
+        }
+    }
+
+    fn serve(&mut self, receiver: &Receiver<Value>) {
+        while !self.done {
+            let request = if matches!(self.mode, Mode::Paused) {
+                match receiver.recv() {
+                    Ok(request) => request,
+                    Err(_) => return,
+                }
+            } else {
+                match receiver.try_recv() {
+                    Ok(request) => request,
+                    Err(TryRecvError::Empty) => {
+                        self.run_batch();
+                        continue;
+                    }
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            };
+            if request["type"] != "request" {
+                continue;
+            }
+            let result = self.request(&request);
+            self.client.respond(&request, result);
+            // events that answer a request go out after its response
+            match request["command"].as_str() {
+                Some("configurationDone") if self.stop_on_entry => {
+                    self.stopped("entry", None, Vec::new())
+                }
+                Some("pause") if !matches!(self.mode, Mode::Paused) => {
+                    self.stopped("pause", None, Vec::new())
+                }
+                Some("terminate") => self.client.event("terminated", json!({})),
+                _ => {}
+            }
+        }
+    }
+
+    fn request(&mut self, request: &Value) -> Result<Value, String> {
+        let args = &request["arguments"];
+        match request["command"].as_str().unwrap_or("") {
+            "initialize" => Ok(capabilities()),
+            "launch" => Err("a program is already launched".to_string()),
+            "configurationDone" => {
+                if !self.stop_on_entry {
+                    self.resume(Mode::Running);
+                }
+                Ok(json!({}))
+            }
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "dataBreakpointInfo" => self.data_breakpoint_info(args),
+            "setDataBreakpoints" => self.set_data_breakpoints(args),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD, "name": "6502" }] })),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Flags", "variablesReference": FLAGS, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK, "expensive": false },
+                { "name": "Zero Page", "variablesReference": ZERO_PAGE, "expensive": false },
+            ] })),
+            "variables" => {
+                Ok(json!({ "variables": self.variables(args["variablesReference"].as_i64()) }))
+            }
+            "setVariable" => self.set_variable(args),
+            "evaluate" => {
+                let expression = args["expression"].as_str().unwrap_or("");
+                let value = Expr::parse(expression)?.eval(&self.cpu)?;
+                let mut body = json!({ "result": format_value(value), "variablesReference": 0 });
+                if (0..=0xffff).contains(&value) {
+                    body["memoryReference"] = json!(format!("0x{:04x}", value));
+                }
+                Ok(body)
+            }
+            "readMemory" => self.read_memory(args),
+            "disassemble" => self.disassemble(args),
+            "continue" => {
+                self.resume(Mode::Running);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => {
+                let mode = self.step_mode(args, true);
+                self.resume(mode);
+                Ok(json!({}))
+            }
+            "stepIn" => {
+                let mode = self.step_mode(args, false);
+                self.resume(mode);
+                Ok(json!({}))
+            }
+            "stepOut" => {
+                self.resume(Mode::Finish {
+                    sp: self.cpu.stack_pointer,
+                });
+                Ok(json!({}))
+            }
+            "pause" => Ok(json!({})),
+            "terminate" | "disconnect" => {
+                self.done = true;
+                Ok(json!({}))
+            }
+            command => Err(format!("unsupported request '{}'", command)),
+        }
+    }
+
+    fn resume(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.guard.resume(&mut self.cpu);
+    }
+
+    // Instruction steps unless debug info maps PC to a source line and the
+    // client did not ask for instruction granularity
+    fn step_mode(&self, args: &Value, over: bool) -> Mode {
+        let pc = self.cpu.program_counter;
+        let from = self.line_at(pc);
+        if from.is_some() && args["granularity"] != "instruction" {
+            let sp = over.then_some(self.cpu.stack_pointer);
+            return Mode::Line { from, sp };
+        }
+        let is_jsr = operation::lookup_opcode(self.cpu.memory.get_byte(pc as usize))
+            .is_some_and(|op| matches!(op.instruction_type, operation::Instruction::JSR));
+        if over && is_jsr {
+            Mode::StepOver {
+                return_pc: pc.wrapping_add(3),
+                sp: self.cpu.stack_pointer,
+            }
+        } else {
+            Mode::Step
+        }
+    }
+
+    fn line_at(&self, addr: u16) -> Option<SourceLine> {
+        self.cpu.debug_info.as_ref()?.line_at(addr)
+    }
+
+    fn run_batch(&mut self) {
+        for _ in 0..BATCH {
+            if matches!(self.mode, Mode::Paused) {
+                break;
+            }
+            self.execute();
+        }
+        self.flush_console();
+    }
+
+    // Runs the instruction at PC unless a breakpoint, a catchpoint or an
+    // invalid opcode stops us first
+    fn execute(&mut self) {
+        let cpu = &mut self.cpu;
+        let mut logs = Vec::new();
+        let stop = self.guard.step(cpu, &mut |line| logs.push(line));
+        for line in logs {
+            let output = json!({ "category": "console", "output": line + "\n" });
+            self.client.event("output", output);
+        }
+        match stop {
+            Some(Stop::Breakpoint { id, .. }) => return self.stopped("breakpoint", None, vec![id]),
+            Some(Stop::Watchpoint { id, text }) => {
+                return self.stopped("data breakpoint", Some(text), vec![id])
+            }
+            Some(Stop::Error(error)) => return self.stopped("breakpoint", Some(error), Vec::new()),
+            Some(Stop::Exception(text)) => {
+                return self.stopped("exception", Some(text), Vec::new())
+            }
+            None => {}
+        }
+
+        let pc = cpu.program_counter;
+        let done = match self.mode {
+            Mode::Step => true,
+            Mode::StepOver { return_pc, sp } => pc == return_pc && cpu.stack_pointer == sp,
+            // RTS popped the return address of the frame we were in
+            Mode::Finish { sp } => cpu.stack_pointer as u16 >= sp as u16 + 2,
+            // a lower SP is a call made by the line, stepped over when sp is set
+            Mode::Line { from, sp } => {
+                let line = self.line_at(pc);
+                line.is_some() && line != from && sp.is_none_or(|sp| self.cpu.stack_pointer >= sp)
+            }
+            Mode::Paused | Mode::Running => false,
+        };
+        if done {
+            self.stopped("step", None, Vec::new());
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>, ids: Vec<usize>) {
+        self.mode = Mode::Paused;
+        self.flush_console();
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD,
+            "allThreadsStopped": true,
+            "hitBreakpointIds": ids,
+        });
+        if let Some(text) = text {
+            body["description"] = json!(text.clone());
+            body["text"] = json!(text);
+        }
+        self.client.event("stopped", body);
+    }
+
+    fn flush_console(&mut self) {
+        let bytes = self.cpu.memory.take_console();
+        if bytes.is_empty() {
+            return;
+        }
+        let output: String = bytes
+            .iter()
+            .map(|&byte| if byte == b'\r' { '\n' } else { byte as char })
+            .collect();
+        let body = json!({ "category": "stdout", "output": output });
+        self.client.event("output", body);
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"]
+            .as_str()
+            .ok_or("source without a path")?;
+        for id in self.source_breaks.remove(path).unwrap_or_default() {
+            self.guard.breakpoints.delete(id)?;
+        }
+        let mut ids = Vec::new();
+        let mut results = Vec::new();
+        for bp in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = bp["line"].as_u64().unwrap_or(0) as usize;
+            let addr = self
+                .cpu
+                .debug_info
+                .as_ref()
+                .ok_or("source breakpoints need a dbgfile".to_string())
+                .and_then(|info| {
+                    info.addr_of_line(path, line)
+                        .ok_or(format!("no code generated at or after line {}", line))
+                });
+            match addr.and_then(|addr| Ok((addr, self.add_break(addr, bp)?))) {
+                Ok((addr, id)) => {
+                    ids.push(id);
+                    // the breakpoint moves forward to the next line with code
+                    let line = self.line_at(addr).map_or(line, |source| source.line);
+                    results.push(json!({ "id": id, "verified": true, "line": line }));
+                }
+                Err(error) => {
+                    results.push(json!({ "verified": false, "line": line, "message": error }))
+                }
+            }
+        }
+        self.source_breaks.insert(path.to_string(), ids);
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        for id in std::mem::take(&mut self.instruction_breaks) {
+            self.guard.breakpoints.delete(id)?;
+        }
+        let mut results = Vec::new();
+        for bp in args["breakpoints"].as_array().into_iter().flatten() {
+            let reference = bp["instructionReference"].as_str().unwrap_or("");
+            let offset = bp["offset"].as_i64().unwrap_or(0);
+            let added = parse_value(&self.cpu, reference)
+                .map(|addr| addr.wrapping_add(offset as u16))
+                .and_then(|addr| Ok((addr, self.add_break(addr, bp)?)));
+            match added {
+                Ok((addr, id)) => {
+                    self.instruction_breaks.push(id);
+                    let reference = format!("0x{:04x}", addr);
+                    results.push(
+                        json!({ "id": id, "verified": true, "instructionReference": reference }),
+                    );
+                }
+                Err(error) => results.push(json!({ "verified": false, "message": error })),
+            }
+        }
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    // Breakpoint at addr with the condition, hitCondition and logMessage of bp
+    fn add_break(&mut self, addr: u16, bp: &Value) -> Result<usize, String> {
+        let condition = match bp["condition"].as_str() {
+            Some(text) if !text.trim().is_empty() => Some(Expr::parse(text)?),
+            _ => None,
+        };
+        let stop_at_hit = match bp["hitCondition"].as_str() {
+            Some(text) if !text.trim().is_empty() => parse_hit_condition(text)?,
+            _ => 1,
+        };
+        let log = bp["logMessage"].as_str().map(Template::parse).transpose()?;
+        let spec = BreakSpec {
+            location: None,
+            condition,
+            stop_at_hit,
+            log,
+        };
+        Ok(self.guard.breakpoints.add_break(Some(addr), spec))
+    }
+
+    // Any address shown in the Stack or Zero Page scopes, or an expression
+    fn data_breakpoint_info(&self, args: &Value) -> Result<Value, String> {
+        let name = args["name"].as_str().unwrap_or("");
+        let addr = match args["variablesReference"].as_i64() {
+            Some(REGISTERS) | Some(FLAGS) => None,
+            _ => parse_value(&self.cpu, name).ok(),
+        };
+        Ok(match addr {
+            Some(addr) => json!({
+                "dataId": format!("0x{:04x}", addr),
+                "description": format!("{} (${:04x})", name, addr),
+                "accessTypes": ["read", "write", "readWrite"],
+            }),
+            None => json!({ "dataId": null, "description": "registers cannot be watched" }),
+        })
+    }
+
+    fn set_data_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        for id in std::mem::take(&mut self.data_breaks) {
+            self.guard.breakpoints.delete(id)?;
+        }
+        let mut results = Vec::new();
+        for bp in args["breakpoints"].as_array().into_iter().flatten() {
+            let kind = match bp["accessType"].as_str() {
+                Some("read") => WatchKind::Read,
+                Some("readWrite") => WatchKind::Access,
+                _ => WatchKind::Write,
+            };
+            match parse_value(&self.cpu, bp["dataId"].as_str().unwrap_or("")) {
+                Ok(addr) => {
+                    let spec = WatchSpec {
+                        start: addr,
+                        end: addr,
+                        value: None,
+                    };
+                    let id = self.guard.breakpoints.add_watch(kind, spec);
+                    self.data_breaks.push(id);
+                    results.push(json!({ "id": id, "verified": true }));
+                }
+                Err(error) => results.push(json!({ "verified": false, "message": error })),
+            }
+        }
+        // the program may be running, start logging accesses for the new watches now
+        self.guard.log_accesses(&mut self.cpu);
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    fn stack_trace(&self) -> Value {
+        let cpu = &self.cpu;
+        let pc = cpu.program_counter;
+        let info = cpu.debug_info.as_ref();
+        let name = info
+            .and_then(|info| info.scope_at(pc))
+            .map_or_else(|| cpu.symbolic(pc, 4), str::to_string);
+        let mut frame = json!({
+            "id": 0,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format!("0x{:04x}", pc),
+        });
+        if let (Some(info), Some(source)) = (info, self.line_at(pc)) {
+            frame["source"] = json!({
+                "name": info.file_name(source.file),
+                "path": info.source_path(source.file),
+            });
+            frame["line"] = json!(source.line);
+            frame["column"] = json!(1);
+        }
+        json!({ "stackFrames": [frame], "totalFrames": 1 })
+    }
+
+    fn variables(&self, reference: Option<i64>) -> Vec<Value> {
+        let cpu = &self.cpu;
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        match reference {
+            Some(REGISTERS) => {
+                let pc = cpu.program_counter;
+                let mut pc_value = format!("${:04x}", pc);
+                if let Some(name) = cpu.symbols.name_at(pc) {
+                    pc_value = format!("{} {}", pc_value, name);
+                }
+                let mut registers = vec![
+                    variable("A".to_string(), format_value(cpu.accumulator as i64)),
+                    variable("X".to_string(), format_value(cpu.x_index as i64)),
+                    variable("Y".to_string(), format_value(cpu.y_index as i64)),
+                    variable("SP".to_string(), format!("${:02x}", cpu.stack_pointer)),
+                    variable("PC".to_string(), pc_value),
+                    variable(
+                        "P".to_string(),
+                        format!("${:02x}", cpu.status_flags.as_u8()),
+                    ),
+                    variable("cycles".to_string(), cpu.cycles.to_string()),
+                ];
+                registers[3]["memoryReference"] =
+                    json!(format!("0x{:04x}", 0x0100 | cpu.stack_pointer as u16));
+                registers[4]["memoryReference"] = json!(format!("0x{:04x}", pc));
+                registers
+            }
+            Some(FLAGS) => {
+                let flags = cpu.status_flags.as_u8();
+                "NVUBDIZC"
+                    .chars()
+                    .enumerate()
+                    .map(|(bit, name)| {
+                        let set = flags & (0x80 >> bit) != 0;
+                        variable(name.to_string(), (set as u8).to_string())
+                    })
+                    .collect()
+            }
+            Some(STACK) => (cpu.stack_pointer as u16 + 1..=0xff)
+                .map(|offset| {
+                    let addr = 0x0100 + offset;
+                    let byte = cpu.memory.get_byte(addr as usize);
+                    variable(format!("${:04x}", addr), format!("${:02x}", byte))
+                })
+                .collect(),
+            Some(ZERO_PAGE) => (0..0x100)
+                .step_by(0x10)
+                .map(|row| {
+                    let bytes: Vec<String> = (row..row + 0x10)
+                        .map(|addr| format!("{:02x}", cpu.memory.get_byte(addr)))
+                        .collect();
+                    let mut row_variable = variable(format!("${:04x}", row), bytes.join(" "));
+                    row_variable["memoryReference"] = json!(format!("0x{:04x}", row));
+                    row_variable
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<Value, String> {
+        let name = args["name"].as_str().unwrap_or("");
+        let value = args["value"].as_str().unwrap_or("");
+        match args["variablesReference"].as_i64() {
+            Some(REGISTERS) | Some(FLAGS) => {
+                set_register(&mut self.cpu, &format!("{}={}", name, value))?
+            }
+            Some(STACK) => {
+                let addr = parse_value(&self.cpu, name)?;
+                let byte = parse_value(&self.cpu, value)?;
+                let byte =
+                    u8::try_from(byte).map_err(|_| format!("{} does not fit in a byte", byte))?;
+                self.cpu.memory.set_byte(addr as usize, byte);
+            }
+            _ => return Err(format!("{} cannot be changed here", name)),
+        }
+        let reference = args["variablesReference"].as_i64();
+        let updated = self
+            .variables(reference)
+            .into_iter()
+            .find(|variable| variable["name"].as_str() == Some(name))
+            .map_or(json!(value), |variable| variable["value"].clone());
+        Ok(json!({ "value": updated }))
+    }
+
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let reference = args["memoryReference"].as_str().unwrap_or("");
+        let start =
+            parse_value(&self.cpu, reference)? as i64 + args["offset"].as_i64().unwrap_or(0);
+        let count = args["count"].as_i64().unwrap_or(0);
+        let end = (start + count).min(0x10000);
+        let start = start.max(0);
+        let bytes: Vec<u8> = (start..end.max(start))
+            .map(|addr| self.cpu.memory.get_byte(addr as usize))
+            .collect();
+        let mut body = json!({ "address": format!("0x{:04x}", start), "data": base64(&bytes) });
+        if end < start + count {
+            body["unreadableBytes"] = json!(start + count - end);
+        }
+        Ok(body)
+    }
+
+    fn disassemble(&self, args: &Value) -> Result<Value, String> {
+        let cpu = &self.cpu;
+        let reference = args["memoryReference"].as_str().unwrap_or("");
+        let offset = args["offset"].as_i64().unwrap_or(0) as u16;
+        let mut addr = parse_value(cpu, reference)?.wrapping_add(offset);
+        let skip = args["instructionOffset"].as_i64().unwrap_or(0);
+        if skip < 0 {
+            addr = lead_in(cpu, addr, skip.unsigned_abs() as usize);
+        } else {
+            for _ in 0..skip {
+                addr = addr.wrapping_add(cpu.disassemble(addr).len());
+            }
+        }
+        let count = args["instructionCount"].as_u64().unwrap_or(0);
+        let symbols = match args["resolveSymbols"].as_bool() {
+            Some(false) => None,
+            _ => Some(&cpu.symbols),
+        };
+        let mut instructions = Vec::new();
+        for _ in 0..count {
+            let decoded = cpu.disassemble(addr);
+            let mut instruction = json!({
+                "address": format!("0x{:04x}", addr),
+                "instructionBytes": decoded.hex(),
+                "instruction": decoded.text(symbols),
+            });
+            if let Some(name) = cpu.symbols.name_at(addr) {
+                instruction["symbol"] = json!(name);
+            }
+            if let (Some(info), Some(source)) = (cpu.debug_info.as_ref(), self.line_at(addr)) {
+                instruction["location"] = json!({
+                    "name": info.file_name(source.file),
+                    "path": info.source_path(source.file),
+                });
+                instruction["line"] = json!(source.line);
+            }
+            instructions.push(instruction);
+            addr = addr.wrapping_add(decoded.len());
+        }
+        Ok(json!({ "instructions": instructions }))
+    }
+}
+
+// Hit conditions as editors write them, `N` or `>= N`
+fn parse_hit_condition(text: &str) -> Result<u64, String> {
+    let count = text.trim().trim_start_matches(">=").trim();
+    match count.parse::<u64>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!(
+            "hit condition '{}' is not a count, eg 5 or >= 5",
+            text
+        )),
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let word = chunk.iter().enumerate().fold(0u32, |word, (i, &byte)| {
+            word | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(word >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu6502::cpu_with_program;
+    use crate::debugger::dap::{base64, parse_hit_condition, read_message, Client, Mode, Session};
+    use serde_json::json;
+    use std::io;
+
+    #[test]
+    fn test_framing() {
+        let body = r#"{"seq":1,"type":"request","command":"threads"}"#;
+        let text = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let message = read_message(&mut text.as_bytes()).unwrap();
+        assert_eq!(message["command"], "threads");
+        assert!(read_message(&mut "".as_bytes()).is_none());
+
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(&[0xa2, 0x00]), "ogA=");
+        assert_eq!(parse_hit_condition(">= 5"), Ok(5));
+        assert!(parse_hit_condition("% 2").is_err());
+    }
+
+    #[test]
+    fn test_instruction_breakpoint() {
+        // LDX #$00 / INX / INX / JMP $0602
+        let program = [0xa2, 0x00, 0xe8, 0xe8, 0x4c, 0x02, 0x06];
+        let cpu = cpu_with_program(&[], 0x0600, &program);
+        let client = Client {
+            out: Box::new(io::sink()),
+            seq: 0,
+        };
+        let mut session = Session::new(client, cpu, &json!({}));
+        let breakpoints = json!({ "breakpoints": [
+            { "instructionReference": "0x0603", "condition": "X == 3" },
+        ] });
+        let result = session.set_instruction_breakpoints(&breakpoints).unwrap();
+        assert_eq!(result["breakpoints"][0]["verified"], true);
+
+        session.request(&json!({ "command": "continue" })).unwrap();
+        while !matches!(session.mode, Mode::Paused) {
+            session.run_batch();
+        }
+        assert_eq!(session.cpu.program_counter, 0x0603);
+        assert_eq!(session.cpu.x_index, 3);
+
+        let evaluate = json!({ "command": "evaluate", "arguments": { "expression": "X + 1" } });
+        assert_eq!(session.request(&evaluate).unwrap()["result"], "$04 (4)");
+        let disassemble = json!({ "command": "disassemble", "arguments": {
+            "memoryReference": "0x0603", "instructionOffset": -1, "instructionCount": 2,
+        } });
+        let instructions = session.request(&disassemble).unwrap()["instructions"].clone();
+        assert_eq!(instructions[0]["address"], "0x0602");
+        assert_eq!(instructions[1]["instruction"], "INX");
+    }
+
+    #[test]
+    fn test_catchpoint_and_history() {
+        // LDX #$00 / INX / BRK
+        let cpu = cpu_with_program(&["--catch", "brk"], 0x0600, &[0xa2, 0x00, 0xe8, 0x00]);
+        let client = Client {
+            out: Box::new(io::sink()),
+            seq: 0,
+        };
+        let mut session = Session::new(client, cpu, &json!({}));
+        session.request(&json!({ "command": "continue" })).unwrap();
+        while !matches!(session.mode, Mode::Paused) {
+            session.run_batch();
+        }
+        assert_eq!(session.cpu.program_counter, 0x0603);
+        assert_eq!(session.guard.history.len(), 2);
+    }
+}
@@ -0,0 +1,419 @@
+// Expressions for breakpoint conditions, `print` and `display`:
+//
+//   A == $0D && mem[$24] > 3      X >= Y      flags.C      cycles > 100000
+//
+// Registers are A X Y SP PC P, flags are flags.N .. flags.C, mem[ADDR] reads
+// a byte and word[ADDR] a little endian word. Numbers take the ca65 forms
+// ($hex, %bin, 'c') plus 0xhex, and any other name is looked up as a symbol.
+// Operators and precedence follow C, with ca65's unary < and > for the low
+// and high byte.
+use crate::cpu6502::Cpu6502;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum UnOp {
+    Not,
+    Neg,
+    Complement,
+    LowByte,
+    HighByte,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Register {
+    A,
+    X,
+    Y,
+    Sp,
+    Pc,
+    P,
+    Cycles,
+    Flag(char),
+}
+
+#[derive(Debug)]
+enum Node {
+    Number(i64),
+    Register(Register),
+    Symbol(String),
+    Byte(Box<Node>),
+    Word(Box<Node>),
+    Unary(UnOp, Box<Node>),
+    Binary(BinOp, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+// Longest first so `<=` is not read as `<` then `=`
+const OPERATORS: [&str; 24] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<<", ">>", "<", ">", "+", "-", "*", "/", "%", "&", "|",
+    "^", "!", "~", "(", ")", "[", "]",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        let ident_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '@';
+        let len = if c == '$' || c == '%' && rest[1..].starts_with(['0', '1']) {
+            let radix = if c == '$' { 16 } else { 2 };
+            let digits = rest[1..]
+                .find(|c: char| !c.is_digit(radix))
+                .unwrap_or(rest.len() - 1);
+            let value = i64::from_str_radix(&rest[1..1 + digits], radix)
+                .map_err(|_| format!("invalid number '{}'", &rest[..1 + digits]))?;
+            tokens.push(Token::Number(value));
+            1 + digits
+        } else if c == '\'' {
+            let mut chars = rest[1..].chars();
+            match (chars.next(), chars.next()) {
+                (Some(ch), Some('\'')) if ch.is_ascii() => tokens.push(Token::Number(ch as i64)),
+                _ => return Err("character literals look like 'c'".to_string()),
+            }
+            3
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            let value = match word.strip_prefix("0x") {
+                Some(hex) => i64::from_str_radix(hex, 16),
+                None => word.parse::<i64>(),
+            }
+            .map_err(|_| format!("invalid number '{}'", word))?;
+            tokens.push(Token::Number(value));
+            len
+        } else if ident_char(c) {
+            let len = rest.find(|c: char| !ident_char(c)).unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            len
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or(format!("unexpected '{}'", c))?;
+            tokens.push(Token::Op(op));
+            op.len()
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+// Binary operators from loosest to tightest binding
+const PRECEDENCE: [&[(&str, BinOp)]; 10] = [
+    &[("||", BinOp::Or)],
+    &[("&&", BinOp::And)],
+    &[("|", BinOp::BitOr)],
+    &[("^", BinOp::BitXor)],
+    &[("&", BinOp::BitAnd)],
+    &[("==", BinOp::Eq), ("!=", BinOp::Ne)],
+    &[
+        ("<", BinOp::Lt),
+        ("<=", BinOp::Le),
+        (">", BinOp::Gt),
+        (">=", BinOp::Ge),
+    ],
+    &[("<<", BinOp::Shl), (">>", BinOp::Shr)],
+    &[("+", BinOp::Add), ("-", BinOp::Sub)],
+    &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Rem)],
+];
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_op() != Some(op) {
+            return Err(format!("expected '{}'", op));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(&(_, op)) = self
+            .peek_op()
+            .and_then(|token| PRECEDENCE[level].iter().find(|(text, _)| *text == token))
+        {
+            self.pos += 1;
+            let right = self.binary(level + 1)?;
+            left = Node::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        let op = match self.peek_op() {
+            Some("!") => UnOp::Not,
+            Some("-") => UnOp::Neg,
+            Some("~") => UnOp::Complement,
+            Some("<") => UnOp::LowByte,
+            Some(">") => UnOp::HighByte,
+            _ => return self.primary(),
+        };
+        self.pos += 1;
+        Ok(Node::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        let token = self.tokens.get(self.pos).ok_or("expression ends early")?;
+        self.pos += 1;
+        match token {
+            Token::Number(value) => Ok(Node::Number(*value)),
+            Token::Op("(") => {
+                let inner = self.binary(0)?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Token::Op(op) => Err(format!("unexpected '{}'", op)),
+            Token::Ident(name) => {
+                let name = name.clone();
+                match name.to_ascii_lowercase().as_str() {
+                    "mem" | "word" => {
+                        self.expect("[")?;
+                        let addr = Box::new(self.binary(0)?);
+                        self.expect("]")?;
+                        Ok(if name.eq_ignore_ascii_case("mem") {
+                            Node::Byte(addr)
+                        } else {
+                            Node::Word(addr)
+                        })
+                    }
+                    lower => Ok(match register(lower) {
+                        Some(register) => Node::Register(register),
+                        None => Node::Symbol(name),
+                    }),
+                }
+            }
+        }
+    }
+}
+
+fn register(name: &str) -> Option<Register> {
+    Some(match name {
+        "a" => Register::A,
+        "x" => Register::X,
+        "y" => Register::Y,
+        "sp" => Register::Sp,
+        "pc" => Register::Pc,
+        "p" => Register::P,
+        "cycles" => Register::Cycles,
+        _ => {
+            let flag = name.strip_prefix("flags.")?;
+            match flag {
+                "n" | "v" | "d" | "i" | "z" | "c" => Register::Flag(flag.chars().next()?),
+                _ => return None,
+            }
+        }
+    })
+}
+
+pub struct Expr {
+    text: String,
+    root: Node,
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+        };
+        if parser.tokens.is_empty() {
+            return Err("empty expression".to_string());
+        }
+        let root = parser.binary(0)?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {:?} after the expression", token));
+        }
+        Ok(Expr {
+            text: text.trim().to_string(),
+            root,
+        })
+    }
+
+    pub fn eval(&self, cpu: &Cpu6502) -> Result<i64, String> {
+        eval(&self.root, cpu)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn address(value: i64) -> Result<usize, String> {
+    u16::try_from(value)
+        .map(|addr| addr as usize)
+        .map_err(|_| format!("address {} is outside memory", value))
+}
+
+fn eval(node: &Node, cpu: &Cpu6502) -> Result<i64, String> {
+    Ok(match node {
+        Node::Number(value) => *value,
+        Node::Register(register) => match register {
+            Register::A => cpu.accumulator as i64,
+            Register::X => cpu.x_index as i64,
+            Register::Y => cpu.y_index as i64,
+            Register::Sp => cpu.stack_pointer as i64,
+            Register::Pc => cpu.program_counter as i64,
+            Register::P => cpu.status_flags.as_u8() as i64,
+            Register::Cycles => cpu.cycles as i64,
+            Register::Flag(flag) => {
+                let flags = &cpu.status_flags;
+                let set = match flag {
+                    'n' => flags.n,
+                    'v' => flags.v,
+                    'd' => flags.d,
+                    'i' => flags.i,
+                    'z' => flags.z,
+                    _ => flags.c,
+                };
+                set as i64
+            }
+        },
+        Node::Symbol(name) => cpu
+            .symbols
+            .addr_of(name)
+            .ok_or(format!("unknown symbol '{}'", name))? as i64,
+        Node::Byte(addr) => cpu.memory.get_byte(address(eval(addr, cpu)?)?) as i64,
+        Node::Word(addr) => {
+            let addr = address(eval(addr, cpu)?)?;
+            let lo = cpu.memory.get_byte(addr) as i64;
+            let hi = cpu.memory.get_byte((addr + 1) & 0xFFFF) as i64;
+            hi << 8 | lo
+        }
+        Node::Unary(op, inner) => {
+            let value = eval(inner, cpu)?;
+            match op {
+                UnOp::Not => (value == 0) as i64,
+                UnOp::Neg => value.wrapping_neg(),
+                UnOp::Complement => !value,
+                UnOp::LowByte => value & 0xFF,
+                UnOp::HighByte => (value >> 8) & 0xFF,
+            }
+        }
+        // short circuit so `X != 0 && mem[$0200 / X]` style guards work
+        Node::Binary(BinOp::And, left, right) => {
+            (eval(left, cpu)? != 0 && eval(right, cpu)? != 0) as i64
+        }
+        Node::Binary(BinOp::Or, left, right) => {
+            (eval(left, cpu)? != 0 || eval(right, cpu)? != 0) as i64
+        }
+        Node::Binary(op, left, right) => {
+            let (l, r) = (eval(left, cpu)?, eval(right, cpu)?);
+            match op {
+                BinOp::Eq => (l == r) as i64,
+                BinOp::Ne => (l != r) as i64,
+                BinOp::Lt => (l < r) as i64,
+                BinOp::Le => (l <= r) as i64,
+                BinOp::Gt => (l > r) as i64,
+                BinOp::Ge => (l >= r) as i64,
+                BinOp::BitOr => l | r,
+                BinOp::BitXor => l ^ r,
+                BinOp::BitAnd => l & r,
+                BinOp::Shl => l.wrapping_shl(r as u32),
+                BinOp::Shr => l.wrapping_shr(r as u32),
+                BinOp::Add => l.wrapping_add(r),
+                BinOp::Sub => l.wrapping_sub(r),
+                BinOp::Mul => l.wrapping_mul(r),
+                BinOp::Div | BinOp::Rem if r == 0 => return Err("division by zero".to_string()),
+                BinOp::Div => l.wrapping_div(r),
+                BinOp::Rem => l.wrapping_rem(r),
+                BinOp::And | BinOp::Or => unreachable!(),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu6502::cpu_with_program;
+    use crate::debugger::expr::Expr;
+
+    fn eval(text: &str) -> Result<i64, String> {
+        let mut cpu = cpu_with_program(&[], 0x0600, &[]);
+        cpu.accumulator = 0x0D;
+        cpu.x_index = 4;
+        cpu.y_index = 4;
+        cpu.status_flags.c = true;
+        cpu.cycles = 123_456;
+        cpu.memory.set_byte(0x24, 5);
+        cpu.memory.set_byte(0x25, 0x12);
+        cpu.symbols.insert("ptr".to_string(), 0x24);
+        Expr::parse(text)?.eval(&cpu)
+    }
+
+    #[test]
+    fn test_conditions() {
+        assert_eq!(eval("A == $0D && mem[$24] > 3"), Ok(1));
+        assert_eq!(eval("X >= Y"), Ok(1));
+        assert_eq!(eval("flags.C"), Ok(1));
+        assert_eq!(eval("flags.Z || !flags.C"), Ok(0));
+        assert_eq!(eval("cycles > 100000"), Ok(1));
+        assert_eq!(eval("word[ptr]"), Ok(0x1205));
+        assert_eq!(eval("a == 'x' - $6b"), Ok(1));
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval(">$1234 + <$1234"), Ok(0x12 + 0x34));
+        assert_eq!(eval("%1010 & 0x0c | 1"), Ok(9));
+        assert_eq!(eval("-1 < 0"), Ok(1));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Expr::parse("A ==").is_err());
+        assert!(Expr::parse("mem[$24").is_err());
+        assert!(Expr::parse("1 2").is_err());
+        assert!(eval("nothere + 1").is_err());
+        assert!(eval("A / 0").is_err());
+        assert!(eval("mem[$10000]").is_err());
+    }
+}
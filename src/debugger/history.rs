@@ -0,0 +1,112 @@
+// Undo log for reverse execution. Each executed instruction (or interrupt
+// entry) stores the registers from before it ran plus the memory accesses it
+// made, the writes carry the old byte so they can be put back. The log is a
+// ring, once full the oldest instructions are forgotten.
+use crate::cpu6502::memory::{Access, AccessKind};
+use crate::cpu6502::{Cpu6502, Registers};
+use std::collections::VecDeque;
+
+struct Entry {
+    registers: Registers,
+    // how many of the front of `accesses` belong to this entry
+    access_count: usize,
+}
+
+pub struct History {
+    capacity: usize,
+    entries: VecDeque<Entry>,
+    // accesses of every entry back to back, kept flat to avoid an allocation
+    // per instruction
+    accesses: VecDeque<Access>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            capacity,
+            entries: VecDeque::new(),
+            accesses: VecDeque::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn record(&mut self, registers: Registers, accesses: &[Access]) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            let oldest = self.entries.pop_front().unwrap();
+            self.accesses.drain(..oldest.access_count);
+        }
+        self.entries.push_back(Entry {
+            registers,
+            access_count: accesses.len(),
+        });
+        self.accesses.extend(accesses);
+    }
+
+    // Undoes the newest entry, returning the accesses it had made
+    pub fn step_back(&mut self, cpu: &mut Cpu6502) -> Option<Vec<Access>> {
+        let entry = self.entries.pop_back()?;
+        let start = self.accesses.len() - entry.access_count;
+        let accesses: Vec<Access> = self.accesses.drain(start..).collect();
+        // newest write first, so a byte written twice ends up at its oldest value
+        let memory = cpu.memory.raw_mut();
+        for access in accesses.iter().rev() {
+            if let AccessKind::Write { old, .. } = access.kind {
+                memory[access.addr as usize] = old;
+            }
+        }
+        cpu.restore_registers(&entry.registers);
+        Some(accesses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu6502::cpu_with_program;
+    use crate::cpu6502::memory::{Access, AccessKind};
+    use crate::debugger::history::History;
+
+    #[test]
+    fn test_step_back_restores_state() {
+        let mut cpu = cpu_with_program(&[], 0x0000, &[]);
+        let mut history = History::new(2);
+        // three fake instructions, each bumping A and writing $10
+        for i in 0..3u8 {
+            let before = cpu.registers();
+            cpu.accumulator = i + 1;
+            cpu.program_counter += 2;
+            let write = Access {
+                addr: 0x10,
+                kind: AccessKind::Write {
+                    old: cpu.memory.get_byte(0x10),
+                    new: i + 1,
+                },
+            };
+            cpu.memory.set_byte(0x10, i + 1);
+            history.record(before, &[write]);
+        }
+        assert_eq!(history.len(), 2);
+
+        let undone = history.step_back(&mut cpu).unwrap();
+        assert_eq!(undone.len(), 1);
+        assert_eq!(cpu.accumulator, 2);
+        assert_eq!(cpu.memory.get_byte(0x10), 2);
+
+        history.step_back(&mut cpu).unwrap();
+        assert_eq!(cpu.accumulator, 1);
+        assert_eq!(cpu.program_counter, 2);
+        assert_eq!(cpu.memory.get_byte(0x10), 1);
+
+        // the first instruction fell out of the ring
+        assert!(history.step_back(&mut cpu).is_none());
+    }
+}
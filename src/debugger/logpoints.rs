@@ -0,0 +1,162 @@
+// Logpoints print a message and keep running. Messages are text with
+// expressions in braces, `{X}` prints hex, `{X:d}` decimal and `{A:c}` the
+// character; `{{` and `}}` are literal braces.
+use crate::cpu6502::Cpu6502;
+use crate::debugger::expr::Expr;
+use std::fmt::Write;
+
+enum Format {
+    Hex,
+    Decimal,
+    Char,
+}
+
+enum Piece {
+    Text(String),
+    Value(Expr, Format),
+}
+
+pub struct Template {
+    text: String,
+    pieces: Vec<Piece>,
+}
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Template, String> {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut inner = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        inner.push(c);
+                    }
+                    if !closed {
+                        return Err("unterminated '{'".to_string());
+                    }
+                    let (expr, format) = match inner.rsplit_once(':') {
+                        Some((expr, "x")) => (expr, Format::Hex),
+                        Some((expr, "d")) => (expr, Format::Decimal),
+                        Some((expr, "c")) => (expr, Format::Char),
+                        Some((_, format)) => return Err(format!("unknown format ':{}'", format)),
+                        None => (inner.as_str(), Format::Hex),
+                    };
+                    if !literal.is_empty() {
+                        pieces.push(Piece::Text(std::mem::take(&mut literal)));
+                    }
+                    pieces.push(Piece::Value(Expr::parse(expr)?, format));
+                }
+                '}' => return Err("unmatched '}', write '}}' for a brace".to_string()),
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Text(literal));
+        }
+        Ok(Template {
+            text: text.to_string(),
+            pieces,
+        })
+    }
+
+    pub fn render(&self, cpu: &Cpu6502) -> String {
+        let mut out = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => out.push_str(text),
+                Piece::Value(expr, format) => {
+                    let value = match expr.eval(cpu) {
+                        Ok(value) => value,
+                        Err(error) => {
+                            write!(out, "<{}>", error).unwrap();
+                            continue;
+                        }
+                    };
+                    match format {
+                        Format::Hex if (0..=0xFF).contains(&value) => write!(out, "${:02x}", value),
+                        Format::Hex => write!(out, "${:04x}", value),
+                        Format::Decimal => write!(out, "{}", value),
+                        Format::Char => match u8::try_from(value) {
+                            Ok(byte) if (0x20..0x7F).contains(&byte) => {
+                                write!(out, "{}", byte as char)
+                            }
+                            _ => write!(out, "\\x{:02x}", value),
+                        },
+                    }
+                    .unwrap();
+                }
+            }
+        }
+        out
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+// Splits `LOC [if COND] "message"` into the breakpoint part and the message
+pub fn split_log_spec(spec: &str) -> Result<(&str, Template), String> {
+    let spec = spec.trim();
+    let usage = "usage: log LOC [if COND] \"message {EXPR}\"";
+    let message = spec.strip_suffix('"').ok_or(usage)?;
+    let (location, message) = message.split_once('"').ok_or(usage)?;
+    if location.trim().is_empty() {
+        return Err(usage.to_string());
+    }
+    Ok((location.trim(), Template::parse(message)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu6502::cpu_with_program;
+    use crate::debugger::logpoints::{split_log_spec, Template};
+
+    #[test]
+    fn test_render() {
+        let mut cpu = cpu_with_program(&[], 0x0621, &[]);
+        cpu.accumulator = b'H';
+        cpu.x_index = 12;
+        cpu.memory.set_byte(0x0210, 3);
+        let render = |text: &str| Template::parse(text).unwrap().render(&cpu);
+
+        assert_eq!(
+            render("print called, index={mem[$0210]}"),
+            "print called, index=$03"
+        );
+        assert_eq!(render("{PC} {X:d} {A:c} {A:x}"), "$0621 12 H $48");
+        assert_eq!(
+            render("{{literal}} {nothere}"),
+            "{literal} <unknown symbol 'nothere'>"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Template::parse("{A:q}").is_err());
+        assert!(Template::parse("{A ==}").is_err());
+        assert!(Template::parse("oops }").is_err());
+        assert!(Template::parse("oops {A").is_err());
+
+        let (location, message) = split_log_spec("$0621 if X > 2 \"x={X}\"").unwrap();
+        assert_eq!(location, "$0621 if X > 2");
+        assert_eq!(message.text(), "x={X}");
+        assert!(split_log_spec("$0621 no message").is_err());
+        assert!(split_log_spec("\"no location\"").is_err());
+    }
+}
@@ -0,0 +1,746 @@
+// VICE binary monitor protocol (--monitor) so front-ends that already talk
+// to VICE can attach over TCP. Every request is
+//
+//   STX(02) version(02) body length(u32) request id(u32) command(u8) body
+//
+// and every response
+//
+//   STX(02) version(02) body length(u32) type(u8) error(u8) request id(u32) body
+//
+// all little endian. Events the client did not ask for, like a checkpoint
+// being hit, use request id ffffffff. There is one flat memory space, so all
+// banks read and write the same RAM.
+use crate::cpu6502::memory::{AccessKind, MemMap};
+use crate::cpu6502::{operation, Cpu6502};
+use crate::debugger::expr::Expr;
+use crate::debugger::Guard;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+const STX: u8 = 0x02;
+const API_VERSION: u8 = 0x02;
+const EVENT: u32 = 0xffff_ffff;
+// instructions between polls of the socket
+const BATCH: usize = 2000;
+
+const MEMORY_GET: u8 = 0x01;
+const MEMORY_SET: u8 = 0x02;
+const CHECKPOINT_INFO: u8 = 0x11;
+const CHECKPOINT_SET: u8 = 0x12;
+const CHECKPOINT_DELETE: u8 = 0x13;
+const CHECKPOINT_LIST: u8 = 0x14;
+const CHECKPOINT_TOGGLE: u8 = 0x15;
+const CONDITION_SET: u8 = 0x22;
+const REGISTERS_GET: u8 = 0x31;
+const REGISTERS_SET: u8 = 0x32;
+const ADVANCE: u8 = 0x71;
+const KEYBOARD_FEED: u8 = 0x72;
+const EXECUTE_UNTIL_RETURN: u8 = 0x73;
+const PING: u8 = 0x81;
+const BANKS_AVAILABLE: u8 = 0x82;
+const REGISTERS_AVAILABLE: u8 = 0x83;
+const VICE_INFO: u8 = 0x85;
+const JAM: u8 = 0x61;
+const STOPPED: u8 = 0x62;
+const RESUMED: u8 = 0x63;
+const EXIT: u8 = 0xaa;
+const QUIT: u8 = 0xbb;
+const RESET: u8 = 0xcc;
+
+const OK: u8 = 0x00;
+const NO_SUCH_OBJECT: u8 = 0x01;
+const INVALID_MEMSPACE: u8 = 0x02;
+const BAD_LENGTH: u8 = 0x80;
+const INVALID_PARAMETER: u8 = 0x81;
+const BAD_VERSION: u8 = 0x82;
+const INVALID_COMMAND: u8 = 0x83;
+
+// checkpoint operations, a bit mask
+const LOAD: u8 = 0x01;
+const STORE: u8 = 0x02;
+const EXEC: u8 = 0x04;
+
+// VICE register ids with their width in bits
+const REGISTERS: [(u8, &str, u8); 6] = [
+    (0x00, "A", 8),
+    (0x01, "X", 8),
+    (0x02, "Y", 8),
+    (0x03, "PC", 16),
+    (0x04, "SP", 8),
+    (0x05, "FL", 8),
+];
+const BANKS: [(u16, &str); 3] = [(0, "default"), (1, "cpu"), (2, "ram")];
+
+struct Checkpoint {
+    id: u32,
+    start: u16,
+    end: u16,
+    stop: bool,
+    enabled: bool,
+    operation: u8,
+    temporary: bool,
+    hits: u32,
+    // hits skipped before the checkpoint counts again
+    ignore: u32,
+    condition: Option<Expr>,
+}
+
+impl Checkpoint {
+    fn covers(&self, operation: u8, addr: u16) -> bool {
+        self.enabled && self.operation & operation != 0 && (self.start..=self.end).contains(&addr)
+    }
+
+    fn info(&self, hit: bool) -> Vec<u8> {
+        let mut body = self.id.to_le_bytes().to_vec();
+        body.push(hit as u8);
+        body.extend(self.start.to_le_bytes());
+        body.extend(self.end.to_le_bytes());
+        body.extend([
+            self.stop as u8,
+            self.enabled as u8,
+            self.operation,
+            self.temporary as u8,
+        ]);
+        body.extend(self.hits.to_le_bytes());
+        body.extend(self.ignore.to_le_bytes());
+        body.extend([self.condition.is_some() as u8, 0]);
+        body
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Stopped,
+    Running,
+    // instructions left to step, JSRs count as one when over is set
+    Advance {
+        remaining: u16,
+        over: bool,
+    },
+    // inside a JSR stepped over by Advance
+    Over {
+        return_pc: u16,
+        sp: u8,
+        remaining: u16,
+    },
+    Finish {
+        sp: u8,
+    },
+}
+
+struct Monitor {
+    // --break, --watch and --catch from the command line, and the undo log
+    guard: Guard,
+    checkpoints: Vec<Checkpoint>,
+    last_id: u32,
+    mode: Mode,
+    // the checkpoint we stopped at must not fire again on resume
+    skip_checkpoint: bool,
+    // keyboard feed text, handed to CHRIN a byte per read
+    keys: VecDeque<u8>,
+    // responses and events waiting to be written to the client
+    outbox: Vec<u8>,
+    quit: bool,
+}
+
+pub fn run(cpu: &mut Cpu6502, addr: &str) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(error) => panic!("Problem with monitor address {}: {}", addr, error),
+    };
+    listener.set_nonblocking(true).unwrap();
+    println!("Binary monitor listening on {}", addr);
+
+    let mut monitor = Monitor {
+        guard: Guard::from_args(cpu),
+        checkpoints: Vec::new(),
+        last_id: 0,
+        mode: if cpu.cmdline_args.step_debug {
+            Mode::Stopped
+        } else {
+            Mode::Running
+        },
+        skip_checkpoint: false,
+        keys: VecDeque::new(),
+        outbox: Vec::new(),
+        quit: false,
+    };
+    let mut client: Option<TcpStream> = None;
+    let mut input = Vec::new();
+    while !monitor.quit && !cpu.interrupted.load(Ordering::Relaxed) {
+        if client.is_none() {
+            if let Ok((stream, _)) = listener.accept() {
+                stream.set_nonblocking(true).unwrap();
+                stream.set_nodelay(true).unwrap();
+                client = Some(stream);
+                input.clear();
+                monitor.outbox.clear();
+            }
+        }
+        let mut idle = true;
+        if let Some(stream) = &mut client {
+            let mut buffer = [0; 4096];
+            match stream.read(&mut buffer) {
+                Ok(0) => client = None,
+                Ok(count) => {
+                    input.extend_from_slice(&buffer[..count]);
+                    idle = false;
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => client = None,
+            }
+        }
+        while let Some((request_id, command, body)) = next_request(&mut input) {
+            monitor.command(cpu, request_id, command, &body);
+        }
+
+        if !matches!(monitor.mode, Mode::Stopped) {
+            monitor.run_batch(cpu);
+            idle = false;
+        }
+        if let Some(stream) = &mut client {
+            if !monitor.outbox.is_empty() {
+                stream.set_nonblocking(false).unwrap();
+                if stream.write_all(&monitor.outbox).is_err() {
+                    client = None;
+                }
+                if let Some(stream) = &client {
+                    stream.set_nonblocking(true).unwrap();
+                }
+            }
+        }
+        monitor.outbox.clear();
+        if idle {
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
+
+// Takes the first complete request off the front of input. A request with
+// the wrong STX or version is answered with an error and skipped.
+fn next_request(input: &mut Vec<u8>) -> Option<(u32, u8, Vec<u8>)> {
+    if input.len() < 11 {
+        return None;
+    }
+    let length = u32::from_le_bytes(input[2..6].try_into().unwrap()) as usize;
+    if input.len() < 11 + length {
+        return None;
+    }
+    let request: Vec<u8> = input.drain(..11 + length).collect();
+    let request_id = u32::from_le_bytes(request[6..10].try_into().unwrap());
+    let command = if request[0] == STX && request[1] == API_VERSION {
+        request[10]
+    } else {
+        // there is no command 0, it is answered with BAD_VERSION
+        0
+    };
+    Some((request_id, command, request[11..].to_vec()))
+}
+
+fn response(kind: u8, error: u8, request_id: u32, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![STX, API_VERSION];
+    out.extend((body.len() as u32).to_le_bytes());
+    out.extend([kind, error]);
+    out.extend(request_id.to_le_bytes());
+    out.extend(body);
+    out
+}
+
+fn word(body: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([body[at], body[at + 1]])
+}
+
+fn long(body: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(body[at..at + 4].try_into().unwrap())
+}
+
+impl Monitor {
+    fn reply(&mut self, kind: u8, error: u8, request_id: u32, body: &[u8]) {
+        self.outbox.extend(response(kind, error, request_id, body));
+    }
+
+    fn command(&mut self, cpu: &mut Cpu6502, request_id: u32, command: u8, body: &[u8]) {
+        // minimum body length of each command
+        let needed = match command {
+            MEMORY_GET => 8,
+            MEMORY_SET => 8,
+            CHECKPOINT_SET => 8,
+            CHECKPOINT_INFO | CHECKPOINT_DELETE => 4,
+            CHECKPOINT_TOGGLE => 5,
+            CONDITION_SET => 5,
+            REGISTERS_GET | REGISTERS_AVAILABLE => 1,
+            REGISTERS_SET => 3,
+            ADVANCE => 3,
+            KEYBOARD_FEED => 1,
+            RESET => 1,
+            _ => 0,
+        };
+        if body.len() < needed {
+            return self.reply(command, BAD_LENGTH, request_id, &[]);
+        }
+        match command {
+            0 => self.reply(0, BAD_VERSION, request_id, &[]),
+            MEMORY_GET => {
+                let (start, end) = (word(body, 1), word(body, 3));
+                if body[5] != 0 {
+                    return self.reply(command, INVALID_MEMSPACE, request_id, &[]);
+                }
+                if end < start {
+                    return self.reply(command, INVALID_PARAMETER, request_id, &[]);
+                }
+                let length = (end - start) as usize + 1;
+                let mut out = (length as u16).to_le_bytes().to_vec();
+                out.extend((start..=end).map(|addr| cpu.memory.get_byte(addr as usize)));
+                self.reply(command, OK, request_id, &out);
+            }
+            MEMORY_SET => {
+                let (start, end) = (word(body, 1), word(body, 3));
+                if body[5] != 0 {
+                    return self.reply(command, INVALID_MEMSPACE, request_id, &[]);
+                }
+                let data = &body[8..];
+                if end < start || data.len() != (end - start) as usize + 1 {
+                    return self.reply(command, INVALID_PARAMETER, request_id, &[]);
+                }
+                for (addr, &byte) in (start..=end).zip(data) {
+                    cpu.memory.set_byte(addr as usize, byte);
+                }
+                self.reply(command, OK, request_id, &[]);
+            }
+            CHECKPOINT_SET => {
+                self.last_id += 1;
+                let checkpoint = Checkpoint {
+                    id: self.last_id,
+                    start: word(body, 0),
+                    end: word(body, 2).max(word(body, 0)),
+                    stop: body[4] != 0,
+                    enabled: body[5] != 0,
+                    operation: body[6],
+                    temporary: body[7] != 0,
+                    hits: 0,
+                    ignore: 0,
+                    condition: None,
+                };
+                if checkpoint.operation & (LOAD | STORE) != 0 {
+                    cpu.memory.enable_access_log();
+                }
+                self.reply(CHECKPOINT_INFO, OK, request_id, &checkpoint.info(false));
+                self.checkpoints.push(checkpoint);
+            }
+            CHECKPOINT_INFO => match self.find(long(body, 0)) {
+                Some(index) => {
+                    let info = self.checkpoints[index].info(false);
+                    self.reply(CHECKPOINT_INFO, OK, request_id, &info);
+                }
+                None => self.reply(CHECKPOINT_INFO, NO_SUCH_OBJECT, request_id, &[]),
+            },
+            CHECKPOINT_DELETE => match self.find(long(body, 0)) {
+                Some(index) => {
+                    self.checkpoints.remove(index);
+                    self.reply(command, OK, request_id, &[]);
+                }
+                None => self.reply(command, NO_SUCH_OBJECT, request_id, &[]),
+            },
+            CHECKPOINT_LIST => {
+                let infos: Vec<Vec<u8>> =
+                    self.checkpoints.iter().map(|cp| cp.info(false)).collect();
+                for info in infos {
+                    self.reply(CHECKPOINT_INFO, OK, request_id, &info);
+                }
+                let count = self.checkpoints.len() as u32;
+                self.reply(command, OK, request_id, &count.to_le_bytes());
+            }
+            CHECKPOINT_TOGGLE => match self.find(long(body, 0)) {
+                Some(index) => {
+                    self.checkpoints[index].enabled = body[4] != 0;
+                    self.reply(command, OK, request_id, &[]);
+                }
+                None => self.reply(command, NO_SUCH_OBJECT, request_id, &[]),
+            },
+            CONDITION_SET => {
+                let length = body[4] as usize;
+                let text = body.get(5..5 + length).map(String::from_utf8_lossy);
+                let condition = match text.map(|text| Expr::parse(&text)) {
+                    Some(Ok(condition)) => condition,
+                    _ => return self.reply(command, INVALID_PARAMETER, request_id, &[]),
+                };
+                match self.find(long(body, 0)) {
+                    Some(index) => {
+                        self.checkpoints[index].condition = Some(condition);
+                        self.reply(command, OK, request_id, &[]);
+                    }
+                    None => self.reply(command, NO_SUCH_OBJECT, request_id, &[]),
+                }
+            }
+            REGISTERS_GET => {
+                if body[0] != 0 {
+                    return self.reply(REGISTERS_GET, INVALID_MEMSPACE, request_id, &[]);
+                }
+                self.reply(REGISTERS_GET, OK, request_id, &registers(cpu));
+            }
+            REGISTERS_SET => {
+                if body[0] != 0 {
+                    return self.reply(REGISTERS_GET, INVALID_MEMSPACE, request_id, &[]);
+                }
+                let count = word(body, 1) as usize;
+                let mut at = 3;
+                for _ in 0..count {
+                    // each item is its size, then register id and a 16 bit value
+                    let item = match body.get(at..at + 4) {
+                        Some(item) if item[0] >= 3 => item,
+                        _ => return self.reply(REGISTERS_GET, BAD_LENGTH, request_id, &[]),
+                    };
+                    if !set_register(cpu, item[1], word(item, 2)) {
+                        return self.reply(REGISTERS_GET, INVALID_PARAMETER, request_id, &[]);
+                    }
+                    at += 1 + item[0] as usize;
+                }
+                self.reply(REGISTERS_GET, OK, request_id, &registers(cpu));
+            }
+            ADVANCE => {
+                let remaining = word(body, 1);
+                self.reply(command, OK, request_id, &[]);
+                if remaining > 0 {
+                    self.resume(
+                        cpu,
+                        Mode::Advance {
+                            remaining,
+                            over: body[0] != 0,
+                        },
+                    );
+                }
+            }
+            KEYBOARD_FEED => {
+                let length = body[0] as usize;
+                self.keys.extend(body.iter().skip(1).take(length));
+                cpu.memory.enable_access_log();
+                // the first key is ready straight away, the rest as CHRIN is read
+                if let Some(key) = self.keys.pop_front() {
+                    cpu.memory.set_byte(MemMap::CHRIN as usize, key);
+                }
+                self.reply(command, OK, request_id, &[]);
+            }
+            EXECUTE_UNTIL_RETURN => {
+                self.reply(command, OK, request_id, &[]);
+                let sp = cpu.stack_pointer;
+                self.resume(cpu, Mode::Finish { sp });
+            }
+            PING => self.reply(command, OK, request_id, &[]),
+            BANKS_AVAILABLE => {
+                let mut out = (BANKS.len() as u16).to_le_bytes().to_vec();
+                for (id, name) in BANKS {
+                    out.push(3 + name.len() as u8);
+                    out.extend(id.to_le_bytes());
+                    out.push(name.len() as u8);
+                    out.extend(name.bytes());
+                }
+                self.reply(command, OK, request_id, &out);
+            }
+            REGISTERS_AVAILABLE => {
+                let mut out = (REGISTERS.len() as u16).to_le_bytes().to_vec();
+                for (id, name, bits) in REGISTERS {
+                    out.extend([3 + name.len() as u8, id, bits, name.len() as u8]);
+                    out.extend(name.bytes());
+                }
+                self.reply(command, OK, request_id, &out);
+            }
+            VICE_INFO => {
+                let mut out = vec![4];
+                out.extend([3, 8, 0, 0]);
+                out.push(4);
+                out.extend([0; 4]);
+                self.reply(command, OK, request_id, &out);
+            }
+            EXIT => {
+                self.reply(command, OK, request_id, &[]);
+                self.resume(cpu, Mode::Running);
+            }
+            QUIT => {
+                self.reply(command, OK, request_id, &[]);
+                self.quit = true;
+            }
+            RESET => {
+                cpu.reset();
+                self.reply(command, OK, request_id, &[]);
+            }
+            _ => self.reply(command, INVALID_COMMAND, request_id, &[]),
+        }
+    }
+
+    fn find(&self, id: u32) -> Option<usize> {
+        self.checkpoints.iter().position(|cp| cp.id == id)
+    }
+
+    fn resume(&mut self, cpu: &mut Cpu6502, mode: Mode) {
+        self.mode = mode;
+        self.skip_checkpoint = true;
+        self.guard.resume(cpu);
+        let pc = cpu.program_counter.to_le_bytes();
+        self.reply(RESUMED, OK, EVENT, &pc);
+    }
+
+    fn stop(&mut self, cpu: &Cpu6502) {
+        self.mode = Mode::Stopped;
+        self.reply(REGISTERS_GET, OK, EVENT, &registers(cpu));
+        let pc = cpu.program_counter.to_le_bytes();
+        self.reply(STOPPED, OK, EVENT, &pc);
+    }
+
+    fn run_batch(&mut self, cpu: &mut Cpu6502) {
+        for _ in 0..BATCH {
+            if matches!(self.mode, Mode::Stopped) {
+                break;
+            }
+            self.execute(cpu);
+        }
+    }
+
+    // Counts a hit on every checkpoint covering addr, true when one stops
+    fn hit(&mut self, cpu: &Cpu6502, operation: u8, addr: u16) -> bool {
+        let mut stop = false;
+        let mut index = 0;
+        while index < self.checkpoints.len() {
+            let cp = &mut self.checkpoints[index];
+            index += 1;
+            if !cp.covers(operation, addr) {
+                continue;
+            }
+            if let Some(condition) = &cp.condition {
+                if !condition.eval(cpu).is_ok_and(|value| value != 0) {
+                    continue;
+                }
+            }
+            if cp.ignore > 0 {
+                cp.ignore -= 1;
+                continue;
+            }
+            cp.hits += 1;
+            if !cp.stop {
+                continue;
+            }
+            let info = cp.info(true);
+            if cp.temporary {
+                index -= 1;
+                self.checkpoints.remove(index);
+            }
+            self.reply(CHECKPOINT_INFO, OK, EVENT, &info);
+            stop = true;
+        }
+        stop
+    }
+
+    fn execute(&mut self, cpu: &mut Cpu6502) {
+        let pc = cpu.program_counter;
+        let op = match operation::lookup_opcode(cpu.memory.get_byte(pc as usize)) {
+            Some(op) => op,
+            None => {
+                self.mode = Mode::Stopped;
+                return self.reply(JAM, OK, EVENT, &pc.to_le_bytes());
+            }
+        };
+        // the console the monitor was started from gets the messages
+        if let Some(stop) = self.guard.check(cpu, &mut |line| println!("{}", line)) {
+            println!("{}", stop.text());
+            return self.stop(cpu);
+        }
+        if !std::mem::take(&mut self.skip_checkpoint) && self.hit(cpu, EXEC, pc) {
+            return self.stop(cpu);
+        }
+        if let Mode::Advance {
+            remaining,
+            over: true,
+        } = self.mode
+        {
+            if matches!(op.instruction_type, operation::Instruction::JSR) {
+                self.mode = Mode::Over {
+                    return_pc: pc.wrapping_add(3),
+                    sp: cpu.stack_pointer,
+                    remaining,
+                };
+            }
+        }
+
+        let mut stop = false;
+        if let Some(hit) = self.guard.instruction(cpu) {
+            println!("{}", hit.text());
+            stop = true;
+        }
+        let accesses = std::mem::take(&mut cpu.last_accesses);
+        for access in &accesses {
+            let operation = match access.kind {
+                AccessKind::Read(_) => LOAD,
+                AccessKind::Write { .. } => STORE,
+            };
+            stop |= self.hit(cpu, operation, access.addr);
+            if access.addr == MemMap::CHRIN as u16 && operation == LOAD {
+                if let Some(key) = self.keys.pop_front() {
+                    cpu.memory.set_byte(MemMap::CHRIN as usize, key);
+                }
+            }
+        }
+        cpu.last_accesses = accesses;
+        if let Some(hit) = self.guard.interrupt(cpu) {
+            println!("{}", hit.text());
+            stop = true;
+        }
+
+        let pc = cpu.program_counter;
+        let finished = match self.mode {
+            Mode::Advance { remaining, over } => self.advanced(remaining, over),
+            Mode::Over {
+                return_pc,
+                sp,
+                remaining,
+            } if pc == return_pc && cpu.stack_pointer == sp => self.advanced(remaining, true),
+            // RTS popped the return address of the frame we were in
+            Mode::Finish { sp } => cpu.stack_pointer as u16 >= sp as u16 + 2,
+            _ => false,
+        };
+        if finished || stop {
+            self.stop(cpu);
+        }
+    }
+
+    // One more instruction of an advance done, true when it was the last
+    fn advanced(&mut self, remaining: u16, over: bool) -> bool {
+        if remaining <= 1 {
+            return true;
+        }
+        self.mode = Mode::Advance {
+            remaining: remaining - 1,
+            over,
+        };
+        false
+    }
+}
+
+fn registers(cpu: &Cpu6502) -> Vec<u8> {
+    let mut out = (REGISTERS.len() as u16).to_le_bytes().to_vec();
+    for (id, _, _) in REGISTERS {
+        let value = match id {
+            0x00 => cpu.accumulator as u16,
+            0x01 => cpu.x_index as u16,
+            0x02 => cpu.y_index as u16,
+            0x03 => cpu.program_counter,
+            0x04 => cpu.stack_pointer as u16,
+            _ => cpu.status_flags.as_u8() as u16,
+        };
+        out.extend([3, id]);
+        out.extend(value.to_le_bytes());
+    }
+    out
+}
+
+fn set_register(cpu: &mut Cpu6502, id: u8, value: u16) -> bool {
+    let byte = value as u8;
+    match id {
+        0x00 => cpu.accumulator = byte,
+        0x01 => cpu.x_index = byte,
+        0x02 => cpu.y_index = byte,
+        0x03 => cpu.program_counter = value,
+        0x04 => cpu.stack_pointer = byte,
+        0x05 => cpu.status_flags.set_from_u8(byte),
+        _ => return false,
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu6502::cpu_with_program;
+    use crate::debugger::monitor::{next_request, Mode, Monitor};
+    use crate::debugger::Guard;
+    use std::collections::VecDeque;
+
+    fn request(request_id: u32, command: u8, body: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x02, 0x02];
+        out.extend((body.len() as u32).to_le_bytes());
+        out.extend(request_id.to_le_bytes());
+        out.push(command);
+        out.extend(body);
+        out
+    }
+
+    #[test]
+    fn test_framing() {
+        let mut input = request(7, 0x81, &[]);
+        input.extend(&request(8, 0x01, &[0, 0x00, 0x06, 0x01, 0x06, 0, 0, 0])[..12]);
+        assert_eq!(next_request(&mut input), Some((7, 0x81, Vec::new())));
+        // the second request is still incomplete
+        assert_eq!(next_request(&mut input), None);
+        assert_eq!(input.len(), 12);
+    }
+
+    #[test]
+    fn test_checkpoint_and_memory() {
+        // LDX #$00 / INX / STX $10 / JMP $0602
+        let program = [0xa2, 0x00, 0xe8, 0x86, 0x10, 0x4c, 0x02, 0x06];
+        let mut cpu = cpu_with_program(&[], 0x0600, &program);
+        let mut monitor = Monitor {
+            guard: Guard::new(&cpu),
+            checkpoints: Vec::new(),
+            last_id: 0,
+            mode: Mode::Stopped,
+            skip_checkpoint: false,
+            keys: VecDeque::new(),
+            outbox: Vec::new(),
+            quit: false,
+        };
+
+        // store checkpoint on $10, then a condition so only X == 3 stops
+        monitor.command(&mut cpu, 1, 0x12, &[0x10, 0x00, 0x10, 0x00, 1, 1, 0x02, 0]);
+        let condition = b"X == 3";
+        let mut body = 1u32.to_le_bytes().to_vec();
+        body.push(condition.len() as u8);
+        body.extend(condition);
+        monitor.command(&mut cpu, 2, 0x22, &body);
+        monitor.outbox.clear();
+
+        monitor.command(&mut cpu, 3, 0xaa, &[]);
+        while !matches!(monitor.mode, Mode::Stopped) {
+            monitor.run_batch(&mut cpu);
+        }
+        assert_eq!(cpu.x_index, 3);
+        assert_eq!(cpu.program_counter, 0x0605);
+        assert_eq!(monitor.checkpoints[0].hits, 1);
+
+        monitor.outbox.clear();
+        monitor.command(&mut cpu, 4, 0x01, &[0, 0x10, 0x00, 0x10, 0x00, 0, 0, 0]);
+        // header, then a length of 1 and the byte
+        assert_eq!(monitor.outbox[6..12], [0x01, 0x00, 4, 0, 0, 0]);
+        assert_eq!(monitor.outbox[12..], [1, 0, 3]);
+
+        monitor.command(&mut cpu, 5, 0x71, &[0, 2, 0]);
+        while !matches!(monitor.mode, Mode::Stopped) {
+            monitor.run_batch(&mut cpu);
+        }
+        assert_eq!(cpu.program_counter, 0x0603);
+    }
+
+    #[test]
+    fn test_catchpoint_from_args() {
+        // LDX #$00 / INX / BRK
+        let mut cpu = cpu_with_program(&["--catch", "brk"], 0x0600, &[0xa2, 0x00, 0xe8, 0x00]);
+        let mut monitor = Monitor {
+            guard: Guard::from_args(&mut cpu),
+            checkpoints: Vec::new(),
+            last_id: 0,
+            mode: Mode::Stopped,
+            skip_checkpoint: false,
+            keys: VecDeque::new(),
+            outbox: Vec::new(),
+            quit: false,
+        };
+
+        monitor.command(&mut cpu, 1, 0xaa, &[]);
+        while !matches!(monitor.mode, Mode::Stopped) {
+            monitor.run_batch(&mut cpu);
+        }
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert_eq!(cpu.x_index, 1);
+    }
+}
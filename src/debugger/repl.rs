@@ -0,0 +1,295 @@
+use crate::asm;
+use crate::cpu6502::memory::AccessKind;
+use crate::cpu6502::{init_cpu6502, Args, Cpu6502, Registers, ReplArgs};
+use crate::debugger::expr::Expr;
+use crate::debugger::{disassemble, format_value, set_register, Guard};
+use crate::utils::line_editor::LineEditor;
+use clap::Parser;
+use colored::Colorize;
+use std::path::Path;
+
+// Where typed instructions go when there is no image to take a reset
+// vector from, the start of ROM in examples/bios.cfg
+const DEFAULT_PC: u16 = 0x0600;
+
+const HELP: &str = "\
+Type an instruction, eg `LDA #$80` or `loop: DEX`, to assemble it at PC and
+run it. Registers, flags and memory it changed are shown afterwards.
+Commands:
+  :step [n]          run the n instructions already at PC, default 1
+  :regs              show all registers
+  :mem START [END]   dump memory, 64 bytes by default
+  :set REG=VAL       set A X Y SP PC P or a flag N V D I Z C
+  :print EXPR        evaluate an expression, eg :print A + X
+  :reset             start over with a fresh CPU
+  :help              show this help
+  :quit              leave, as does Ctrl+D";
+
+// A fresh CPU with the image loaded, PC at its reset vector
+fn new_cpu(args: &ReplArgs) -> Result<Cpu6502, String> {
+    let image = args.image.as_deref().unwrap_or("unused.bin");
+    let mut cpu_args = Args::parse_from(["rust6502", image]);
+    cpu_args.labels = args.labels.clone();
+    cpu_args.no_print = true;
+    // the loaders panic on missing files
+    for path in [args.image.as_ref(), args.labels.as_ref()]
+        .into_iter()
+        .flatten()
+    {
+        if !Path::new(path).is_file() {
+            return Err(format!("cannot open {}", path));
+        }
+    }
+    let mut cpu = init_cpu6502(cpu_args);
+    // the writes an instruction made are part of what it changed
+    cpu.memory.enable_access_log();
+    cpu.memory.capture_console();
+    if args.image.is_some() {
+        cpu.load_file_into_memory();
+        cpu.reset();
+    } else {
+        cpu.program_counter = DEFAULT_PC;
+    }
+    cpu.load_debug_info();
+    Ok(cpu)
+}
+
+// Runs the instruction at PC and returns the lines describing what changed
+fn execute(cpu: &mut Cpu6502) -> Result<Vec<String>, String> {
+    let pc = cpu.program_counter;
+    let decoded = cpu.disassemble(pc);
+    if decoded.op.is_none() {
+        let opcode = cpu.memory.get_byte(pc as usize);
+        return Err(format!(
+            "${:02x} at {} is not an instruction",
+            opcode,
+            cpu.symbolic(pc, 4)
+        ));
+    }
+    let before = cpu.registers();
+    // nothing to break on, it runs the instruction the way the debuggers do
+    if let Some(stop) = Guard::new(cpu).execute(cpu) {
+        return Err(stop.text().to_string());
+    }
+    Ok(changes(cpu, &before, pc.wrapping_add(decoded.len())))
+}
+
+// Registers, flags and memory that differ from before, PC only when the
+// instruction went somewhere other than the next one
+fn changes(cpu: &mut Cpu6502, before: &Registers, next: u16) -> Vec<String> {
+    let after = cpu.registers();
+    let mut lines = Vec::new();
+    for (name, old, new) in [
+        ("A", before.a, after.a),
+        ("X", before.x, after.x),
+        ("Y", before.y, after.y),
+        ("SP", before.sp, after.sp),
+    ] {
+        if old != new {
+            lines.push(format!("{:<6} ${:02x} -> ${:02x}", name, old, new));
+        }
+    }
+    for (bit, name) in [(7, "N"), (6, "V"), (3, "D"), (2, "I"), (1, "Z"), (0, "C")] {
+        let old = (before.p >> bit) & 1;
+        let new = (after.p >> bit) & 1;
+        if old != new {
+            lines.push(format!("{:<6} {} -> {}", name, old, new));
+        }
+    }
+    if after.pc != next {
+        lines.push(format!(
+            "{:<6} ${:04x} -> ${:04x}",
+            "PC", before.pc, after.pc
+        ));
+    }
+    for access in &cpu.last_accesses {
+        if let AccessKind::Write { old, new } = access.kind {
+            let addr = cpu.symbolic(access.addr, 4);
+            lines.push(format!("{:<6} ${:02x} -> ${:02x}", addr, old, new));
+        }
+    }
+    let output = cpu.memory.take_console();
+    if !output.is_empty() {
+        lines.push(format!("output {:?}", String::from_utf8_lossy(&output)));
+    }
+    lines.push(format!("{} cycles", after.cycles - before.cycles));
+    lines
+}
+
+fn print_registers(cpu: &Cpu6502) {
+    let flags: String = "NV-BDIZC"
+        .chars()
+        .enumerate()
+        .map(
+            |(i, name)| match (cpu.status_flags.as_u8() >> (7 - i)) & 1 {
+                1 => name,
+                _ => name.to_ascii_lowercase(),
+            },
+        )
+        .collect();
+    println!(
+        "A=${:02x} X=${:02x} Y=${:02x} SP=${:02x} PC=${:04x} P={}",
+        cpu.accumulator, cpu.x_index, cpu.y_index, cpu.stack_pointer, cpu.program_counter, flags
+    );
+}
+
+fn print_lines(lines: &[String]) {
+    for line in lines {
+        println!("  {}", line);
+    }
+}
+
+// Assembles one line at PC and runs it, a line with only a label just names PC
+fn enter(cpu: &mut Cpu6502, line: &str) -> Result<(), String> {
+    let pc = cpu.program_counter;
+    let known: Vec<(String, u16)> = cpu
+        .symbols
+        .in_range(0, 0x10000)
+        .map(|(addr, name)| (name.to_string(), addr))
+        .collect();
+    let output = asm::assemble_at(line, pc, &known)?;
+    for (name, value) in output.labels {
+        cpu.symbols.insert(name, value);
+    }
+    if output.image.is_empty() {
+        return Ok(());
+    }
+    for (offset, byte) in output.image.iter().enumerate() {
+        cpu.set_byte_wrap(pc.wrapping_add(offset as u16) as usize, *byte);
+    }
+    if cpu.disassemble(pc).len() as usize != output.image.len() {
+        return Err("enter one instruction per line".to_string());
+    }
+    print_lines(&execute(cpu)?);
+    Ok(())
+}
+
+fn command(cpu: &mut Cpu6502, args: &ReplArgs, line: &str) -> Result<bool, String> {
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or("");
+    let rest: Vec<&str> = words.collect();
+    match name {
+        "step" | "s" => {
+            let count = match rest.first() {
+                Some(count) => count
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid count '{}'", count))?,
+                None => 1,
+            };
+            for _ in 0..count {
+                let (_, text) = disassemble(cpu, cpu.program_counter);
+                println!("0x{:#>04x}: {}", cpu.program_counter, text);
+                print_lines(&execute(cpu)?);
+            }
+        }
+        "regs" | "r" => print_registers(cpu),
+        "mem" | "m" => {
+            let start = cpu.resolve_location(rest.first().ok_or("usage: :mem START [END]")?)?;
+            let end = match rest.get(1) {
+                Some(end) => cpu.resolve_location(end)?,
+                None => start.saturating_add(0x3f),
+            };
+            if end < start {
+                return Err("END is before START".to_string());
+            }
+            cpu.memory.dump_range(
+                start as usize,
+                end as usize,
+                cpu.program_counter,
+                cpu.stack_pointer,
+                &cpu.symbols,
+            );
+        }
+        "set" => set_register(cpu, &rest.join(" "))?,
+        "print" | "p" => println!("{}", format_value(Expr::parse(&rest.join(" "))?.eval(cpu)?)),
+        "reset" => {
+            *cpu = new_cpu(args)?;
+            print_registers(cpu);
+        }
+        "help" | "h" => println!("{}", HELP),
+        "quit" | "q" => return Ok(true),
+        _ => return Err(format!("unknown command ':{}', try :help", name)),
+    }
+    Ok(false)
+}
+
+pub fn run(args: &ReplArgs) {
+    let mut cpu = match new_cpu(args) {
+        Ok(cpu) => cpu,
+        Err(error) => panic!("Problem starting the repl: {}", error),
+    };
+    println!("6502 repl, :help for commands");
+    print_registers(&cpu);
+    let mut editor = LineEditor::default();
+    loop {
+        let prompt = format!("0x{:#>04x}> ", cpu.program_counter);
+        let line = match editor.read_line(&prompt) {
+            Some(line) => line,
+            None => return,
+        };
+        let line = line.trim();
+        let result = match line.strip_prefix(':') {
+            Some(line) => command(&mut cpu, args, line),
+            None if line.is_empty() => Ok(false),
+            None => enter(&mut cpu, line).map(|_| false),
+        };
+        match result {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(error) => println!("{}", error.red()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu6502::cpu_with_program;
+    use crate::debugger::repl::enter;
+
+    #[test]
+    fn test_changes() {
+        let mut cpu = cpu_with_program(&[], 0x0600, &[]);
+        cpu.memory.enable_access_log();
+        cpu.memory.capture_console();
+        enter(&mut cpu, "LDA #$50").unwrap();
+        assert_eq!(cpu.accumulator, 0x50);
+        assert_eq!(cpu.program_counter, 0x0602);
+
+        let before = cpu.registers();
+        cpu.set_byte_wrap(0x0602, 0xc9); // CMP #$50
+        cpu.set_byte_wrap(0x0603, 0x50);
+        let lines = super::execute(&mut cpu).unwrap();
+        assert_eq!(lines, ["Z      0 -> 1", "C      0 -> 1", "2 cycles"]);
+        assert_eq!(cpu.registers().pc, before.pc + 2);
+
+        enter(&mut cpu, "STA $0200").unwrap();
+        assert_eq!(cpu.memory.get_byte(0x0200), 0x50);
+        let before = cpu.registers();
+        cpu.set_byte_wrap(0x0607, 0x9d); // STA $01ff,X with X=0
+        cpu.set_byte_wrap(0x0608, 0xff);
+        cpu.set_byte_wrap(0x0609, 0x01);
+        let lines = super::execute(&mut cpu).unwrap();
+        assert_eq!(lines, ["$01ff  $00 -> $50", "5 cycles"]);
+        assert_eq!(cpu.registers().x, before.x);
+
+        // BRK pushes PC+2 and P with B set, then jumps through $fffe
+        cpu.set_byte_wrap(0x060a, 0x00);
+        cpu.set_byte_wrap(0xfffe, 0x00);
+        cpu.set_byte_wrap(0xffff, 0x07);
+        let lines = super::execute(&mut cpu).unwrap();
+        assert_eq!(
+            lines,
+            [
+                "SP     $ff -> $fc",
+                "I      0 -> 1",
+                "PC     $060a -> $0700",
+                "$01ff  $50 -> $06",
+                "$01fe  $00 -> $0c",
+                "$01fd  $00 -> $33",
+                "7 cycles"
+            ]
+        );
+        assert_eq!(cpu.program_counter, 0x0700);
+        assert!(enter(&mut cpu, "LDA #").is_err());
+    }
+}
@@ -0,0 +1,524 @@
+// Full screen debugger (--tui). Registers, stack, disassembly, memory and the
+// CHROUT console are drawn from the CPU state in panes instead of dumping all
+// of memory every instruction. While running the screen is redrawn every
+// REDRAW, the emulator runs flat out in between.
+use crate::cpu6502::memory::MemMap;
+use crate::cpu6502::{operation, Cpu6502};
+use crate::debugger::{lead_in, Guard};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Color, PrintStyledContent, StyledContent, Stylize};
+use crossterm::terminal::{self, ClearType};
+use crossterm::{cursor, execute, queue};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+const REDRAW: Duration = Duration::from_millis(50);
+// instructions between checks for key presses while running
+const BATCH: usize = 2000;
+const LEFT_WIDTH: u16 = 24;
+const CONSOLE_LINES: usize = 500;
+const KEYS: &str = " s step  n next  c continue  p pause  b breakpoint  \
+                    \u{2191}\u{2193} select  PgUp/PgDn memory  Tab console  q quit";
+
+type Line = Vec<StyledContent<String>>;
+
+enum Mode {
+    Paused,
+    Running,
+    // running until a JSR returns to return_pc with the stack back at sp
+    StepOver { return_pc: u16, sp: u8 },
+}
+
+#[derive(PartialEq)]
+enum Focus {
+    Debugger,
+    // keys go to CHRIN
+    Console,
+}
+
+// CHROUT output split into lines, logpoint messages are added as their own lines
+struct Console {
+    lines: Vec<String>,
+}
+
+impl Console {
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            match byte {
+                b'\r' | b'\n' => self.lines.push(String::new()),
+                0x08 => {
+                    self.lines.last_mut().unwrap().pop();
+                }
+                0x20..=0x7e => self.lines.last_mut().unwrap().push(byte as char),
+                _ => {}
+            }
+        }
+        self.trim();
+    }
+
+    fn push_line(&mut self, line: String) {
+        if self.lines.last().is_some_and(|last| last.is_empty()) {
+            self.lines.pop();
+        }
+        self.lines.push(line);
+        self.lines.push(String::new());
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        if self.lines.len() > CONSOLE_LINES {
+            self.lines.drain(..self.lines.len() - CONSOLE_LINES);
+        }
+    }
+}
+
+struct Tui {
+    guard: Guard,
+    mode: Mode,
+    focus: Focus,
+    // first address shown in the memory pane
+    mem_top: u16,
+    // selected disassembly line, None follows PC
+    selected: Option<u16>,
+    console: Console,
+    status: String,
+    quit: bool,
+}
+
+// Puts the terminal back however the debugger exits
+struct Screen;
+
+impl Screen {
+    fn enter() -> Screen {
+        terminal::enable_raw_mode().unwrap();
+        execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide).unwrap();
+        Screen
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+pub fn run(cpu: &mut Cpu6502) {
+    let mut tui = Tui {
+        guard: Guard::from_args(cpu),
+        mode: if cpu.cmdline_args.step_debug {
+            Mode::Paused
+        } else {
+            Mode::Running
+        },
+        focus: Focus::Debugger,
+        mem_top: cpu.program_counter & 0xfff0,
+        selected: None,
+        console: Console {
+            lines: vec![String::new()],
+        },
+        status: String::new(),
+        quit: false,
+    };
+    cpu.memory.capture_console();
+    let screen = Screen::enter();
+    let mut out = io::stdout();
+    let mut last_draw = Instant::now();
+    let mut dirty = true;
+    while !tui.quit {
+        let running = !matches!(tui.mode, Mode::Paused);
+        let timeout = if running { Duration::ZERO } else { REDRAW };
+        if event::poll(timeout).unwrap() {
+            match event::read().unwrap() {
+                Event::Key(key) if key.kind != KeyEventKind::Release => tui.key(cpu, key),
+                Event::Resize(..) => {
+                    queue!(out, terminal::Clear(ClearType::All)).unwrap();
+                }
+                _ => {}
+            }
+            dirty = true;
+        }
+        for _ in 0..BATCH {
+            if matches!(tui.mode, Mode::Paused) {
+                break;
+            }
+            tui.execute(cpu, true);
+            dirty = true;
+        }
+        tui.console.push_bytes(&cpu.memory.take_console());
+        if dirty && (matches!(tui.mode, Mode::Paused) || last_draw.elapsed() >= REDRAW) {
+            tui.draw(cpu, &mut out);
+            last_draw = Instant::now();
+            dirty = false;
+        }
+    }
+    drop(screen);
+    // what the program printed is lost with the alternate screen otherwise
+    for line in &tui.console.lines {
+        print!("{}\r\n", line);
+    }
+}
+
+impl Tui {
+    fn key(&mut self, cpu: &mut Cpu6502, key: KeyEvent) {
+        if key.code == KeyCode::Char('c') && key.modifiers == KeyModifiers::CONTROL {
+            self.quit = true;
+            return;
+        }
+        if key.code == KeyCode::Tab {
+            self.focus = match self.focus {
+                Focus::Debugger => Focus::Console,
+                Focus::Console => Focus::Debugger,
+            };
+            return;
+        }
+        if self.focus == Focus::Console {
+            let byte = match key.code {
+                KeyCode::Esc => {
+                    self.focus = Focus::Debugger;
+                    return;
+                }
+                KeyCode::Backspace => 0x08,
+                KeyCode::Enter => 0x0d,
+                KeyCode::Char(c) if c.is_ascii() => c as u8,
+                _ => return,
+            };
+            cpu.memory.set_byte(MemMap::CHRIN as usize, byte);
+            return;
+        }
+
+        let rows = self.memory_rows() as u16 * self.memory_width() as u16;
+        match key.code {
+            KeyCode::Char('q') => self.quit = true,
+            KeyCode::Char('s') | KeyCode::F(11) => self.step(cpu),
+            KeyCode::Char('n') | KeyCode::F(10) => self.step_over(cpu),
+            KeyCode::Char('c') | KeyCode::F(5) => {
+                self.status.clear();
+                self.mode = Mode::Running;
+                // the breakpoint we are sitting on must not stop us straight away
+                self.execute(cpu, false);
+            }
+            KeyCode::Char('p') | KeyCode::F(6) if !matches!(self.mode, Mode::Paused) => {
+                self.stop(format!(
+                    "Paused at {}",
+                    cpu.symbolic(cpu.program_counter, 4)
+                ));
+            }
+            KeyCode::Char('b') | KeyCode::F(9) => {
+                let addr = self.selected.unwrap_or(cpu.program_counter);
+                self.status = match self.guard.breakpoints.toggle(addr) {
+                    Some(id) => format!("Breakpoint {} set at {}", id, cpu.symbolic(addr, 4)),
+                    None => format!("Breakpoint at {} removed", cpu.symbolic(addr, 4)),
+                };
+            }
+            KeyCode::Down => {
+                let addr = self.selected.unwrap_or(cpu.program_counter);
+                self.selected = Some(addr.wrapping_add(cpu.disassemble(addr).len()));
+            }
+            KeyCode::Up => {
+                let addr = self.selected.unwrap_or(cpu.program_counter);
+                self.selected = Some(lead_in(cpu, addr, 1));
+            }
+            KeyCode::Esc => self.selected = None,
+            KeyCode::PageDown => self.mem_top = self.mem_top.wrapping_add(rows),
+            KeyCode::PageUp => self.mem_top = self.mem_top.wrapping_sub(rows),
+            KeyCode::Home => self.mem_top = cpu.program_counter & 0xfff0,
+            KeyCode::End => self.mem_top = (0x0100 | cpu.stack_pointer as u16) & 0xfff0,
+            _ => {}
+        }
+    }
+
+    fn step(&mut self, cpu: &mut Cpu6502) {
+        self.status.clear();
+        self.mode = Mode::Paused;
+        self.execute(cpu, false);
+        self.selected = None;
+    }
+
+    fn step_over(&mut self, cpu: &mut Cpu6502) {
+        let pc = cpu.program_counter;
+        let is_jsr = operation::lookup_opcode(cpu.memory.get_byte(pc as usize))
+            .is_some_and(|op| matches!(op.instruction_type, operation::Instruction::JSR));
+        if !is_jsr {
+            self.step(cpu);
+            return;
+        }
+        self.status.clear();
+        self.mode = Mode::StepOver {
+            return_pc: pc.wrapping_add(3),
+            sp: cpu.stack_pointer,
+        };
+        self.execute(cpu, false);
+    }
+
+    fn stop(&mut self, status: String) {
+        self.mode = Mode::Paused;
+        self.status = status;
+        self.selected = None;
+    }
+
+    // Runs the instruction at PC unless a breakpoint (when check_breaks),
+    // a catchpoint or an invalid opcode stops us first
+    fn execute(&mut self, cpu: &mut Cpu6502, check_breaks: bool) {
+        if !check_breaks {
+            self.guard.resume(cpu);
+        }
+        let console = &mut self.console;
+        if let Some(stop) = self.guard.step(cpu, &mut |line| console.push_line(line)) {
+            return self.stop(stop.text().to_string());
+        }
+        if let Mode::StepOver { return_pc, sp } = self.mode {
+            if cpu.program_counter == return_pc && cpu.stack_pointer == sp {
+                self.stop(String::new());
+            }
+        }
+    }
+
+    fn console_height(&self) -> usize {
+        let (_, height) = terminal::size().unwrap();
+        (height as usize / 4).max(4)
+    }
+
+    fn memory_width(&self) -> usize {
+        let (width, _) = terminal::size().unwrap();
+        // address, hex and ASCII columns for 16 bytes
+        if width.saturating_sub(LEFT_WIDTH + 1) >= 6 + 16 * 4 {
+            16
+        } else {
+            8
+        }
+    }
+
+    // Rows of the memory pane, the disassembly pane gets the same
+    fn memory_rows(&self) -> usize {
+        let (_, height) = terminal::size().unwrap();
+        let body = (height as usize).saturating_sub(self.console_height() + 3);
+        (body / 2).saturating_sub(1).max(1)
+    }
+
+    fn draw(&self, cpu: &Cpu6502, out: &mut impl Write) {
+        let (width, height) = terminal::size().unwrap();
+        let right = LEFT_WIDTH + 1;
+        let right_width = width.saturating_sub(right);
+        let console_height = self.console_height();
+        let body = (height as usize).saturating_sub(console_height + 3);
+        let rows = self.memory_rows();
+
+        let (state, color) = match self.mode {
+            Mode::Paused => ("PAUSED", Color::Yellow),
+            _ => ("RUNNING", Color::Green),
+        };
+        let title = vec![
+            format!(" rust6502  {}  ", cpu.cmdline_args.binary_file).reverse(),
+            format!(" {} ", state).black().on(color),
+            format!(
+                "  {} instructions  {} cycles",
+                cpu.instructions_executed, cpu.cycles
+            )
+            .reverse(),
+        ];
+        put(out, 0, 0, width, &title);
+
+        // left column, registers then the stack
+        let mut left = vec![vec![header("Registers", false)]];
+        left.extend(self.registers(cpu));
+        left.push(vec![header("Stack", false)]);
+        let stack_rows = body.saturating_sub(left.len());
+        left.extend(stack(cpu, stack_rows));
+        for y in 0..body {
+            let line = left.get(y).cloned().unwrap_or_default();
+            put(out, 0, y as u16 + 1, LEFT_WIDTH, &line);
+        }
+
+        // right column, disassembly over memory
+        let mut lines = vec![vec![header("Disassembly", false)]];
+        lines.extend(self.disassembly(cpu, rows));
+        lines.push(vec![header("Memory", false)]);
+        lines.extend(self.memory(cpu, rows));
+        for y in 0..body {
+            let line = lines.get(y).cloned().unwrap_or_default();
+            put(out, right, y as u16 + 1, right_width, &line);
+        }
+
+        let top = body as u16 + 1;
+        let title = if self.focus == Focus::Console {
+            "Console (typing goes to CHRIN, Tab/Esc to leave)"
+        } else {
+            "Console"
+        };
+        put(
+            out,
+            0,
+            top,
+            width,
+            &[header(title, self.focus == Focus::Console)],
+        );
+        let shown = console_height - 1;
+        let start = self.console.lines.len().saturating_sub(shown);
+        for y in 0..shown {
+            let line = self
+                .console
+                .lines
+                .get(start + y)
+                .cloned()
+                .unwrap_or_default();
+            put(out, 0, top + 1 + y as u16, width, &[line.stylize()]);
+        }
+
+        let status = if self.status.is_empty() {
+            KEYS.to_string().dark_grey()
+        } else {
+            format!(" {}", self.status).yellow()
+        };
+        put(out, 0, height.saturating_sub(1), width, &[status]);
+        out.flush().unwrap();
+    }
+
+    fn registers(&self, cpu: &Cpu6502) -> Vec<Line> {
+        let flags = cpu.status_flags.as_u8();
+        let mut flag_line = vec![" P  ".to_string().stylize()];
+        for (bit, name) in "NVUBDIZC".chars().enumerate() {
+            let set = flags & (0x80 >> bit) != 0;
+            flag_line.push(if set {
+                name.to_string().green().bold()
+            } else {
+                name.to_ascii_lowercase().to_string().dark_grey()
+            });
+        }
+        flag_line.push(format!(" ${:02x}", flags).stylize());
+        let pc = cpu.program_counter;
+        let name = cpu.symbols.name_at(pc).unwrap_or("");
+        vec![
+            vec![
+                " PC".to_string().blue(),
+                format!(" ${:04x} ", pc).stylize(),
+                name.to_string().cyan(),
+            ],
+            vec![
+                " SP".to_string().yellow(),
+                format!(" ${:02x}", cpu.stack_pointer).stylize(),
+            ],
+            vec![format!(" A  ${:02x} {:>3}", cpu.accumulator, cpu.accumulator).stylize()],
+            vec![format!(" X  ${:02x} {:>3}", cpu.x_index, cpu.x_index).stylize()],
+            vec![format!(" Y  ${:02x} {:>3}", cpu.y_index, cpu.y_index).stylize()],
+            flag_line,
+        ]
+    }
+
+    fn disassembly(&self, cpu: &Cpu6502, rows: usize) -> Vec<Line> {
+        let pc = cpu.program_counter;
+        let focus = self.selected.unwrap_or(pc);
+        let mut addr = lead_in(cpu, focus, rows / 3);
+        let mut lines = Vec::new();
+        while lines.len() < rows {
+            if let Some(name) = cpu.symbols.name_at(addr) {
+                lines.push(vec![format!("        {}:", name).cyan()]);
+                if lines.len() == rows {
+                    break;
+                }
+            }
+            let decoded = cpu.disassemble(addr);
+            let has_break = self
+                .guard
+                .breakpoints
+                .breaks
+                .iter()
+                .any(|bp| bp.enabled && bp.log.is_none() && bp.addr == Some(addr));
+            let marker = if has_break {
+                " \u{25cf}".to_string().red()
+            } else {
+                "  ".to_string().stylize()
+            };
+            let arrow = if addr == pc {
+                "=>".to_string().blue().bold()
+            } else {
+                "  ".to_string().stylize()
+            };
+            let mut line = vec![
+                marker,
+                arrow,
+                format!(" {:04x}  {:<9} ", addr, decoded.hex()).stylize(),
+                decoded.mnemonic().green(),
+                format!(" {}", decoded.operand(Some(&cpu.symbols))).stylize(),
+            ];
+            if Some(addr) == self.selected {
+                line = line.into_iter().map(|span| span.reverse()).collect();
+            }
+            lines.push(line);
+            addr = addr.wrapping_add(decoded.len());
+        }
+        lines
+    }
+
+    fn memory(&self, cpu: &Cpu6502, rows: usize) -> Vec<Line> {
+        let per_row = self.memory_width();
+        let pc = cpu.program_counter;
+        let sp = 0x0100 | cpu.stack_pointer as u16;
+        (0..rows)
+            .map(|row| {
+                let start = self.mem_top.wrapping_add((row * per_row) as u16);
+                let mut line = vec![format!(" {:04x}: ", start).stylize()];
+                let mut ascii = String::new();
+                for offset in 0..per_row as u16 {
+                    let addr = start.wrapping_add(offset);
+                    let byte = cpu.memory.get_byte(addr as usize);
+                    let text = format!("{:02x}", byte);
+                    line.push(if addr == pc {
+                        text.blue().underlined()
+                    } else if addr == sp {
+                        text.yellow().underlined()
+                    } else {
+                        text.stylize()
+                    });
+                    line.push(" ".to_string().stylize());
+                    ascii.push(if byte.is_ascii_graphic() {
+                        byte as char
+                    } else {
+                        '.'
+                    });
+                }
+                line.push(ascii.stylize());
+                line
+            })
+            .collect()
+    }
+}
+
+fn stack(cpu: &Cpu6502, rows: usize) -> Vec<Line> {
+    let sp = cpu.stack_pointer;
+    (1..=rows.min(0xff - sp as usize))
+        .map(|offset| {
+            let addr = 0x0100 + sp as u16 + offset as u16;
+            let byte = cpu.memory.get_byte(addr as usize);
+            vec![format!(" {:04x}  ${:02x}", addr, byte).stylize()]
+        })
+        .collect()
+}
+
+fn header(title: &str, focused: bool) -> StyledContent<String> {
+    let text = format!("\u{2500} {} {}", title, "\u{2500}".repeat(200));
+    if focused {
+        text.yellow().bold()
+    } else {
+        text.dark_grey()
+    }
+}
+
+// Writes spans at (x, y), cut or padded to exactly width columns
+fn put(out: &mut impl Write, x: u16, y: u16, width: u16, spans: &[StyledContent<String>]) {
+    queue!(out, cursor::MoveTo(x, y)).unwrap();
+    let mut left = width as usize;
+    for span in spans {
+        if left == 0 {
+            break;
+        }
+        let text: String = span.content().chars().take(left).collect();
+        left -= text.chars().count();
+        queue!(
+            out,
+            PrintStyledContent(StyledContent::new(*span.style(), text))
+        )
+        .unwrap();
+    }
+    queue!(out, PrintStyledContent(" ".repeat(left).stylize())).unwrap();
+}
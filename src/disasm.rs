@@ -0,0 +1,184 @@
+use crate::cpu6502::operation::{self, AddressingMode, InstructionMetadata};
+use crate::symbols::SymbolTable;
+
+pub mod source;
+
+// One decoded instruction. Operands are rebuilt from the instruction bytes
+// rather than the CPU state, so any range of memory decodes the same way
+// whether or not the PC is anywhere near it.
+pub struct Disassembled {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    // None for bytes that are not an opcode, shown as `.byte`
+    pub op: Option<InstructionMetadata>,
+}
+
+impl Disassembled {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    // Raw bytes as hex, eg `b1 24`
+    pub fn hex(&self) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        bytes.join(" ")
+    }
+
+    pub fn mnemonic(&self) -> String {
+        match self.op {
+            Some(op) => format!("{:?}", op.instruction_type),
+            None => ".byte".to_string(),
+        }
+    }
+
+    // The address the operand names, for everything except immediates
+    pub fn target(&self) -> Option<u16> {
+        let op = self.op?;
+        let lo = self.bytes.get(1).copied().unwrap_or(0);
+        let word = (self.bytes.get(2).copied().unwrap_or(0) as u16) << 8 | lo as u16;
+        match op.mode {
+            AddressingMode::Accumulator | AddressingMode::Implied | AddressingMode::Immediate => {
+                None
+            }
+            AddressingMode::Relative => Some(
+                self.addr
+                    .wrapping_add(2)
+                    .wrapping_add_signed(lo as i8 as i16),
+            ),
+            AddressingMode::ZeroPage
+            | AddressingMode::ZeroPageX
+            | AddressingMode::ZeroPageY
+            | AddressingMode::ZeroPageIndirectIndexedX
+            | AddressingMode::ZeroPageIndirectIndexedY => Some(lo as u16),
+            AddressingMode::Absolute
+            | AddressingMode::AbsoluteXIndexed
+            | AddressingMode::AbsoluteYIndexed
+            | AddressingMode::AbsoluteIndirect
+            | AddressingMode::AbsoluteIndirectX
+            | AddressingMode::AbsoluteIndirectY => Some(word),
+        }
+    }
+
+    // Operand in ca65 syntax, eg `($24),Y` or `print`. Labels replace
+    // addresses when a symbol table is given.
+    pub fn operand(&self, symbols: Option<&SymbolTable>) -> String {
+        let op = match self.op {
+            Some(op) => op,
+            None => return format!("${:02x}", self.bytes[0]),
+        };
+        let name = |addr: u16, width: usize| match symbols.and_then(|symbols| symbols.name_at(addr))
+        {
+            Some(name) => name.to_string(),
+            None => format!("${:0width$x}", addr, width = width),
+        };
+        let target = self.target().unwrap_or(0);
+        // ca65 picks zero page for small addresses, `a:` keeps the absolute form
+        let absolute = if target < 0x100 {
+            format!("a:{}", name(target, 4))
+        } else {
+            name(target, 4)
+        };
+        match op.mode {
+            AddressingMode::Accumulator => "A".to_string(),
+            AddressingMode::Implied => String::new(),
+            AddressingMode::Immediate => format!("#${:02x}", self.bytes[1]),
+            AddressingMode::Relative => name(target, 4),
+            AddressingMode::Absolute => absolute,
+            AddressingMode::AbsoluteXIndexed => format!("{},X", absolute),
+            AddressingMode::AbsoluteYIndexed => format!("{},Y", absolute),
+            AddressingMode::AbsoluteIndirect => format!("({})", name(target, 4)),
+            AddressingMode::AbsoluteIndirectX => format!("({},X)", name(target, 4)),
+            AddressingMode::AbsoluteIndirectY => format!("({}),Y", name(target, 4)),
+            AddressingMode::ZeroPage => name(target, 2),
+            AddressingMode::ZeroPageX => format!("{},X", name(target, 2)),
+            AddressingMode::ZeroPageY => format!("{},Y", name(target, 2)),
+            AddressingMode::ZeroPageIndirectIndexedX => format!("({},X)", name(target, 2)),
+            AddressingMode::ZeroPageIndirectIndexedY => format!("({}),Y", name(target, 2)),
+        }
+    }
+
+    // Mnemonic and operand, eg `LDA ($24),Y`
+    pub fn text(&self, symbols: Option<&SymbolTable>) -> String {
+        let operand = self.operand(symbols);
+        if operand.is_empty() {
+            self.mnemonic()
+        } else {
+            format!("{} {}", self.mnemonic(), operand)
+        }
+    }
+}
+
+// Decodes the instruction at addr, reading bytes through `read` so that
+// both live memory and a loaded image can be disassembled
+pub fn decode(read: impl Fn(u16) -> u8, addr: u16) -> Disassembled {
+    let op = operation::lookup_opcode(read(addr));
+    let len = op.map_or(1, |op| op.instruction_byte_length as u16);
+    Disassembled {
+        addr,
+        bytes: (0..len).map(|i| read(addr.wrapping_add(i))).collect(),
+        op,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disasm::decode;
+    use crate::symbols::SymbolTable;
+
+    fn text_of(bytes: &[u8], addr: u16) -> String {
+        let read = |at: u16| {
+            bytes
+                .get(at.wrapping_sub(addr) as usize)
+                .copied()
+                .unwrap_or(0)
+        };
+        decode(read, addr).text(None)
+    }
+
+    #[test]
+    fn test_every_addressing_mode() {
+        let cases: &[(&[u8], &str)] = &[
+            (&[0x0a], "ASL A"),
+            (&[0xea], "NOP"),
+            (&[0xa9, 0x41], "LDA #$41"),
+            (&[0xad, 0x12, 0x06], "LDA $0612"),
+            (&[0xad, 0x24, 0x00], "LDA a:$0024"),
+            (&[0xbd, 0x00, 0x02], "LDA $0200,X"),
+            (&[0xb9, 0x00, 0x02], "LDA $0200,Y"),
+            (&[0xbc, 0x00, 0x02], "LDY $0200,X"),
+            (&[0xa5, 0x24], "LDA $24"),
+            (&[0xb5, 0x24], "LDA $24,X"),
+            (&[0xb4, 0x24], "LDY $24,X"),
+            (&[0xb6, 0x24], "LDX $24,Y"),
+            (&[0x6c, 0xfc, 0xff], "JMP ($fffc)"),
+            (&[0xa1, 0x24], "LDA ($24,X)"),
+            (&[0xb1, 0x24], "LDA ($24),Y"),
+            (&[0xf1, 0x24], "SBC ($24),Y"),
+            (&[0xd0, 0x0e], "BNE $0612"),
+            (&[0xd0, 0xfe], "BNE $0602"),
+            (&[0x02], ".byte $02"),
+        ];
+        for (bytes, expected) in cases {
+            assert_eq!(text_of(bytes, 0x0602), *expected);
+        }
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut symbols = SymbolTable::default();
+        symbols.insert("print".to_string(), 0x0611);
+        symbols.insert("ptr".to_string(), 0x24);
+        let bytes = [0x20, 0x11, 0x06, 0xb1, 0x24, 0xa9, 0x24];
+        let read = |at: u16| bytes.get(at as usize - 0x0600).copied().unwrap_or(0);
+        let mut lines = Vec::new();
+        let mut addr = 0x0600;
+        while addr < 0x0607 {
+            lines.push(decode(read, addr));
+            addr += lines.last().unwrap().len();
+        }
+        let text: Vec<String> = lines.iter().map(|l| l.text(Some(&symbols))).collect();
+        assert_eq!(text, ["JSR print", "LDA (ptr),Y", "LDA #$24"]);
+        assert_eq!(lines[1].addr, 0x0603);
+        assert_eq!(lines[0].hex(), "20 11 06");
+    }
+}
@@ -0,0 +1,365 @@
+// Recovers ca65 source from a raw image. Code is found by recursive
+// traversal from the vectors and any extra entry points, everything the
+// traversal does not reach is emitted as data, so the listing reassembles
+// to the same bytes: `ca65 out.s && ld65 -t none -o out.bin out.o`.
+use crate::cpu6502::operation::{AddressingMode, Instruction};
+use crate::cpu6502::DisasmArgs;
+use crate::disasm::{decode, Disassembled};
+use crate::symbols::{self, parse_addr, SymbolTable};
+use std::collections::BTreeMap;
+use std::fs;
+
+const VECTORS: [(u16, &str); 3] = [(0xFFFA, "nmi"), (0xFFFC, "reset"), (0xFFFE, "irq")];
+const BYTES_PER_LINE: usize = 16;
+// Repeated bytes become `.res` and printable runs strings past these lengths
+const MIN_FILL: usize = 32;
+const MIN_STRING: usize = 4;
+
+pub struct Image {
+    memory: Vec<u8>,
+    start: u16,
+    end: u32,
+}
+
+impl Image {
+    pub fn new(bytes: &[u8], start: u16) -> Result<Image, String> {
+        let end = start as u32 + bytes.len() as u32;
+        if bytes.is_empty() || end > 0x10000 {
+            return Err(format!(
+                "{} bytes do not fit in memory from ${:04x}",
+                bytes.len(),
+                start
+            ));
+        }
+        let mut memory = vec![0; 0x10000];
+        memory[start as usize..end as usize].copy_from_slice(bytes);
+        Ok(Image { memory, start, end })
+    }
+
+    fn contains(&self, addr: u32) -> bool {
+        addr >= self.start as u32 && addr < self.end
+    }
+
+    fn byte(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn decode(&self, addr: u16) -> Disassembled {
+        decode(|at| self.byte(at), addr)
+    }
+
+    fn word(&self, addr: u16) -> u16 {
+        (self.byte(addr.wrapping_add(1)) as u16) << 8 | self.byte(addr) as u16
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Byte {
+    Data,
+    // first byte of an instruction
+    Code,
+    // operand byte of the instruction before it
+    Operand,
+}
+
+pub struct Listing {
+    bytes: Vec<Byte>,
+    labels: BTreeMap<u16, String>,
+}
+
+// Follows every path from the entry points, stopping at RTS, RTI, BRK,
+// indirect jumps, invalid opcodes and the edges of the image
+pub fn trace(image: &Image, entries: &[u16], names: &SymbolTable) -> Listing {
+    let mut bytes = vec![Byte::Data; 0x10000];
+    let mut targets: BTreeMap<u16, char> = BTreeMap::new();
+    let mut pending: Vec<u16> = entries.to_vec();
+    while let Some(mut pc) = pending.pop() {
+        while image.contains(pc as u32) && bytes[pc as usize] == Byte::Data {
+            let line = image.decode(pc);
+            let op = match line.op {
+                Some(op) => op,
+                None => break,
+            };
+            let next = pc as u32 + line.len() as u32;
+            // an instruction running off the image or into other code is data
+            if next > image.end || (pc as u32 + 1..next).any(|at| bytes[at as usize] != Byte::Data)
+            {
+                break;
+            }
+            bytes[pc as usize] = Byte::Code;
+            for at in pc as u32 + 1..next {
+                bytes[at as usize] = Byte::Operand;
+            }
+            let target = line.target().unwrap_or(0);
+            match (op.instruction_type, op.mode) {
+                (Instruction::JSR, _) => {
+                    targets.entry(target).or_insert('S');
+                    pending.push(target);
+                }
+                (_, AddressingMode::Relative) => {
+                    targets.entry(target).or_insert('L');
+                    pending.push(target);
+                }
+                (Instruction::JMP, AddressingMode::Absolute) => {
+                    targets.entry(target).or_insert('L');
+                    pending.push(target);
+                    break;
+                }
+                (Instruction::JMP | Instruction::RTS | Instruction::RTI | Instruction::BRK, _) => {
+                    break
+                }
+                _ => {}
+            }
+            pc = next as u16;
+        }
+    }
+
+    // Known names first, then generated ones for targets that start an
+    // instruction. A target inside another instruction keeps its address.
+    let mut labels = BTreeMap::new();
+    for (addr, name) in names.in_range(0, 0x10000) {
+        labels.insert(addr, name.to_string());
+    }
+    for (addr, prefix) in targets {
+        if bytes[addr as usize] == Byte::Code {
+            labels
+                .entry(addr)
+                .or_insert_with(|| format!("{}{:04X}", prefix, addr));
+        }
+    }
+    Listing { bytes, labels }
+}
+
+// Entry points the vectors name, unprogrammed ($0000 or $FFFF) ones skipped
+fn vector_entries(image: &Image, names: &mut SymbolTable) -> Vec<u16> {
+    let mut entries = Vec::new();
+    for (vector, name) in VECTORS {
+        if !image.contains(vector as u32) || !image.contains(vector as u32 + 1) {
+            continue;
+        }
+        let target = image.word(vector);
+        if target != 0x0000 && target != 0xFFFF {
+            names.insert(name.to_string(), target);
+            entries.push(target);
+        }
+    }
+    entries
+}
+
+pub fn to_ca65(image: &Image, listing: &Listing, source_name: &str) -> String {
+    let mut out = format!(
+        "; Disassembled from {} by rust6502\n.setcpu \"6502\"\n\n",
+        source_name
+    );
+    // Labels that cannot sit on a line of their own become equates
+    let placed =
+        |addr: u16| image.contains(addr as u32) && listing.bytes[addr as usize] != Byte::Operand;
+    let mut symbols = SymbolTable::default();
+    let mut equates = String::new();
+    for (&addr, name) in &listing.labels {
+        symbols.insert(name.clone(), addr);
+        if !placed(addr) {
+            equates.push_str(&format!("{} = ${:04x}\n", name, addr));
+        }
+    }
+    if !equates.is_empty() {
+        out.push_str(&equates);
+        out.push('\n');
+    }
+    out.push_str(&format!(".org ${:04x}\n", image.start));
+
+    let mut addr = image.start as u32;
+    while addr < image.end {
+        let at = addr as u16;
+        if let Some(name) = listing.labels.get(&at) {
+            out.push_str(&format!("{}:\n", name));
+        }
+        if listing.bytes[addr as usize] == Byte::Code {
+            let line = image.decode(at);
+            // ca65 assumes a forward reference is absolute, so zero page
+            // operands only use labels that are already defined
+            let forward = line.target().is_some_and(|target| {
+                target > at && placed(target) && listing.labels.contains_key(&target)
+            });
+            let zero_page = matches!(
+                line.op.map(|op| op.mode),
+                Some(
+                    AddressingMode::ZeroPage
+                        | AddressingMode::ZeroPageX
+                        | AddressingMode::ZeroPageY
+                        | AddressingMode::ZeroPageIndirectIndexedX
+                        | AddressingMode::ZeroPageIndirectIndexedY
+                )
+            );
+            let text = if forward && zero_page {
+                line.text(None)
+            } else {
+                line.text(Some(&symbols))
+            };
+            out.push_str(&format!("    {:<24}; {:04x}  {}\n", text, at, line.hex()));
+            addr += line.len() as u32;
+            continue;
+        }
+        // data runs up to the next label or instruction
+        let mut end = addr + 1;
+        while end < image.end
+            && listing.bytes[end as usize] == Byte::Data
+            && !listing.labels.contains_key(&(end as u16))
+            && end != VECTORS[0].0 as u32
+        {
+            end += 1;
+        }
+        data_lines(&mut out, image, &symbols, addr, end);
+        addr = end;
+    }
+    out
+}
+
+fn data_lines(out: &mut String, image: &Image, symbols: &SymbolTable, start: u32, end: u32) {
+    let mut addr = start;
+    let mut pending: Vec<String> = Vec::new();
+    let flush = |out: &mut String, pending: &mut Vec<String>| {
+        if !pending.is_empty() {
+            out.push_str(&format!("    .byte {}\n", pending.join(", ")));
+            pending.clear();
+        }
+    };
+    while addr < end {
+        let byte = image.byte(addr as u16);
+        let run = (addr..end)
+            .take_while(|&at| image.byte(at as u16) == byte)
+            .count();
+        let text = (addr..end)
+            .take_while(|&at| printable(image.byte(at as u16)))
+            .count();
+        if addr == 0xFFFA && end == 0x10000 {
+            // the vector table, by name where the targets have one
+            flush(out, &mut pending);
+            let words: Vec<String> = VECTORS
+                .iter()
+                .map(|&(vector, _)| {
+                    let target = image.word(vector);
+                    match symbols.name_at(target) {
+                        Some(name) => name.to_string(),
+                        None => format!("${:04x}", target),
+                    }
+                })
+                .collect();
+            out.push_str(&format!("    .word {}\n", words.join(", ")));
+            addr = end;
+        } else if run >= MIN_FILL {
+            flush(out, &mut pending);
+            out.push_str(&format!("    .res {}, ${:02x}\n", run, byte));
+            addr += run as u32;
+        } else if text >= MIN_STRING {
+            flush(out, &mut pending);
+            let string: String = (addr..addr + text as u32)
+                .map(|at| image.byte(at as u16) as char)
+                .collect();
+            out.push_str(&format!("    .byte \"{}\"\n", string));
+            addr += text as u32;
+        } else {
+            pending.push(format!("${:02x}", byte));
+            if pending.len() == BYTES_PER_LINE {
+                flush(out, &mut pending);
+            }
+            addr += 1;
+        }
+    }
+    flush(out, &mut pending);
+}
+
+// Characters that can go in a ca65 string literal as they are
+fn printable(byte: u8) -> bool {
+    (0x20..0x7f).contains(&byte) && byte != b'"'
+}
+
+pub fn run(args: &DisasmArgs) {
+    let bytes = match fs::read(&args.image) {
+        Ok(bytes) => bytes,
+        Err(error) => panic!("Problem opening the file: {:?}", error),
+    };
+    let mut names = match &args.labels {
+        Some(path) => match symbols::load_label_file(path) {
+            Ok(names) => names,
+            Err(error) => panic!("Problem loading label file {}: {}", path, error),
+        },
+        None => SymbolTable::default(),
+    };
+    let origin = match &args.origin {
+        Some(origin) => match parse_addr(origin, &names) {
+            Ok(origin) => origin,
+            Err(error) => panic!("Problem with origin: {}", error),
+        },
+        None => 0x10000_u32.saturating_sub(bytes.len() as u32) as u16,
+    };
+    let image = match Image::new(&bytes, origin) {
+        Ok(image) => image,
+        Err(error) => panic!("Problem loading image {}: {}", args.image, error),
+    };
+
+    let mut entries = vector_entries(&image, &mut names);
+    for entry in &args.entry {
+        match parse_addr(entry, &names) {
+            Ok(addr) => entries.push(addr),
+            Err(error) => panic!("Problem with entry point: {}", error),
+        }
+    }
+    let listing = trace(&image, &entries, &names);
+    let source = to_ca65(&image, &listing, &args.image);
+    match &args.output {
+        Some(path) => {
+            if let Err(error) = fs::write(path, source) {
+                panic!("Problem writing {}: {:?}", path, error);
+            }
+        }
+        None => print!("{}", source),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disasm::source::{to_ca65, trace, vector_entries, Image};
+    use crate::symbols::SymbolTable;
+
+    #[test]
+    fn test_trace_separates_code_and_data() {
+        let mut bytes = vec![0; 0x100];
+        // $ff00: JSR $ff0c, BNE $ff00, JMP ($fff0), "Hi!!"
+        let code = [0x20, 0x0c, 0xff, 0xd0, 0xfb, 0x6c, 0xf0, 0xff];
+        bytes[..code.len()].copy_from_slice(&code);
+        bytes[8..12].copy_from_slice(b"Hi!!");
+        // $ff0c: LDA $24 / STA $fe00,X / RTS
+        bytes[12..18].copy_from_slice(&[0xa5, 0x24, 0x9d, 0x00, 0xfe, 0x60]);
+        bytes[0xfa..].copy_from_slice(&[0x00, 0x00, 0x00, 0xff, 0xff, 0xff]);
+        let image = Image::new(&bytes, 0xff00).unwrap();
+
+        let mut names = SymbolTable::default();
+        names.insert("DSP".to_string(), 0xfe00);
+        let entries = vector_entries(&image, &mut names);
+        assert_eq!(entries, vec![0xff00]);
+        let listing = trace(&image, &entries, &names);
+        let source = to_ca65(&image, &listing, "test.bin");
+        let lines: Vec<&str> = source
+            .lines()
+            .map(|l| l.split(';').next().unwrap().trim_end())
+            .collect();
+        let expected = [
+            "DSP = $fe00",
+            "",
+            ".org $ff00",
+            "reset:",
+            "    JSR SFF0C",
+            "    BNE reset",
+            "    JMP ($fff0)",
+            "    .byte \"Hi!!\"",
+            "SFF0C:",
+            "    LDA $24",
+            "    STA DSP,X",
+            "    RTS",
+            "    .res 232, $00",
+            "    .word $0000, reset, $ffff",
+        ];
+        assert_eq!(lines[3..], expected);
+    }
+}
@@ -0,0 +1,9 @@
+pub mod elf;
+pub mod patch;
+pub mod woz_hex;
+
+// A contiguous run of bytes to place at a fixed address
+pub struct Segment {
+    pub addr: u16,
+    pub data: Vec<u8>,
+}
@@ -0,0 +1,245 @@
+// Minimal ELF32 reader for the images llvm-mos produces. Only the pieces
+// needed to run a program are parsed: PT_LOAD program headers, the entry
+// point and the .symtab symbols.
+use crate::loader::Segment;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHN_UNDEF: u16 = 0;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+pub struct ElfImage {
+    pub entry: u16,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<(String, u16)>,
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(&ELF_MAGIC)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, String> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(format!("unexpected end of file at offset 0x{:x}", offset))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(format!("unexpected end of file at offset 0x{:x}", offset))
+}
+
+fn read_cstr(bytes: &[u8], offset: usize) -> Result<String, String> {
+    let tail = bytes
+        .get(offset..)
+        .ok_or(format!("string offset 0x{:x} out of range", offset))?;
+    let end = tail.iter().position(|&b| b == 0).unwrap_or(tail.len());
+    Ok(String::from_utf8_lossy(&tail[..end]).into_owned())
+}
+
+pub fn parse(bytes: &[u8]) -> Result<ElfImage, String> {
+    if !is_elf(bytes) {
+        return Err("missing ELF magic".to_string());
+    }
+    if bytes.get(4) != Some(&ELFCLASS32) || bytes.get(5) != Some(&ELFDATA2LSB) {
+        return Err("only little endian ELF32 images are supported".to_string());
+    }
+
+    let entry = read_u32(bytes, 24)?;
+    let phoff = read_u32(bytes, 28)? as usize;
+    let shoff = read_u32(bytes, 32)? as usize;
+    let phentsize = read_u16(bytes, 42)? as usize;
+    let phnum = read_u16(bytes, 44)? as usize;
+    let shentsize = read_u16(bytes, 46)? as usize;
+    let shnum = read_u16(bytes, 48)? as usize;
+
+    if entry > 0xFFFF {
+        return Err(format!(
+            "entry point 0x{:x} is outside the 64K space",
+            entry
+        ));
+    }
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let ph = phoff + i * phentsize;
+        if read_u32(bytes, ph)? != PT_LOAD {
+            continue;
+        }
+        let offset = read_u32(bytes, ph + 4)? as usize;
+        let vaddr = read_u32(bytes, ph + 8)? as usize;
+        let filesz = read_u32(bytes, ph + 16)? as usize;
+        let memsz = read_u32(bytes, ph + 20)? as usize;
+        if memsz == 0 {
+            continue;
+        }
+        if vaddr + memsz > 0x10000 {
+            return Err(format!(
+                "segment at 0x{:x} (0x{:x} bytes) does not fit in 64K",
+                vaddr, memsz
+            ));
+        }
+        let mut data = bytes
+            .get(offset..offset + filesz)
+            .ok_or(format!("segment data at 0x{:x} is truncated", offset))?
+            .to_vec();
+        // .bss style tails are zero filled
+        data.resize(memsz, 0);
+        segments.push(Segment {
+            addr: vaddr as u16,
+            data,
+        });
+    }
+
+    let mut symbols = Vec::new();
+    for i in 0..shnum {
+        let sh = shoff + i * shentsize;
+        if read_u32(bytes, sh + 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let sym_offset = read_u32(bytes, sh + 16)? as usize;
+        let sym_size = read_u32(bytes, sh + 20)? as usize;
+        let strtab_index = read_u32(bytes, sh + 24)? as usize;
+        let entsize = read_u32(bytes, sh + 36)? as usize;
+        let strtab_sh = shoff + strtab_index * shentsize;
+        let strtab_offset = read_u32(bytes, strtab_sh + 16)? as usize;
+        if entsize == 0 {
+            continue;
+        }
+
+        for j in 0..sym_size / entsize {
+            let sym = sym_offset + j * entsize;
+            let name_offset = read_u32(bytes, sym)? as usize;
+            let value = read_u32(bytes, sym + 4)?;
+            let info = *bytes.get(sym + 12).ok_or("symbol table is truncated")?;
+            let shndx = read_u16(bytes, sym + 14)?;
+            let sym_type = info & 0xf;
+            if shndx == SHN_UNDEF
+                || value > 0xFFFF
+                || !matches!(sym_type, STT_NOTYPE | STT_OBJECT | STT_FUNC)
+            {
+                continue;
+            }
+            let name = read_cstr(bytes, strtab_offset + name_offset)?;
+            if name.is_empty() {
+                continue;
+            }
+            symbols.push((name, value as u16));
+        }
+    }
+
+    Ok(ElfImage {
+        entry: entry as u16,
+        segments,
+        symbols,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::elf::{is_elf, parse};
+
+    fn push_u16(out: &mut Vec<u8>, val: u16) {
+        out.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn push_u32(out: &mut Vec<u8>, val: u32) {
+        out.extend_from_slice(&val.to_le_bytes());
+    }
+
+    // Hand built image: one PT_LOAD at $0600 and a .symtab with `main`
+    fn build_test_elf() -> Vec<u8> {
+        let code = [0xa9, 0x41, 0x8d, 0x00, 0xfe, 0x00];
+        let strtab = b"\0main\0";
+        let phoff = 52;
+        let code_off = phoff + 32;
+        let symtab_off = code_off + code.len();
+        let strtab_off = symtab_off + 32;
+        let shoff = strtab_off + strtab.len();
+
+        let mut out = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0];
+        out.resize(16, 0);
+        push_u16(&mut out, 2); // ET_EXEC
+        push_u16(&mut out, 6502); // EM_MOS
+        push_u32(&mut out, 1);
+        push_u32(&mut out, 0x0600); // entry
+        push_u32(&mut out, phoff as u32);
+        push_u32(&mut out, shoff as u32);
+        push_u32(&mut out, 0);
+        push_u16(&mut out, 52);
+        push_u16(&mut out, 32);
+        push_u16(&mut out, 1);
+        push_u16(&mut out, 40);
+        push_u16(&mut out, 3);
+        push_u16(&mut out, 0);
+
+        // program header
+        for val in [1, code_off as u32, 0x0600, 0x0600, 6, 8, 5, 1] {
+            push_u32(&mut out, val);
+        }
+        out.extend_from_slice(&code);
+
+        // null symbol, then `main`
+        out.resize(out.len() + 16, 0);
+        push_u32(&mut out, 1);
+        push_u32(&mut out, 0x0600);
+        push_u32(&mut out, 6);
+        out.extend_from_slice(&[0x12, 0]);
+        push_u16(&mut out, 1);
+        out.extend_from_slice(strtab);
+
+        // null section, .symtab, .strtab
+        out.resize(out.len() + 40, 0);
+        for val in [0, 2, 0, 0, symtab_off as u32, 32, 2, 1, 4, 16] {
+            push_u32(&mut out, val);
+        }
+        for val in [
+            0,
+            3,
+            0,
+            0,
+            strtab_off as u32,
+            strtab.len() as u32,
+            0,
+            0,
+            1,
+            0,
+        ] {
+            push_u32(&mut out, val);
+        }
+        out
+    }
+
+    #[test]
+    fn test_parse_elf_segments_and_entry() {
+        let image = parse(&build_test_elf()).expect("test image should parse");
+        assert_eq!(image.entry, 0x0600);
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].addr, 0x0600);
+        // memsz is larger than filesz so the tail is zero filled
+        assert_eq!(
+            image.segments[0].data,
+            vec![0xa9, 0x41, 0x8d, 0x00, 0xfe, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_parse_elf_symbols() {
+        let image = parse(&build_test_elf()).expect("test image should parse");
+        assert_eq!(image.symbols, vec![("main".to_string(), 0x0600)]);
+    }
+
+    #[test]
+    fn test_raw_image_is_not_elf() {
+        assert!(!is_elf(&[0xa9, 0x00, 0x8d, 0x00]));
+        assert!(parse(&[0u8; 64]).is_err());
+    }
+}
@@ -0,0 +1,301 @@
+// IPS and BPS patch application. Patch offsets are image offsets, which for
+// our flat 64K images are the same thing as addresses.
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+// Applies an IPS or BPS patch in place, returning the (start, len) ranges
+// that were written
+pub fn apply(patch: &[u8], image: &mut [u8]) -> Result<Vec<(usize, usize)>, String> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(patch, image)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(patch, image)
+    } else {
+        Err("not an IPS or BPS patch".to_string())
+    }
+}
+
+fn take<'a>(patch: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], String> {
+    let bytes = pos
+        .checked_add(len)
+        .and_then(|end| patch.get(*pos..end))
+        .ok_or(format!("patch is truncated at offset 0x{:x}", *pos))?;
+    *pos += len;
+    Ok(bytes)
+}
+
+fn write_range(image: &mut [u8], offset: usize, data: &[u8]) -> Result<(), String> {
+    image
+        .get_mut(offset..offset + data.len())
+        .ok_or(format!(
+            "patch writes 0x{:x} bytes at 0x{:x}, past the end of the image",
+            data.len(),
+            offset
+        ))?
+        .copy_from_slice(data);
+    Ok(())
+}
+
+fn apply_ips(patch: &[u8], image: &mut [u8]) -> Result<Vec<(usize, usize)>, String> {
+    let mut pos = IPS_MAGIC.len();
+    let mut ranges = Vec::new();
+    loop {
+        let header = take(patch, &mut pos, 3)?;
+        // a trailing 3 byte truncate length may follow EOF, there is nothing
+        // to truncate in a fixed 64K memory so it is ignored
+        if header == IPS_EOF {
+            break;
+        }
+        let offset = (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize;
+        let size = take(patch, &mut pos, 2)?;
+        let size = (size[0] as usize) << 8 | size[1] as usize;
+        if size == 0 {
+            // RLE record, a 16 bit run length followed by the fill byte
+            let run = take(patch, &mut pos, 3)?;
+            let run_len = (run[0] as usize) << 8 | run[1] as usize;
+            write_range(image, offset, &vec![run[2]; run_len])?;
+            ranges.push((offset, run_len));
+        } else {
+            write_range(image, offset, take(patch, &mut pos, size)?)?;
+            ranges.push((offset, size));
+        }
+    }
+    Ok(ranges)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn read_varint(patch: &[u8], pos: &mut usize) -> Result<usize, String> {
+    let mut data = 0usize;
+    let mut shift = 1usize;
+    loop {
+        let x = take(patch, pos, 1)?[0] as usize;
+        let bad = || "bad number in patch".to_string();
+        data = (x & 0x7f)
+            .checked_mul(shift)
+            .and_then(|part| data.checked_add(part))
+            .ok_or_else(bad)?;
+        if x & 0x80 != 0 {
+            return Ok(data);
+        }
+        shift = shift.checked_mul(0x80).ok_or_else(bad)?;
+        data = data.checked_add(shift).ok_or_else(bad)?;
+    }
+}
+
+fn read_signed_offset(patch: &[u8], pos: &mut usize) -> Result<isize, String> {
+    let data = read_varint(patch, pos)?;
+    let magnitude = (data >> 1) as isize;
+    Ok(if data & 1 != 0 { -magnitude } else { magnitude })
+}
+
+fn apply_bps(patch: &[u8], image: &mut [u8]) -> Result<Vec<(usize, usize)>, String> {
+    if patch.len() < BPS_MAGIC.len() + 12 {
+        return Err("patch is truncated".to_string());
+    }
+    let footer = patch.len() - 12;
+    let read_crc = |at: usize| u32::from_le_bytes(patch[at..at + 4].try_into().unwrap());
+    if crc32(&patch[..footer + 8]) != read_crc(footer + 8) {
+        return Err("patch checksum mismatch, the file is corrupt".to_string());
+    }
+
+    // the actions must not read into the checksums at the end
+    let patch = &patch[..footer];
+    let mut pos = BPS_MAGIC.len();
+    let source_size = read_varint(patch, &mut pos)?;
+    let target_size = read_varint(patch, &mut pos)?;
+    let metadata_size = read_varint(patch, &mut pos)?;
+    pos = pos
+        .checked_add(metadata_size)
+        .filter(|&end| end <= footer)
+        .ok_or("patch metadata runs past the end of the patch")?;
+    if source_size > image.len() || target_size > image.len() {
+        return Err(format!(
+            "patch expects a 0x{:x} byte source and 0x{:x} byte target, image is 0x{:x} bytes",
+            source_size,
+            target_size,
+            image.len()
+        ));
+    }
+    let source = image[..source_size].to_vec();
+    if crc32(&source) != read_crc(footer) {
+        return Err("source checksum mismatch, patch is for a different image".to_string());
+    }
+
+    let mut target = vec![0u8; target_size];
+    let mut out = 0usize;
+    let mut source_rel = 0isize;
+    let mut target_rel = 0isize;
+    while pos < footer {
+        let data = read_varint(patch, &mut pos)?;
+        let len = (data >> 2) + 1;
+        if out + len > target_size {
+            return Err("patch writes past the end of the target".to_string());
+        }
+        match data & 3 {
+            // SourceRead
+            0 => {
+                let bytes = source
+                    .get(out..out + len)
+                    .ok_or("source read past the end of the source")?;
+                target[out..out + len].copy_from_slice(bytes);
+            }
+            // TargetRead
+            1 => target[out..out + len].copy_from_slice(take(patch, &mut pos, len)?),
+            // SourceCopy
+            2 => {
+                source_rel = source_rel
+                    .checked_add(read_signed_offset(patch, &mut pos)?)
+                    .ok_or("source copy offset out of range")?;
+                let start = usize::try_from(source_rel).map_err(|_| "negative source copy")?;
+                let bytes = source
+                    .get(start..start + len)
+                    .ok_or("source copy past the end of the source")?;
+                target[out..out + len].copy_from_slice(bytes);
+                source_rel += len as isize;
+            }
+            // TargetCopy, may overlap the bytes being written so go one at a time
+            _ => {
+                target_rel = target_rel
+                    .checked_add(read_signed_offset(patch, &mut pos)?)
+                    .ok_or("target copy offset out of range")?;
+                for i in 0..len {
+                    let from = usize::try_from(target_rel).map_err(|_| "negative target copy")?;
+                    if from >= out + i {
+                        return Err("target copy reads unwritten bytes".to_string());
+                    }
+                    target[out + i] = target[from];
+                    target_rel += 1;
+                }
+            }
+        }
+        out += len;
+    }
+    if crc32(&target) != read_crc(footer + 4) {
+        return Err("target checksum mismatch after patching".to_string());
+    }
+
+    // report runs of bytes that actually changed
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for (addr, &byte) in target.iter().enumerate() {
+        if image[addr] != byte {
+            match ranges.last_mut() {
+                Some((start, len)) if *start + *len == addr => *len += 1,
+                _ => ranges.push((addr, 1)),
+            }
+        }
+    }
+    image[..target_size].copy_from_slice(&target);
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::patch::{apply, crc32, read_varint};
+
+    #[test]
+    fn test_ips_records_and_rle() {
+        let mut image = vec![0u8; 0x100];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x02, 0xa9, 0x41]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x04, 0xea]);
+        patch.extend_from_slice(b"EOF");
+        let ranges = apply(&patch, &mut image).expect("patch should apply");
+        assert_eq!(ranges, vec![(0x10, 2), (0x20, 4)]);
+        assert_eq!(&image[0x10..0x12], &[0xa9, 0x41]);
+        assert_eq!(&image[0x20..0x25], &[0xea, 0xea, 0xea, 0xea, 0x00]);
+    }
+
+    #[test]
+    fn test_ips_out_of_range() {
+        let mut image = vec![0u8; 0x10];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x0f, 0x00, 0x02, 0x01, 0x02]);
+        patch.extend_from_slice(b"EOF");
+        assert!(apply(&patch, &mut image).is_err());
+        assert!(apply(b"nope", &mut image).is_err());
+    }
+
+    fn push_varint(out: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let x = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(0x80 | x);
+                break;
+            }
+            out.push(x);
+            value -= 1;
+        }
+    }
+
+    #[test]
+    fn test_bps_patch() {
+        let source = b"ABCDABCD".to_vec();
+        let target = b"ABXDABXD".to_vec();
+        let mut patch = b"BPS1".to_vec();
+        push_varint(&mut patch, source.len());
+        push_varint(&mut patch, target.len());
+        push_varint(&mut patch, 0);
+        push_varint(&mut patch, 1 << 2); // SourceRead "AB"
+        push_varint(&mut patch, 1); // TargetRead "X"
+        patch.push(b'X');
+        push_varint(&mut patch, 0); // SourceRead "D"
+        push_varint(&mut patch, (3 << 2) | 3); // TargetCopy "ABXD" from 0
+        push_varint(&mut patch, 0);
+        patch.extend_from_slice(&crc32(&source).to_le_bytes());
+        patch.extend_from_slice(&crc32(&target).to_le_bytes());
+        let patch_crc = crc32(&patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+
+        let mut image = source.clone();
+        let ranges = apply(&patch, &mut image).expect("patch should apply");
+        assert_eq!(image, target);
+        assert_eq!(ranges, vec![(2, 1), (6, 1)]);
+
+        // applying again fails the source checksum
+        assert!(apply(&patch, &mut image).is_err());
+    }
+
+    #[test]
+    fn test_bps_number_overflow() {
+        let mut patch = vec![0x7f; 12];
+        patch.push(0x80);
+        let mut pos = 0;
+        assert_eq!(
+            read_varint(&patch, &mut pos),
+            Err("bad number in patch".to_string())
+        );
+    }
+
+    #[test]
+    fn test_bps_oversized_metadata() {
+        let source = b"ABCD".to_vec();
+        let mut patch = b"BPS1".to_vec();
+        push_varint(&mut patch, source.len());
+        push_varint(&mut patch, source.len());
+        push_varint(&mut patch, usize::MAX - 2);
+        patch.extend_from_slice(&crc32(&source).to_le_bytes());
+        patch.extend_from_slice(&crc32(&source).to_le_bytes());
+        let patch_crc = crc32(&patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+
+        let mut image = source.clone();
+        assert_eq!(
+            apply(&patch, &mut image),
+            Err("patch metadata runs past the end of the patch".to_string())
+        );
+    }
+}
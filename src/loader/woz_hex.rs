@@ -0,0 +1,239 @@
+// Text hex listings as traded for the Apple-1. The Woz Monitor format is
+//
+//   0300: A9 00 8D 00 FE
+//   : 4C 00 03
+//   300R
+//
+// where a bare `:` continues at the next address and `R` marks the run
+// address. The common `addr: bytes` dumps are accepted as well: our own
+// memory dump (`0x0300: a9 00 ... ascii`), xxd (`00000300: a900 8d00`) and
+// hexdump -C (`00000300  a9 00 8d  |...|`).
+use crate::loader::Segment;
+
+const BYTES_PER_LINE: usize = 8;
+
+pub struct HexListing {
+    pub segments: Vec<Segment>,
+    pub run: Option<u16>,
+}
+
+fn parse_addr(text: &str) -> Option<u16> {
+    let text = text.strip_prefix("0x").unwrap_or(text);
+    if text.is_empty() || text.len() > 8 {
+        return None;
+    }
+    u32::from_str_radix(text, 16)
+        .ok()
+        .filter(|&addr| addr <= 0xFFFF)
+        .map(|addr| addr as u16)
+}
+
+// Hex byte groups like `a9` or xxd's `a900`, None once the ascii column starts
+fn parse_byte_group(token: &str) -> Option<Vec<u8>> {
+    if token.is_empty() || token.len() % 2 != 0 || !token.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..token.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&token[i..i + 2], 16).ok())
+        .collect()
+}
+
+pub fn is_hex_listing(bytes: &[u8]) -> bool {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => {
+            !text.trim().is_empty()
+                && text
+                    .chars()
+                    .all(|c| !c.is_control() || c.is_ascii_whitespace())
+                && parse(text).is_ok()
+        }
+        Err(_) => false,
+    }
+}
+
+// A line's address, None for a `:` continuation, and every hex group on it
+struct Row {
+    line_no: usize,
+    addr: Option<u32>,
+    data: Vec<u8>,
+}
+
+pub fn parse(text: &str) -> Result<HexListing, String> {
+    let mut rows: Vec<Row> = Vec::new();
+    let mut run = None;
+
+    for (line_no, line) in text.lines().enumerate() {
+        let err = |msg: String| format!("line {}: {}", line_no + 1, msg);
+        // drop hexdump -C's |ascii| column and comments
+        let line = line.split('|').next().unwrap_or("");
+        let line = line.split(';').next().unwrap_or("").trim();
+        if line.is_empty() || line == "*" || line.starts_with('#') || line.starts_with("Memory:") {
+            continue;
+        }
+
+        // `300R` / `0300 R` runs the program
+        let upper = line.to_ascii_uppercase();
+        if let Some(addr) = upper.strip_suffix('R') {
+            if let Some(addr) = parse_addr(addr.trim()) {
+                run = Some(addr);
+                continue;
+            }
+        }
+
+        let (addr, rest) = match line.split_once(':') {
+            Some(("", rest)) => (None, rest),
+            Some((addr, rest)) => {
+                let addr =
+                    parse_addr(addr.trim()).ok_or(err(format!("invalid address '{}'", addr)))?;
+                (Some(addr as u32), rest)
+            }
+            None => {
+                // hexdump -C style, address followed by whitespace
+                let (addr, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+                let addr = parse_addr(addr).ok_or(err(format!("unrecognised line '{}'", line)))?;
+                (Some(addr as u32), rest)
+            }
+        };
+
+        let mut data = Vec::new();
+        for token in rest.split_whitespace() {
+            match parse_byte_group(token) {
+                Some(bytes) => data.extend(bytes),
+                None => break,
+            }
+        }
+        rows.push(Row {
+            line_no,
+            addr,
+            data,
+        });
+    }
+
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut next_addr: Option<u32> = None;
+    // bytes per row of a dump, from the step between addressed rows
+    let mut width: Option<u32> = None;
+    for (i, row) in rows.iter().enumerate() {
+        let err = |msg: &str| format!("line {}: {}", row.line_no + 1, msg);
+        let addr = match row.addr {
+            Some(addr) => addr,
+            None => next_addr.ok_or(err("continuation line without an address"))?,
+        };
+        let mut data = row.data.clone();
+        // an ascii column of hex letters, eg `ABCD`, would read as more bytes,
+        // so a row holds no more than the address step to the next row
+        if row.addr.is_some() {
+            let step = rows
+                .get(i + 1)
+                .and_then(|next| next.addr)
+                .filter(|&next| next > addr)
+                .map(|next| next - addr);
+            if step.is_some() {
+                width = step;
+            }
+            if let Some(width) = step.or(width) {
+                data.truncate(width as usize);
+            }
+        }
+        if addr as usize + data.len() > 0x10000 {
+            return Err(err("data runs past $FFFF"));
+        }
+        next_addr = Some(addr + data.len() as u32);
+        if data.is_empty() {
+            continue;
+        }
+
+        // merge runs of consecutive lines into one segment
+        match segments.last_mut() {
+            Some(last) if last.addr as usize + last.data.len() == addr as usize => {
+                last.data.extend(data)
+            }
+            _ => segments.push(Segment {
+                addr: addr as u16,
+                data,
+            }),
+        }
+    }
+
+    Ok(HexListing { segments, run })
+}
+
+// Writes bytes back out as Woz Monitor lines starting at `start`
+pub fn format(start: u16, bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        let addr = start as usize + i * BYTES_PER_LINE;
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+        out.push_str(&format!("{:04X}: {}\n", addr, hex.join(" ")));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::woz_hex::{format, is_hex_listing, parse};
+
+    #[test]
+    fn test_parse_woz_listing() {
+        let listing = parse("0300: A9 00 8D 00 FE\n: 4C 00 03\n\n0310: EA\n300R\n").unwrap();
+        assert_eq!(listing.run, Some(0x0300));
+        assert_eq!(listing.segments.len(), 2);
+        assert_eq!(listing.segments[0].addr, 0x0300);
+        assert_eq!(
+            listing.segments[0].data,
+            vec![0xa9, 0x00, 0x8d, 0x00, 0xfe, 0x4c, 0x00, 0x03]
+        );
+        assert_eq!(listing.segments[1].addr, 0x0310);
+    }
+
+    #[test]
+    fn test_parse_dump_formats() {
+        // our own dump_memory output
+        let ours = parse(concat!(
+            "Memory: 0  1  2\n",
+            "0x0000: 48 65 6c 6c 6f 2c 20 57 6f 72 6c 64 21 0a 00 00 Hello,.World!...\n",
+            "*\n"
+        ))
+        .unwrap();
+        assert_eq!(ours.segments[0].data.len(), 16);
+        assert_eq!(ours.segments[0].data[0], 0x48);
+
+        let xxd = parse("00000300: a900 8dfe  ....").unwrap();
+        assert_eq!(xxd.segments[0].data, vec![0xa9, 0x00, 0x8d, 0xfe]);
+
+        let hexdump = parse("00000300  a9 00 8d 00  |....|\n").unwrap();
+        assert_eq!(hexdump.segments[0].addr, 0x0300);
+        assert_eq!(hexdump.segments[0].data, vec![0xa9, 0x00, 0x8d, 0x00]);
+    }
+
+    #[test]
+    fn test_hex_looking_ascii_column() {
+        let listing = parse(concat!(
+            "0300: 41 42 43 44 41 42 43 44 ABCDABCD\n",
+            "0308: 45 46 00 00 00 00 00 00 EF......\n",
+            "0310: 41 42 43 44 41 42 43 44 ABCDABCD\n",
+        ))
+        .unwrap();
+        assert_eq!(listing.segments.len(), 1);
+        assert_eq!(listing.segments[0].data.len(), 24);
+        assert_eq!(listing.segments[0].data[8], 0x45);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes: Vec<u8> = (0..20).collect();
+        let text = format(0x0300, &bytes);
+        assert!(text.starts_with("0300: 00 01 02 03 04 05 06 07\n0308: "));
+        let listing = parse(&text).unwrap();
+        assert_eq!(listing.segments[0].addr, 0x0300);
+        assert_eq!(listing.segments[0].data, bytes);
+    }
+
+    #[test]
+    fn test_binary_is_not_a_listing() {
+        assert!(!is_hex_listing(&[0u8; 256]));
+        assert!(!is_hex_listing(b"hello there"));
+        assert!(is_hex_listing(b"0300: A9 00\n"));
+    }
+}
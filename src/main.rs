@@ -1,8 +1,21 @@
 use clap::Parser;
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+use signal_hook::consts::SIGINT;
+use signal_hook::flag;
+use std::sync::Arc;
+mod asm;
+mod coverage;
 pub mod cpu6502;
+mod debug_info;
+mod debugger;
+mod disasm;
+mod loader;
+mod profile;
+mod symbols;
+mod trace;
 
 mod utils {
+    pub mod line_editor;
     pub mod pause;
 }
 
@@ -10,22 +23,76 @@ fn main() {
     let args: cpu6502::Args = cpu6502::Args::parse();
     env_logger::init();
 
+    match &args.command {
+        Some(cpu6502::Command::Disasm(args)) => return disasm::source::run(args),
+        Some(cpu6502::Command::Asm(args)) => {
+            return asm::run(
+                &args.source,
+                args.config.as_deref(),
+                args.output.as_deref(),
+                args.labels.as_deref(),
+                args.dbgfile.as_deref(),
+            )
+        }
+        Some(cpu6502::Command::Repl(args)) => return debugger::repl::run(args),
+        Some(cpu6502::Command::TraceQuery(args)) => return trace::query::run(args),
+        None => {}
+    }
+
+    // stdout carries the protocol, nothing else may print to it
+    if args.dap {
+        debugger::dap::run(args);
+        return;
+    }
+
     println!("Running {}!", args.binary_file);
 
     let mut cpu: cpu6502::Cpu6502 = cpu6502::init_cpu6502(args);
+    // Ctrl+C ends the run instead of the process so the on-exit outputs below
+    // are still written, a second Ctrl+C exits straight away
+    let interrupted = Arc::clone(&cpu.interrupted);
+    flag::register_conditional_shutdown(SIGINT, 130, Arc::clone(&interrupted)).unwrap();
+    flag::register(SIGINT, interrupted).unwrap();
 
     if cpu.cmdline_args.keyboard {
         enable_raw_mode().expect("Failed to enable raw mode.");
     }
 
     cpu.load_file_into_memory();
-    cpu.run();
+    cpu.load_debug_info();
+    let result = cpu.run();
+    cpu.flush_traces();
     if cpu.cmdline_args.keyboard {
         disable_raw_mode().expect("Failed to enable raw mode.");
     }
 
+    cpu.export_hex();
+
+    if let Some(profile) = &cpu.profile {
+        if cpu.cmdline_args.profile {
+            profile.report(&cpu, 10);
+        }
+        if let Some(path) = &cpu.cmdline_args.flamegraph {
+            profile.write_collapsed(path, &cpu.symbols);
+        }
+    }
+    if let Some(coverage) = &cpu.coverage {
+        coverage.export(&cpu);
+        if cpu.cmdline_args.heatmap {
+            coverage::heatmap::render(coverage);
+        }
+        if let Some(path) = &cpu.cmdline_args.heatmap_export {
+            coverage::heatmap::export(coverage, path);
+        }
+    }
+
     if cpu.cmdline_args.dump_state_exit {
         cpu.cmdline_args.no_print = false;
         cpu.print_state();
     }
+
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,316 @@
+use crate::cpu6502::operation::Instruction;
+use crate::cpu6502::{Cpu6502, Interrupt, Registers};
+use crate::symbols::SymbolTable;
+use std::collections::HashMap;
+use std::fs;
+
+// Per address execution counts and cycles, plus time per subroutine worked
+// out from a shadow call stack. A JSR or an interrupt opens a frame that
+// closes once the stack pointer climbs back above what it pushed, so RTS,
+// RTI and code that pulls its return address to return elsewhere all end it.
+pub struct Profile {
+    counts: Vec<u64>,
+    cycles: Vec<u64>,
+    // keyed by entry point, None for code outside any subroutine
+    routines: HashMap<Option<u16>, Routine>,
+    stack: Vec<Frame>,
+    // every call path seen, node 0 is the top level, with the cycles spent
+    // in it and not in a deeper call
+    paths: Vec<Path>,
+    children: HashMap<(usize, Entry), usize>,
+    // where the top level started, to name it
+    start: Option<u16>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Entry {
+    Call(u16),
+    Interrupt(Interrupt, u16),
+}
+
+struct Path {
+    parent: usize,
+    entry: Option<Entry>,
+    cycles: u64,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Routine {
+    calls: u64,
+    inclusive: u64,
+    exclusive: u64,
+}
+
+struct Frame {
+    entry: u16,
+    // SP after the JSR or interrupt pushed its return address
+    sp: u8,
+    start: u64,
+    path: usize,
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile {
+            counts: vec![0; 0x10000],
+            cycles: vec![0; 0x10000],
+            routines: HashMap::new(),
+            stack: Vec::new(),
+            paths: vec![Path {
+                parent: 0,
+                entry: None,
+                cycles: 0,
+            }],
+            children: HashMap::new(),
+            start: None,
+        }
+    }
+}
+
+impl Profile {
+    // After the instruction at pc ran, taking cycles, leaving the CPU in after
+    pub fn instruction(&mut self, pc: u16, kind: Instruction, cycles: u64, after: &Registers) {
+        self.start.get_or_insert(pc);
+        self.counts[pc as usize] += 1;
+        self.cycles[pc as usize] += cycles;
+        self.charge(cycles);
+        if let Instruction::JSR = kind {
+            self.enter(Entry::Call(after.pc), after);
+            return;
+        }
+        while let Some(frame) = self.stack.last() {
+            if after.sp <= frame.sp {
+                break;
+            }
+            let frame = self.stack.pop().unwrap();
+            // recursive calls count once, in the outermost frame
+            if !self.stack.iter().any(|open| open.entry == frame.entry) {
+                let routine = self.routines.entry(Some(frame.entry)).or_default();
+                routine.inclusive += after.cycles - frame.start;
+            }
+        }
+    }
+
+    // After service_interrupt pushed PC and P and jumped to the handler
+    pub fn interrupt(&mut self, interrupt: Interrupt, cycles: u64, after: &Registers) {
+        self.enter(Entry::Interrupt(interrupt, after.pc), after);
+        self.charge(cycles);
+    }
+
+    fn charge(&mut self, cycles: u64) {
+        let current = self.stack.last();
+        self.routines
+            .entry(current.map(|frame| frame.entry))
+            .or_default()
+            .exclusive += cycles;
+        self.paths[current.map_or(0, |frame| frame.path)].cycles += cycles;
+    }
+
+    fn enter(&mut self, entry: Entry, after: &Registers) {
+        let parent = self.stack.last().map_or(0, |frame| frame.path);
+        let paths = &mut self.paths;
+        let path = *self.children.entry((parent, entry)).or_insert_with(|| {
+            paths.push(Path {
+                parent,
+                entry: Some(entry),
+                cycles: 0,
+            });
+            paths.len() - 1
+        });
+        self.routines.entry(Some(after.pc)).or_default().calls += 1;
+        self.stack.push(Frame {
+            entry: after.pc,
+            sp: after.sp,
+            start: after.cycles,
+            path,
+        });
+    }
+
+    // Collapsed stacks, one `top;caller;callee cycles` line per call path,
+    // the input inferno and flamegraph.pl render
+    pub fn collapsed(&self, symbols: &SymbolTable) -> String {
+        let label = |addr: u16| match symbols.name_at(addr) {
+            Some(name) => name.to_string(),
+            None => format!("${:04x}", addr),
+        };
+        let name = |path: &Path| match path.entry {
+            None => self.start.map_or("top".to_string(), label),
+            Some(Entry::Call(addr)) => label(addr),
+            Some(Entry::Interrupt(Interrupt::Irq, addr)) => format!("irq:{}", label(addr)),
+            Some(Entry::Interrupt(Interrupt::Nmi, addr)) => format!("nmi:{}", label(addr)),
+        };
+        let mut lines = Vec::new();
+        for (index, path) in self.paths.iter().enumerate() {
+            if path.cycles == 0 {
+                continue;
+            }
+            let mut names = vec![name(path)];
+            let mut at = index;
+            while at != 0 {
+                at = self.paths[at].parent;
+                names.push(name(&self.paths[at]));
+            }
+            names.reverse();
+            lines.push(format!("{} {}\n", names.join(";"), path.cycles));
+        }
+        lines.sort();
+        lines.concat()
+    }
+
+    pub fn write_collapsed(&self, path: &str, symbols: &SymbolTable) {
+        if let Err(error) = fs::write(path, self.collapsed(symbols)) {
+            panic!("Problem writing flame graph stacks {}: {:?}", path, error);
+        }
+        println!("Wrote collapsed stacks to {}", path);
+    }
+
+    // Subroutine totals with the frames still open counted up to now
+    fn routines(&self, now: u64) -> Vec<(Option<u16>, Routine)> {
+        let mut routines = self.routines.clone();
+        let mut seen = Vec::new();
+        for frame in &self.stack {
+            if !seen.contains(&frame.entry) {
+                seen.push(frame.entry);
+                let routine = routines.entry(Some(frame.entry)).or_default();
+                routine.inclusive += now - frame.start;
+            }
+        }
+        // everything runs inside the top level
+        let total: u64 = routines.values().map(|routine| routine.exclusive).sum();
+        routines.entry(None).or_default().inclusive = total;
+        routines.into_iter().collect()
+    }
+
+    pub fn report(&self, cpu: &Cpu6502, count: usize) {
+        let total: u64 = self.cycles.iter().sum();
+        let percent = |cycles: u64| cycles as f64 * 100.0 / total.max(1) as f64;
+        println!(
+            "Profile: {} instructions, {} cycles",
+            self.counts.iter().sum::<u64>(),
+            total
+        );
+
+        let mut hot: Vec<usize> = (0..0x10000).filter(|&addr| self.counts[addr] > 0).collect();
+        hot.sort_by_key(|&addr| std::cmp::Reverse(self.cycles[addr]));
+        println!("\nHottest instructions");
+        println!(
+            "{:>10} {:>12} {:>6}  {:<20} instruction",
+            "count", "cycles", "%", "address"
+        );
+        for &addr in hot.iter().take(count) {
+            let addr16 = addr as u16;
+            println!(
+                "{:>10} {:>12} {:>5.1}%  {:<20} {}",
+                self.counts[addr],
+                self.cycles[addr],
+                percent(self.cycles[addr]),
+                locate(&cpu.symbols, addr16),
+                cpu.disassemble(addr16).text(Some(&cpu.symbols))
+            );
+        }
+
+        let mut routines = self.routines(cpu.cycles);
+        routines.sort_by_key(|(_, routine)| std::cmp::Reverse(routine.inclusive));
+        println!("\nHottest subroutines");
+        println!(
+            "{:>10} {:>12} {:>6} {:>12} {:>6}  name",
+            "calls", "inclusive", "%", "exclusive", "%"
+        );
+        for (entry, routine) in routines.iter().take(count + 1) {
+            let name = match entry {
+                Some(entry) => cpu.symbolic(*entry, 4),
+                None => "[top level]".to_string(),
+            };
+            println!(
+                "{:>10} {:>12} {:>5.1}% {:>12} {:>5.1}%  {}",
+                routine.calls,
+                routine.inclusive,
+                percent(routine.inclusive),
+                routine.exclusive,
+                percent(routine.exclusive),
+                name
+            );
+        }
+    }
+}
+
+// `$061b print+10`, the address and the nearest label at or before it
+// within a page, so zero page variables don't name the code after them
+fn locate(symbols: &SymbolTable, addr: u16) -> String {
+    let start = addr.saturating_sub(0xff);
+    match symbols.in_range(start, addr as u32 + 1).last() {
+        Some((at, name)) if at == addr => format!("${:04x} {}", addr, name),
+        Some((at, name)) => format!("${:04x} {}+{}", addr, name, addr - at),
+        None => format!("${:04x}", addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu6502::{cpu_with_program, Interrupt};
+
+    #[test]
+    fn test_subroutine_times() {
+        let program = [
+            0x20, 0x06, 0x06, // 0600 JSR outer
+            0x4c, 0x00, 0x06, // 0603 JMP $0600
+            0x20, 0x0a, 0x06, // 0606 outer: JSR inner
+            0x60, // 0609 RTS
+            0xea, // 060a inner: NOP
+            0x60, // 060b RTS
+        ];
+        let mut cpu = cpu_with_program(&["--profile"], 0x0600, &program);
+        // two rounds of JSR, JSR, NOP, RTS, RTS, JMP
+        for _ in 0..12 {
+            cpu.step();
+        }
+        let profile = cpu.profile.as_ref().unwrap();
+        assert_eq!(profile.counts[0x060a], 2);
+        assert_eq!(profile.cycles[0x0600], 12);
+        assert!(profile.stack.is_empty());
+
+        let routines = profile.routines(cpu.cycles);
+        let routine = |entry| routines.iter().find(|(at, _)| *at == entry).unwrap().1;
+        // inner: NOP 2 + RTS 6
+        assert_eq!(routine(Some(0x060a)).calls, 2);
+        assert_eq!(routine(Some(0x060a)).inclusive, 2 * 8);
+        assert_eq!(routine(Some(0x060a)).exclusive, 2 * 8);
+        // outer: JSR 6 + RTS 6 of its own, plus inner
+        assert_eq!(routine(Some(0x0606)).inclusive, 2 * (12 + 8));
+        assert_eq!(routine(Some(0x0606)).exclusive, 2 * 12);
+        assert_eq!(routine(None).exclusive, 2 * (6 + 3));
+        assert_eq!(routine(None).inclusive, cpu.cycles);
+    }
+
+    #[test]
+    fn test_collapsed_stacks() {
+        let program = [
+            0x20, 0x05, 0x06, // 0600 main: JSR print
+            0xd0, 0xfb, // 0603 BNE main
+            0xea, // 0605 print: NOP
+            0x60, // 0606 RTS
+        ];
+        let mut cpu = cpu_with_program(&["--profile"], 0x0600, &program);
+        cpu.set_byte_wrap(0x0700, 0x40); // handler: RTI
+        cpu.set_byte_wrap(0xfffe, 0x00);
+        cpu.set_byte_wrap(0xffff, 0x07);
+        cpu.symbols.insert("main".to_string(), 0x0600);
+        cpu.symbols.insert("print".to_string(), 0x0605);
+        cpu.symbols.insert("handler".to_string(), 0x0700);
+        cpu.status_flags.z = false;
+
+        cpu.step(); // JSR
+        cpu.pending_interrupt = Some(Interrupt::Irq);
+        cpu.service_interrupt();
+        cpu.step(); // RTI
+        cpu.step(); // NOP
+        cpu.step(); // RTS
+        cpu.step(); // BNE
+
+        let collapsed = cpu.profile.as_ref().unwrap().collapsed(&cpu.symbols);
+        assert_eq!(
+            collapsed,
+            "main 9\nmain;print 8\nmain;print;irq:handler 13\n"
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, HashMap};
+
+// Address -> name lookup for labels imported from object files, so that
+// step output can show `JSR print` instead of a bare address.
+#[derive(Default)]
+pub struct SymbolTable {
+    by_addr: BTreeMap<u16, String>,
+    by_name: HashMap<String, u16>,
+}
+
+impl SymbolTable {
+    pub fn insert(&mut self, name: String, addr: u16) {
+        // first definition wins, later aliases for the same address are dropped
+        self.by_addr.entry(addr).or_insert(name.clone());
+        self.by_name.entry(name).or_insert(addr);
+    }
+
+    pub fn name_at(&self, addr: u16) -> Option<&str> {
+        self.by_addr.get(&addr).map(|name| name.as_str())
+    }
+
+    pub fn addr_of(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    // Symbols defined in [start, end)
+    pub fn in_range(&self, start: u16, end: u32) -> impl Iterator<Item = (u16, &str)> {
+        self.by_addr
+            .range(start..)
+            .take_while(move |(&addr, _)| (addr as u32) < end)
+            .map(|(&addr, name)| (addr, name.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_addr.len()
+    }
+}
+
+// An address as $hex, 0xhex, decimal or a symbol from names
+pub fn parse_addr(text: &str, names: &SymbolTable) -> Result<u16, String> {
+    let hex = text.strip_prefix('$').or_else(|| text.strip_prefix("0x"));
+    let addr = match hex {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse::<u16>().ok().or_else(|| names.addr_of(text)),
+    };
+    addr.ok_or(format!("invalid address '{}'", text))
+}
+
+// Reads a label file straight into a table, for the tools that have no CPU
+pub fn load_label_file(path: &str) -> Result<SymbolTable, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut names = SymbolTable::default();
+    for (name, addr) in parse_label_file(&text)? {
+        names.insert(name, addr);
+    }
+    Ok(names)
+}
+
+// Parses ld65 `-Ln` / VICE label files, one `al C:0621 .print` per line.
+// ld65 writes the address without the `C:` bank prefix, eg `al 000621 .print`.
+pub fn parse_label_file(text: &str) -> Result<Vec<(String, u16)>, String> {
+    let mut labels = Vec::new();
+    for (line_no, line) in text.lines().enumerate() {
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            Some("al") => {}
+            // VICE monitor files can carry other commands, only labels matter
+            _ => continue,
+        }
+        let (addr, name) = match (parts.next(), parts.next()) {
+            (Some(addr), Some(name)) => (addr, name),
+            _ => {
+                return Err(format!(
+                    "line {}: expected 'al <addr> .<name>'",
+                    line_no + 1
+                ))
+            }
+        };
+        let addr = addr.rsplit(':').next().unwrap_or(addr);
+        let addr = u32::from_str_radix(addr, 16)
+            .ok()
+            .filter(|&addr| addr <= 0xFFFF)
+            .ok_or(format!("line {}: invalid address '{}'", line_no + 1, addr))?;
+        let name = name.strip_prefix('.').unwrap_or(name);
+        labels.push((name.to_string(), addr as u16));
+    }
+    Ok(labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::symbols::{parse_label_file, SymbolTable};
+
+    #[test]
+    fn test_parse_label_file_formats() {
+        let text = "al 000621 .print\nal C:FF00 .RESET\n\nbreak 0600\nal 00000F .index\n";
+        let labels = parse_label_file(text).expect("label file should parse");
+        assert_eq!(
+            labels,
+            vec![
+                ("print".to_string(), 0x0621),
+                ("RESET".to_string(), 0xff00),
+                ("index".to_string(), 0x000f)
+            ]
+        );
+        assert!(parse_label_file("al zz .bad").is_err());
+        assert!(parse_label_file("al 10000 .too_big").is_err());
+    }
+
+    #[test]
+    fn test_symbol_table_lookup() {
+        let mut symbols = SymbolTable::default();
+        symbols.insert("print".to_string(), 0x0621);
+        symbols.insert("alias".to_string(), 0x0621);
+        symbols.insert("done".to_string(), 0x0630);
+        assert_eq!(symbols.name_at(0x0621), Some("print"));
+        assert_eq!(symbols.addr_of("alias"), Some(0x0621));
+        let names: Vec<&str> = symbols.in_range(0x0620, 0x0630).map(|(_, n)| n).collect();
+        assert_eq!(names, vec!["print"]);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::cpu6502::operation::{AddressingMode, Instruction};
+use crate::cpu6502::Cpu6502;
+use crate::disasm::Disassembled;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+pub mod query;
+pub mod record;
+
+// Text trace of every executed instruction in the column layout of
+// nestest.log, so runs can be compared with reference traces using diff:
+//
+// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7
+//
+// There is no PPU here, so its column is left out.
+pub struct Trace {
+    out: BufWriter<File>,
+}
+
+impl Trace {
+    pub fn create(path: &str) -> Trace {
+        match File::create(path) {
+            Ok(file) => Trace {
+                out: BufWriter::new(file),
+            },
+            Err(error) => panic!("Problem creating trace {}: {:?}", path, error),
+        }
+    }
+
+    // Called before the instruction at PC runs
+    pub fn record(&mut self, cpu: &Cpu6502) {
+        if let Err(error) = writeln!(self.out, "{}", line(cpu)) {
+            panic!("Problem writing trace: {:?}", error);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(error) = self.out.flush() {
+            panic!("Problem writing trace: {:?}", error);
+        }
+    }
+}
+
+// The trace line for the instruction at PC with the registers before it runs
+pub fn line(cpu: &Cpu6502) -> String {
+    let pc = cpu.program_counter;
+    let decoded = cpu.disassemble(pc);
+    let bytes: Vec<String> = decoded.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    let operand = operand(cpu, &decoded);
+    let text = if operand.is_empty() {
+        decoded.mnemonic()
+    } else {
+        format!("{} {}", decoded.mnemonic(), operand)
+    };
+    format!(
+        "{:04X}  {:<8}  {:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+        pc,
+        bytes.join(" "),
+        text,
+        cpu.accumulator,
+        cpu.x_index,
+        cpu.y_index,
+        cpu.status_flags.as_u8(),
+        cpu.stack_pointer,
+        cpu.cycles
+    )
+}
+
+// Operands as nestest prints them, with the effective address and the value
+// there worked out the way a 6502 would: `$0300,X @ 0302 = 5A`,
+// `($80,X) @ 82 = 0200 = 5A`, `($89),Y = 0300 @ 0302 = 5A`
+fn operand(cpu: &Cpu6502, decoded: &Disassembled) -> String {
+    let op = match decoded.op {
+        Some(op) => op,
+        None => return format!("${:02X}", decoded.bytes[0]),
+    };
+    let byte = |addr: u16| cpu.memory.get_byte(addr as usize);
+    // pointers in the zero page wrap within it
+    let zp_word =
+        |addr: u8| (byte(addr.wrapping_add(1) as u16) as u16) << 8 | byte(addr as u16) as u16;
+    let target = decoded.target().unwrap_or(0);
+    let lo = decoded.bytes.get(1).copied().unwrap_or(0);
+    let jump = matches!(op.instruction_type, Instruction::JMP | Instruction::JSR);
+    match op.mode {
+        AddressingMode::Accumulator => "A".to_string(),
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Immediate => format!("#${:02X}", lo),
+        AddressingMode::Relative => format!("${:04X}", target),
+        AddressingMode::Absolute if jump => format!("${:04X}", target),
+        AddressingMode::Absolute => format!("${:04X} = {:02X}", target, byte(target)),
+        AddressingMode::AbsoluteXIndexed | AddressingMode::AbsoluteYIndexed => {
+            let (index, name) = match op.mode {
+                AddressingMode::AbsoluteXIndexed => (cpu.x_index, "X"),
+                _ => (cpu.y_index, "Y"),
+            };
+            let addr = target.wrapping_add(index as u16);
+            format!(
+                "${:04X},{} @ {:04X} = {:02X}",
+                target,
+                name,
+                addr,
+                byte(addr)
+            )
+        }
+        AddressingMode::AbsoluteIndirect => {
+            // the 6502 does not carry into the high byte of the pointer
+            let high = (target & 0xFF00) | (target.wrapping_add(1) & 0x00FF);
+            let addr = (byte(high) as u16) << 8 | byte(target) as u16;
+            format!("(${:04X}) = {:04X}", target, addr)
+        }
+        AddressingMode::AbsoluteIndirectX => {
+            let ptr = target.wrapping_add(cpu.x_index as u16);
+            let addr = (byte(ptr.wrapping_add(1)) as u16) << 8 | byte(ptr) as u16;
+            format!("(${:04X},X) @ {:04X} = {:04X}", target, ptr, addr)
+        }
+        AddressingMode::AbsoluteIndirectY => {
+            let addr = (byte(target.wrapping_add(1)) as u16) << 8 | byte(target) as u16;
+            format!("(${:04X}),Y = {:04X}", target, addr)
+        }
+        AddressingMode::ZeroPage => format!("${:02X} = {:02X}", lo, byte(lo as u16)),
+        AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+            let (index, name) = match op.mode {
+                AddressingMode::ZeroPageX => (cpu.x_index, "X"),
+                _ => (cpu.y_index, "Y"),
+            };
+            let addr = lo.wrapping_add(index);
+            format!(
+                "${:02X},{} @ {:02X} = {:02X}",
+                lo,
+                name,
+                addr,
+                byte(addr as u16)
+            )
+        }
+        AddressingMode::ZeroPageIndirectIndexedX => {
+            let ptr = lo.wrapping_add(cpu.x_index);
+            let addr = zp_word(ptr);
+            format!(
+                "(${:02X},X) @ {:02X} = {:04X} = {:02X}",
+                lo,
+                ptr,
+                addr,
+                byte(addr)
+            )
+        }
+        AddressingMode::ZeroPageIndirectIndexedY => {
+            let base = zp_word(lo);
+            let addr = base.wrapping_add(cpu.y_index as u16);
+            format!(
+                "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
+                lo,
+                base,
+                addr,
+                byte(addr)
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu6502::cpu_with_program;
+    use crate::trace::line;
+
+    #[test]
+    fn test_nestest_columns() {
+        let program = [
+            0x4c, 0xf5, 0xc5, // JMP $C5F5
+            0xa2, 0x00, // LDX #$00
+            0x86, 0x01, // STX $01
+            0xbd, 0x00, 0x03, // LDA $0300,X
+            0xa1, 0x80, // LDA ($80,X)
+            0xb1, 0xff, // LDA ($FF),Y
+            0x6c, 0xff, 0x02, // JMP ($02FF)
+            0x0a, // ASL A
+            0xd0, 0xfe, // BNE *
+        ];
+        let mut cpu = cpu_with_program(&[], 0xc000, &program);
+        cpu.set_byte_wrap(0x0302, 0x5a);
+        cpu.set_byte_wrap(0x0082, 0x00);
+        cpu.set_byte_wrap(0x0083, 0x02);
+        cpu.set_byte_wrap(0x00ff, 0x00);
+        cpu.set_byte_wrap(0x0000, 0x03);
+        cpu.set_byte_wrap(0x02ff, 0x7e);
+        cpu.set_byte_wrap(0x0200, 0xdb);
+        cpu.accumulator = 0x00;
+        cpu.x_index = 0x02;
+        cpu.y_index = 0x04;
+        cpu.stack_pointer = 0xfd;
+        cpu.status_flags.set_from_u8(0x24);
+        cpu.cycles = 7;
+
+        let mut lines = Vec::new();
+        for addr in [
+            0xc000, 0xc003, 0xc005, 0xc007, 0xc00a, 0xc00c, 0xc00e, 0xc011, 0xc012,
+        ] {
+            cpu.program_counter = addr;
+            lines.push(line(&cpu));
+        }
+        let registers = "A:00 X:02 Y:04 P:24 SP:FD CYC:7";
+        let expected = [
+            "C000  4C F5 C5  JMP $C5F5",
+            "C003  A2 00     LDX #$00",
+            "C005  86 01     STX $01 = 00",
+            "C007  BD 00 03  LDA $0300,X @ 0302 = 5A",
+            "C00A  A1 80     LDA ($80,X) @ 82 = 0200 = DB",
+            "C00C  B1 FF     LDA ($FF),Y = 0300 @ 0304 = 00",
+            "C00E  6C FF 02  JMP ($02FF) = DB7E",
+            "C011  0A        ASL A",
+            "C012  D0 FE     BNE $C012",
+        ];
+        for (line, expected) in lines.iter().zip(expected) {
+            assert_eq!(*line, format!("{:<48}{}", expected, registers));
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use crate::cpu6502::{Query, TraceQueryArgs};
+use crate::symbols::{self, parse_addr, SymbolTable};
+use crate::trace::record::{Reader, Step};
+use std::fs::File;
+use std::io::BufReader;
+
+// `$0621` or `$0621 print` when a label is known
+fn describe(addr: u16, names: &SymbolTable) -> String {
+    match names.name_at(addr) {
+        Some(name) => format!("${:04x} {}", addr, name),
+        None => format!("${:04x}", addr),
+    }
+}
+
+fn heading(step: &Step, names: &SymbolTable) -> String {
+    format!(
+        "#{:<10} cycle {:<12} {}",
+        step.instruction,
+        step.cycle,
+        describe(step.pc, names)
+    )
+}
+
+fn answer(
+    query: &Query,
+    steps: impl Iterator<Item = Result<Step, String>>,
+    names: &SymbolTable,
+    start: u16,
+) -> Result<(), String> {
+    let addr = |text: &str| parse_addr(text, names);
+    match query {
+        Query::Summary => {
+            let (mut instructions, mut interrupts, mut calls, mut writes) = (0, 0, 0, 0);
+            let mut end = None;
+            for step in steps {
+                let step = step?;
+                match step.interrupt {
+                    true => interrupts += 1,
+                    false => instructions += 1,
+                }
+                calls += step.call as u64;
+                writes += step.writes.len();
+                end = Some(step);
+            }
+            println!("start:        {}", describe(start, names));
+            println!("instructions: {}", instructions);
+            println!("interrupts:   {}", interrupts);
+            println!("calls:        {}", calls);
+            println!("writes:       {}", writes);
+            if let Some(end) = end {
+                println!("cycles:       {}", end.cycle + end.cycles);
+                println!("last:         {}", heading(&end, names));
+            }
+        }
+        Query::Writes {
+            addr: text,
+            before,
+            last,
+        } => {
+            let target = addr(text)?;
+            let mut found = Vec::new();
+            for step in steps {
+                let step = step?;
+                if before.is_some_and(|before| step.instruction >= before) {
+                    break;
+                }
+                for (at, value) in &step.writes {
+                    if *at == target {
+                        let line = format!("{}  wrote ${:02x}", heading(&step, names), value);
+                        if *last {
+                            found.clear();
+                        }
+                        found.push(line);
+                    }
+                }
+            }
+            if found.is_empty() {
+                println!("{} was not written", describe(target, names));
+            }
+            for line in found {
+                println!("{}", line);
+            }
+        }
+        Query::Calls { addr: text } => {
+            let target = addr(text)?;
+            let mut count = 0;
+            for step in steps {
+                let step = step?;
+                if step.call && step.next_pc == Some(target) {
+                    println!("{}  {}", heading(&step, names), step.before);
+                    count += 1;
+                }
+            }
+            println!("{} calls to {}", count, describe(target, names));
+        }
+        Query::Regs { cycle, instruction } => {
+            for step in steps {
+                let step = step?;
+                let found = match (cycle, instruction) {
+                    (Some(cycle), _) => step.cycle + step.cycles > *cycle,
+                    (_, Some(instruction)) => !step.interrupt && step.instruction == *instruction,
+                    _ => true,
+                };
+                if found {
+                    println!("{}", heading(&step, names));
+                    println!("before: PC=${:04x} {}", step.pc, step.before);
+                    return Ok(());
+                }
+            }
+            return Err("the recording ends before that point".to_string());
+        }
+    }
+    Ok(())
+}
+
+pub fn run(args: &TraceQueryArgs) {
+    let names = match &args.labels {
+        Some(path) => match symbols::load_label_file(path) {
+            Ok(names) => names,
+            Err(error) => panic!("Problem loading label file {}: {}", path, error),
+        },
+        None => SymbolTable::default(),
+    };
+    let file = match File::open(&args.recording) {
+        Ok(file) => file,
+        Err(error) => panic!("Problem opening the recording: {:?}", error),
+    };
+    let mut reader = match Reader::new(BufReader::new(file)) {
+        Ok(reader) => reader,
+        Err(error) => panic!("Problem reading {}: {}", args.recording, error),
+    };
+    let start = reader.start();
+    if let Err(error) = answer(&args.query, &mut reader, &names, start) {
+        panic!("Problem with the query: {}", error);
+    }
+    if reader.truncated {
+        println!("(the recording was cut short, its last record is incomplete)");
+    }
+}
@@ -0,0 +1,411 @@
+use crate::cpu6502::memory::AccessKind;
+use crate::cpu6502::{Cpu6502, Registers};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+
+// Compact binary recording of a run, small enough to leave on for minutes
+// of wozmon idling. After a header with the starting registers each
+// instruction or interrupt is one record:
+//
+//   flags          what follows, FLAG_* below
+//   pc             zigzag LEB128 delta from the previous record's PC
+//   cycles         LEB128 cycles taken
+//   A X Y SP P     one byte each, only those that changed
+//   writes         LEB128 count, then address (u16 LE) and new value each
+//
+// A straight-line instruction that only touches registers takes 4 bytes.
+
+const MAGIC: &[u8; 4] = b"R65T";
+const VERSION: u8 = 1;
+
+const FLAG_A: u8 = 0x01;
+const FLAG_X: u8 = 0x02;
+const FLAG_Y: u8 = 0x04;
+const FLAG_SP: u8 = 0x08;
+const FLAG_P: u8 = 0x10;
+const FLAG_WRITES: u8 = 0x20;
+const FLAG_INTERRUPT: u8 = 0x40;
+const FLAG_CALL: u8 = 0x80;
+
+// The registers a record can change, PC is implied by the next record
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Regs {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub p: u8,
+}
+
+impl Regs {
+    fn of(registers: &Registers) -> Regs {
+        Regs {
+            a: registers.a,
+            x: registers.x,
+            y: registers.y,
+            sp: registers.sp,
+            p: registers.p,
+        }
+    }
+
+    fn fields(&mut self) -> [(u8, &mut u8); 5] {
+        [
+            (FLAG_A, &mut self.a),
+            (FLAG_X, &mut self.x),
+            (FLAG_Y, &mut self.y),
+            (FLAG_SP, &mut self.sp),
+            (FLAG_P, &mut self.p),
+        ]
+    }
+}
+
+impl std::fmt::Display for Regs {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "A=${:02x} X=${:02x} Y=${:02x} SP=${:02x} P=${:02x}",
+            self.a, self.x, self.y, self.sp, self.p
+        )
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+pub struct Recording<W: Write> {
+    out: W,
+    pc: u16,
+    regs: Regs,
+    cycles: u64,
+    // one record, reused so recording does not allocate per instruction
+    buffer: Vec<u8>,
+}
+
+impl Recording<BufWriter<File>> {
+    pub fn create(path: &str, cpu: &Cpu6502) -> Recording<BufWriter<File>> {
+        match File::create(path) {
+            Ok(file) => Recording::new(BufWriter::new(file), cpu),
+            Err(error) => panic!("Problem creating recording {}: {:?}", path, error),
+        }
+    }
+}
+
+impl<W: Write> Recording<W> {
+    pub fn new(out: W, cpu: &Cpu6502) -> Recording<W> {
+        let registers = cpu.registers();
+        let mut recording = Recording {
+            out,
+            pc: registers.pc,
+            regs: Regs::of(&registers),
+            cycles: registers.cycles,
+            buffer: Vec::new(),
+        };
+        recording.buffer.extend_from_slice(MAGIC);
+        recording.buffer.push(VERSION);
+        recording
+            .buffer
+            .extend_from_slice(&registers.pc.to_le_bytes());
+        let regs = recording.regs;
+        recording
+            .buffer
+            .extend_from_slice(&[regs.a, regs.x, regs.y, regs.sp, regs.p]);
+        recording
+            .buffer
+            .extend_from_slice(&registers.cycles.to_le_bytes());
+        recording.flush_buffer();
+        recording
+    }
+
+    // After step ran the instruction that was at pc
+    pub fn instruction(&mut self, cpu: &Cpu6502, pc: u16, call: bool) {
+        self.record(cpu, pc, if call { FLAG_CALL } else { 0 });
+    }
+
+    // After service_interrupt took an interrupt with PC at pc
+    pub fn interrupt(&mut self, cpu: &Cpu6502, pc: u16) {
+        self.record(cpu, pc, FLAG_INTERRUPT);
+    }
+
+    fn record(&mut self, cpu: &Cpu6502, pc: u16, mut flags: u8) {
+        let registers = cpu.registers();
+        let mut regs = Regs::of(&registers);
+        // flags go first but are only known once the rest is written
+        self.buffer.push(0);
+        let delta = pc.wrapping_sub(self.pc) as i16;
+        write_varint(
+            &mut self.buffer,
+            ((delta << 1) ^ (delta >> 15)) as u16 as u64,
+        );
+        write_varint(&mut self.buffer, registers.cycles - self.cycles);
+        for ((flag, old), (_, new)) in self.regs.fields().into_iter().zip(regs.fields()) {
+            if *old != *new {
+                flags |= flag;
+                self.buffer.push(*new);
+            }
+        }
+        let writes = cpu
+            .last_accesses
+            .iter()
+            .filter_map(|access| match access.kind {
+                AccessKind::Write { new, .. } => Some((access.addr, new)),
+                AccessKind::Read(_) => None,
+            });
+        let count = writes.clone().count();
+        if count > 0 {
+            flags |= FLAG_WRITES;
+            write_varint(&mut self.buffer, count as u64);
+            for (addr, value) in writes {
+                self.buffer.extend_from_slice(&addr.to_le_bytes());
+                self.buffer.push(value);
+            }
+        }
+        self.buffer[0] = flags;
+        self.flush_buffer();
+
+        self.pc = pc;
+        self.regs = regs;
+        self.cycles = registers.cycles;
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(error) = self.out.flush() {
+            panic!("Problem writing recording: {:?}", error);
+        }
+    }
+
+    fn flush_buffer(&mut self) {
+        if let Err(error) = self.out.write_all(&self.buffer) {
+            panic!("Problem writing recording: {:?}", error);
+        }
+        self.buffer.clear();
+    }
+}
+
+// One record read back, with the state around it filled in
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    // instructions executed before this one, interrupts are not counted
+    pub instruction: u64,
+    pub pc: u16,
+    // cycle count when it started
+    pub cycle: u64,
+    pub cycles: u64,
+    pub before: Regs,
+    pub after: Regs,
+    pub writes: Vec<(u16, u8)>,
+    // a JSR, pc is the caller and next_pc the subroutine
+    pub call: bool,
+    pub interrupt: bool,
+    // where execution went next, unknown for the last record
+    pub next_pc: Option<u16>,
+}
+
+pub struct Reader<R: Read> {
+    input: R,
+    pc: u16,
+    regs: Regs,
+    cycle: u64,
+    instruction: u64,
+    // records are read one ahead to know where each one went
+    ahead: Option<Step>,
+    started: bool,
+    // the run was killed before the last record was written out in full,
+    // which ends the recording there rather than failing it
+    pub truncated: bool,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut input: R) -> Result<Reader<R>, String> {
+        let mut header = [0u8; 20];
+        input
+            .read_exact(&mut header)
+            .map_err(|_| "too short for a recording".to_string())?;
+        if &header[..4] != MAGIC {
+            return Err("not a recording made with --record".to_string());
+        }
+        if header[4] != VERSION {
+            return Err(format!("unsupported recording version {}", header[4]));
+        }
+        Ok(Reader {
+            input,
+            pc: u16::from_le_bytes([header[5], header[6]]),
+            regs: Regs {
+                a: header[7],
+                x: header[8],
+                y: header[9],
+                sp: header[10],
+                p: header[11],
+            },
+            cycle: u64::from_le_bytes(header[12..20].try_into().unwrap()),
+            instruction: 0,
+            ahead: None,
+            started: false,
+            truncated: false,
+        })
+    }
+
+    // PC when the recording started
+    pub fn start(&self) -> u16 {
+        self.pc
+    }
+
+    // None at a clean end of input
+    fn byte(&mut self) -> Result<Option<u8>, String> {
+        let mut byte = [0u8];
+        match self.input.read(&mut byte) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    fn required(&mut self) -> Result<u8, String> {
+        let byte = self.byte()?;
+        self.truncated = byte.is_none();
+        byte.ok_or_else(|| "recording ends in the middle of a record".to_string())
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.required()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("bad number in recording".to_string())
+    }
+
+    fn read_step(&mut self) -> Result<Option<Step>, String> {
+        let flags = match self.byte()? {
+            Some(flags) => flags,
+            None => return Ok(None),
+        };
+        let zigzag = self.varint()? as u16;
+        let delta = (zigzag >> 1) as i16 ^ -((zigzag & 1) as i16);
+        let pc = self.pc.wrapping_add(delta as u16);
+        let cycles = self.varint()?;
+        let before = self.regs;
+        let mut after = before;
+        for (flag, value) in after.fields() {
+            if flags & flag != 0 {
+                *value = self.required()?;
+            }
+        }
+        let mut writes = Vec::new();
+        if flags & FLAG_WRITES != 0 {
+            for _ in 0..self.varint()? {
+                let addr = u16::from_le_bytes([self.required()?, self.required()?]);
+                writes.push((addr, self.required()?));
+            }
+        }
+        let interrupt = flags & FLAG_INTERRUPT != 0;
+        let step = Step {
+            instruction: self.instruction,
+            pc,
+            cycle: self.cycle,
+            cycles,
+            before,
+            after,
+            writes,
+            call: flags & FLAG_CALL != 0,
+            interrupt,
+            next_pc: None,
+        };
+        self.pc = pc;
+        self.regs = after;
+        self.cycle += cycles;
+        if !interrupt {
+            self.instruction += 1;
+        }
+        Ok(Some(step))
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Step, String>;
+
+    fn next(&mut self) -> Option<Result<Step, String>> {
+        if !self.started {
+            self.started = true;
+            self.ahead = match self.read_step() {
+                Ok(step) => step,
+                Err(_) if self.truncated => None,
+                Err(error) => return Some(Err(error)),
+            };
+        }
+        let mut step = self.ahead.take()?;
+        match self.read_step() {
+            Ok(next) => {
+                step.next_pc = next.as_ref().map(|next| next.pc);
+                self.ahead = next;
+                Some(Ok(step))
+            }
+            Err(_) if self.truncated => Some(Ok(step)),
+            Err(error) => Some(Err(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu6502::cpu_with_program;
+    use crate::trace::record::{Reader, Recording};
+
+    #[test]
+    fn test_round_trip() {
+        let program = [
+            0xa9, 0x41, // 0600 LDA #$41
+            0x20, 0x08, 0x06, // 0602 JSR $0608
+            0x4c, 0x05, 0x06, // 0605 JMP $0605
+            0x85, 0x24, // 0608 STA $24
+            0x60, // 060a RTS
+        ];
+        let mut cpu = cpu_with_program(&[], 0x0600, &program);
+        cpu.memory.enable_access_log();
+
+        let mut bytes = Vec::new();
+        let mut recording = Recording::new(&mut bytes, &cpu);
+        for _ in 0..6 {
+            let pc = cpu.program_counter;
+            let call = cpu.memory.get_byte(pc as usize) == 0x20;
+            cpu.step();
+            recording.instruction(&cpu, pc, call);
+        }
+        drop(recording);
+        // header plus 4 bytes for the JMP that only moves the PC
+        assert!(bytes.len() < 20 + 6 * 8);
+
+        let steps: Vec<_> = Reader::new(&bytes[..])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let pcs: Vec<u16> = steps.iter().map(|step| step.pc).collect();
+        assert_eq!(pcs, [0x0600, 0x0602, 0x0608, 0x060a, 0x0605, 0x0605]);
+        assert_eq!(steps[0].after.a, 0x41);
+        assert!(steps[1].call);
+        assert_eq!(steps[1].next_pc, Some(0x0608));
+        let pushed: Vec<u16> = steps[1].writes.iter().map(|(addr, _)| *addr).collect();
+        assert_eq!(pushed, [0x01ff, 0x01fe]);
+        assert_eq!(steps[1].after.sp, 0xfd);
+        assert_eq!(steps[2].writes, [(0x0024, 0x41)]);
+        assert_eq!(steps[3].after.sp, 0xff);
+        assert_eq!(steps[5].instruction, 5);
+        assert_eq!(steps[5].cycle, 2 + 6 + 3 + 6 + 3);
+        assert_eq!(steps[5].next_pc, None);
+
+        assert!(Reader::new(&b"nope"[..]).is_err());
+        let mut cut = bytes.clone();
+        cut.pop();
+        let mut reader = Reader::new(&cut[..]).unwrap();
+        let steps: Vec<_> = reader.by_ref().collect::<Result<_, _>>().unwrap();
+        assert_eq!(steps.len(), 5);
+        assert!(reader.truncated);
+    }
+}
@@ -0,0 +1,102 @@
+use crossterm::{
+    cursor::MoveToColumn,
+    event::{read, Event, KeyCode, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, Clear, ClearType},
+};
+use std::io::{self, IsTerminal, Write};
+
+// Small readline replacement for the debugger prompt: cursor movement and
+// up/down history when attached to a terminal, plain line reads otherwise.
+#[derive(Default)]
+pub struct LineEditor {
+    history: Vec<String>,
+}
+
+impl LineEditor {
+    // Returns None on end of input (Ctrl+D, Ctrl+C or a closed stdin)
+    pub fn read_line(&mut self, prompt: &str) -> Option<String> {
+        let line = if io::stdin().is_terminal() {
+            self.read_line_raw(prompt)
+        } else {
+            read_line_plain(prompt)
+        }?;
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        Some(line)
+    }
+
+    fn read_line_raw(&mut self, prompt: &str) -> Option<String> {
+        let mut stdout = io::stdout();
+        let mut buffer: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // one past the newest entry means "not browsing history"
+        let mut history_index = self.history.len();
+
+        enable_raw_mode().expect("Failed to enable raw mode.");
+        let result = loop {
+            execute!(stdout, MoveToColumn(0), Clear(ClearType::CurrentLine)).unwrap();
+            let text: String = buffer.iter().collect();
+            write!(stdout, "{}{}", prompt, text).unwrap();
+            execute!(
+                stdout,
+                MoveToColumn((prompt.chars().count() + cursor) as u16)
+            )
+            .unwrap();
+
+            let key = match read() {
+                Ok(Event::Key(key)) if key.kind != KeyEventKind::Release => key,
+                Ok(_) => continue,
+                Err(_) => break None,
+            };
+            let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+            match key.code {
+                KeyCode::Char('c') | KeyCode::Char('d') if ctrl => break None,
+                KeyCode::Char(c) => {
+                    buffer.insert(cursor, c);
+                    cursor += 1;
+                }
+                KeyCode::Enter => break Some(buffer.iter().collect()),
+                KeyCode::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    buffer.remove(cursor);
+                }
+                KeyCode::Delete if cursor < buffer.len() => {
+                    buffer.remove(cursor);
+                }
+                KeyCode::Left if cursor > 0 => cursor -= 1,
+                KeyCode::Right if cursor < buffer.len() => cursor += 1,
+                KeyCode::Home => cursor = 0,
+                KeyCode::End => cursor = buffer.len(),
+                KeyCode::Up if history_index > 0 => {
+                    history_index -= 1;
+                    buffer = self.history[history_index].chars().collect();
+                    cursor = buffer.len();
+                }
+                KeyCode::Down if history_index < self.history.len() => {
+                    history_index += 1;
+                    buffer = match self.history.get(history_index) {
+                        Some(entry) => entry.chars().collect(),
+                        None => Vec::new(),
+                    };
+                    cursor = buffer.len();
+                }
+                _ => {}
+            }
+        };
+        disable_raw_mode().expect("Failed to disable raw mode.");
+        println!();
+        result
+    }
+}
+
+fn read_line_plain(prompt: &str) -> Option<String> {
+    print!("{}", prompt);
+    io::stdout().flush().unwrap();
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end_matches(['\r', '\n']).to_string()),
+    }
+}